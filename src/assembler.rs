@@ -1,19 +1,30 @@
+pub mod debug_info;
 mod directive_parsers;
+pub mod formatter;
 mod instruction_parsers;
 mod label_parsers;
 mod opcode_parsers;
 mod operand_parsers;
 pub mod program_parsers;
 mod register_parsers;
-mod symbol;
+pub mod source_map;
+pub mod symbol;
+mod trivia_parsers;
 
-use nom::{error::Error, IResult};
+use std::fmt;
 
-use crate::instruction::Opcode;
+use nom::error::{Error, ErrorKind};
+
+use crate::{
+    instruction::{Opcode, OperandKind, INSTRUCTION_LENGTH},
+    register,
+};
 
 use self::{
+    debug_info::DEBUG_OFFSET_RANGE,
     instruction_parsers::AssemblerInstruction,
     program_parsers::{program_parser, Program},
+    source_map::{SourceLocation, SourceMap},
     symbol::{Symbol, SymbolTable, SymbolType},
 };
 
@@ -31,6 +42,21 @@ pub enum Token {
     String { value: String },
 }
 
+impl fmt::Display for Token {
+    /// Writes the token the way it is written in assembly source
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Op { code } => write!(f, "{}", code),
+            Token::Register { reg_num } => write!(f, "{}", register::register_name(*reg_num)),
+            Token::IntegerOperand { value } => write!(f, "#{}", value),
+            Token::LabelDeclaration { name } => write!(f, "{}:", name),
+            Token::LabelUsage { name } => write!(f, "@{}", name),
+            Token::Directive { name } => write!(f, ".{}", name),
+            Token::String { value } => write!(f, "'{}'", value),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Assembler {
     /// Tracks which phase the assember is in
@@ -41,6 +67,10 @@ pub struct Assembler {
     pub ro: Vec<u8>,
     /// The compiled bytecode generated from the assembly instructions
     pub bytecode: Vec<u8>,
+    /// Where the assembled instructions are in the source
+    pub source_map: SourceMap,
+    /// Source file named in the debug section, which is only written when set
+    debug_file: Option<String>,
     /// Tracks the current offset of the read-only section
     ro_offset: u32,
     /// A list of all the sections we've seen in the code
@@ -58,6 +88,8 @@ impl Assembler {
             symbols: SymbolTable::new(),
             ro: vec![],
             bytecode: vec![],
+            source_map: SourceMap::new(),
+            debug_file: None,
             ro_offset: 0,
             sections: vec![],
             current_section: None,
@@ -65,27 +97,48 @@ impl Assembler {
         }
     }
 
+    /// Writes a debug section after the code of the programs assembled with
+    /// `assemble`, naming `file` as their source. See `debug_info`.
+    pub fn emit_debug_info(&mut self, file: &str) {
+        self.debug_file = Some(file.to_string());
+    }
+
     pub fn assemble(&mut self, raw: &str) -> Result<Vec<u8>, AssemblerError> {
-        let (_, program) = program_parser(raw)?;
         let mut assembled_program = self.write_pie_header();
+        let mut body = self.assemble_at(raw, PIE_HEADER_LENGTH)?;
+        assembled_program.append(&mut body);
+        if let Some(file) = &self.debug_file {
+            let offset = assembled_program.len() as u32;
+            assembled_program[DEBUG_OFFSET_RANGE].copy_from_slice(&offset.to_le_bytes());
+            assembled_program.append(&mut debug_info::encode(
+                file,
+                &self.source_map,
+                &self.symbols,
+            ));
+        }
+        Ok(assembled_program)
+    }
 
-        self.process_first_phase(&program)?;
+    /// Assembles code to be loaded at `offset` in a program, such as the end
+    /// of the program of the REPL. There is no PIE header, and labels are
+    /// relative to `offset`.
+    pub fn assemble_at(&mut self, raw: &str, offset: usize) -> Result<Vec<u8>, AssemblerError> {
+        let program = parse(raw)?;
 
-        let mut body = self.process_second_phase(&program)?;
+        self.process_first_phase(&program, offset as u32)?;
 
-        assembled_program.append(&mut body);
-        Ok(assembled_program)
+        self.process_second_phase(&program, raw, offset)
     }
 
     fn write_pie_header(&self) -> Vec<u8> {
         let mut header = Vec::with_capacity(PIE_HEADER_LENGTH);
         header.append(&mut PIE_HEADER_PREFIX.to_vec());
-        header.fill(0);
+        header.resize(PIE_HEADER_LENGTH, 0);
         header
     }
 
-    fn process_first_phase(&mut self, p: &Program) -> Result<(), AssemblerError> {
-        self.extract_labels(p)?;
+    fn process_first_phase(&mut self, p: &Program, offset: u32) -> Result<(), AssemblerError> {
+        self.extract_labels(p, offset)?;
         // if self.sections.len() != 2 {
         //     Err(AssemblerError::InsufficientSections)
         // } else {
@@ -94,13 +147,28 @@ impl Assembler {
         // }
     }
 
-    fn process_second_phase(&mut self, p: &Program) -> Result<Vec<u8>, AssemblerError> {
+    /// Encodes the instructions of `p`, parsed from `raw`, recording where
+    /// they are in `raw` along with their address
+    fn process_second_phase(
+        &mut self,
+        p: &Program,
+        raw: &str,
+        offset: usize,
+    ) -> Result<Vec<u8>, AssemblerError> {
         self.current_instruction = 0;
+        // Sections were already recorded by the first phase
+        self.sections.clear();
+        self.current_section = None;
 
         let mut program = vec![];
-        for i in &p.instructions {
+        for (i, &start) in p.instructions.iter().zip(&p.offsets) {
             if i.is_opcode() {
-                let mut bytes = i.to_bytes();
+                // Instructions may be preceded by the indentation of their line
+                let text = &raw[start..];
+                let start = start + text.len() - text.trim_start().len();
+                self.source_map
+                    .add(offset + program.len(), SourceLocation::at(raw, start));
+                let mut bytes = i.to_bytes(&self.symbols)?;
                 program.append(&mut bytes);
             } else if i.is_directive() {
                 self.process_directive(i)?;
@@ -110,23 +178,37 @@ impl Assembler {
         Ok(program)
     }
 
-    fn extract_labels(&mut self, p: &Program) -> Result<(), AssemblerError> {
+    /// Records every label along with the address of the instruction it
+    /// labels. Labels on directives are constants, their offset in the
+    /// read-only section is only known in the second phase.
+    fn extract_labels(&mut self, p: &Program, mut offset: u32) -> Result<(), AssemblerError> {
         for i in &p.instructions {
-            if i.is_label() {
-                if self.current_section.is_some() {
-                    if let Some(name) = i.get_label_name() {
-                        let symbol = Symbol::new(name, SymbolType::Label, 0);
-                        if self.symbols.has_symbol(&symbol) {
-                            return Err(AssemblerError::SymbolAlreadyDeclared);
-                        }
-                        self.symbols.add_symbol(symbol);
-                    } else {
-                        return Err(AssemblerError::StringConstantDeclaredWithoutLabel {
-                            instruction: self.current_instruction,
-                        });
+            if i.is_directive() && !i.has_operands() {
+                if let Some(name) = i.get_directive_name() {
+                    self.process_section_header(&name)?;
+                }
+            }
+            if i.is_label() && self.current_section.is_some() {
+                if let Some(name) = i.get_label_name() {
+                    if self.symbols.symbol_value(&name).is_some() {
+                        return Err(AssemblerError::SymbolAlreadyDeclared);
                     }
+                    let symbol_type = if i.is_opcode() {
+                        SymbolType::Label
+                    } else {
+                        SymbolType::Constant
+                    };
+                    self.symbols
+                        .add_symbol(Symbol::new(name, symbol_type, offset));
+                } else {
+                    return Err(AssemblerError::StringConstantDeclaredWithoutLabel {
+                        instruction: self.current_instruction,
+                    });
                 }
             }
+            if i.is_opcode() {
+                offset += INSTRUCTION_LENGTH as u32;
+            }
         }
         Ok(())
     }
@@ -135,19 +217,17 @@ impl Assembler {
         if let Some(directive_name) = i.get_directive_name() {
             if i.has_operands() {
                 match directive_name.as_ref() {
-                    "asciiz" => return self.handle_asciiz(i),
-                    _ => {
-                        return Err(AssemblerError::UnknownDirectiveFound {
-                            directive: directive_name,
-                        })
-                    }
-                };
+                    "asciiz" => self.handle_asciiz(i),
+                    _ => Err(AssemblerError::UnknownDirectiveFound {
+                        directive: directive_name,
+                    }),
+                }
             } else {
                 self.process_section_header(&directive_name)?;
                 Ok(())
             }
         } else {
-            println!("Directive has invalid name: {:?}", i);
+            log::debug!("Directive has invalid name: {:?}", i);
             Err(AssemblerError::DirectiveHasInvalidName)
         }
     }
@@ -170,7 +250,7 @@ impl Assembler {
             } else {
                 // This would be someone typing:
                 // .asciiz 'Hello'
-                println!("Found a string constant with no associated label!");
+                log::debug!("Found a string constant with no associated label!");
                 return Err(AssemblerError::NoLabel);
             }
 
@@ -185,12 +265,18 @@ impl Assembler {
             Ok(())
         } else {
             // This just means someone typed `.asciiz` for some reason
-            println!("String constant following an .asciiz was empty");
-            return Err(AssemblerError::NoStringConstant);
+            log::debug!("String constant following an .asciiz was empty");
+            Err(AssemblerError::NoStringConstant)
         }
     }
 }
 
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum AssemblerPhase {
     First,
@@ -200,25 +286,91 @@ pub enum AssemblerPhase {
 #[derive(Debug)]
 pub enum AssemblerError {
     SymbolAlreadyDeclared,
-    StringConstantDeclaredWithoutLabel { instruction: u32 },
-    ParseError { error: String },
+    StringConstantDeclaredWithoutLabel {
+        instruction: u32,
+    },
+    ParseError {
+        error: String,
+    },
     InsufficientSections,
-    UnknownDirectiveFound { directive: String },
+    UnknownDirectiveFound {
+        directive: String,
+    },
     DirectiveHasInvalidName,
     UnknownSectionFound,
     ShouldBeSecondPhase,
     NoStringConstant,
     NoLabel,
+    InvalidRegister {
+        register: String,
+    },
+    UnknownLabel {
+        name: String,
+    },
+    /// Only instructions with an opcode can be encoded
+    NoOpcode,
+    /// The operand is not of the kind the opcode expects, such as a string or
+    /// a label where it expects a register
+    InvalidOperand {
+        operand: String,
+        expected: OperandKind,
+    },
+    /// The instruction does not have as many operands as its opcode takes
+    WrongOperandCount {
+        opcode: Opcode,
+        expected: usize,
+        found: usize,
+    },
+    /// The label is at an address that does not fit in an integer operand
+    AddressTooLarge {
+        name: String,
+    },
+    /// The mnemonic of the instruction on this line is not an opcode
+    UnknownOpcode {
+        line: usize,
+    },
+}
+
+/// Parses a whole program, failing on any input left over
+pub fn parse(raw: &str) -> Result<Program, AssemblerError> {
+    let (rest, program) = program_parser(raw)?;
+    if !rest.trim().is_empty() {
+        let line = raw[..raw.len() - rest.len()].matches('\n').count() + 1;
+        return Err(AssemblerError::ParseError {
+            error: format!("Unexpected input at line {}: '{}'", line, first_line(rest)),
+        });
+    }
+    Ok(program)
 }
 
 impl From<nom::Err<nom::error::Error<&str>>> for AssemblerError {
     fn from(value: nom::Err<Error<&str>>) -> Self {
-        AssemblerError::ParseError {
-            error: "Test".to_string(),
+        match value {
+            // The register parser is the only one failing hard on a `map_opt`,
+            // when the name following a `$` is not a known register
+            nom::Err::Failure(e) if e.code == ErrorKind::MapOpt => {
+                AssemblerError::InvalidRegister {
+                    register: format!("${}", first_word(e.input)),
+                }
+            }
+            nom::Err::Error(e) | nom::Err::Failure(e) => AssemblerError::ParseError {
+                error: format!("{:?} at '{}'", e.code, first_line(e.input)),
+            },
+            nom::Err::Incomplete(_) => AssemblerError::ParseError {
+                error: "Incomplete input".to_string(),
+            },
         }
     }
 }
 
+fn first_line(input: &str) -> &str {
+    input.lines().next().unwrap_or_default().trim()
+}
+
+fn first_word(input: &str) -> &str {
+    input.split_whitespace().next().unwrap_or_default()
+}
+
 #[derive(Debug, Clone)]
 enum AssemblerSection {
     Header,
@@ -238,3 +390,144 @@ impl TryFrom<&str> for AssemblerSection {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assemble_rejects_invalid_register() {
+        let mut asm = Assembler::new();
+        let result = asm.assemble(".code\nLOAD $0 #10\nADD $0 $255 $2\n");
+        assert!(matches!(
+            result,
+            Err(AssemblerError::InvalidRegister { register }) if register == "$255"
+        ));
+    }
+
+    #[test]
+    fn test_assemble_named_registers() {
+        let mut asm = Assembler::new();
+        let program = asm.assemble(".code\nADD $sp $zero $fp\n").unwrap();
+        assert_eq!(program[program.len() - 4..], [1, 28, 31, 29]);
+    }
+
+    #[test]
+    fn test_assemble_header() {
+        let mut asm = Assembler::new();
+        let program = asm.assemble(".code\nHLT\n").unwrap();
+        assert_eq!(program.len(), PIE_HEADER_LENGTH + INSTRUCTION_LENGTH);
+        assert_eq!(program[..4], PIE_HEADER_PREFIX);
+        assert_eq!(program[PIE_HEADER_LENGTH], Opcode::HLT as u8);
+    }
+
+    #[test]
+    fn test_assemble_label_offsets() {
+        let mut asm = Assembler::new();
+        asm.assemble(".data\nhello: .asciiz 'Hi'\n.code\nLOAD $0 #1\nloop: INC $0\nHLT\n")
+            .unwrap();
+        assert_eq!(asm.symbols.symbol_value("loop"), Some(68));
+        assert_eq!(asm.symbols.symbol_value("hello"), Some(0));
+    }
+
+    #[test]
+    fn test_assemble_label_usage() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble(".code\nLOAD $0 @end\nJMP $0\nend: HLT\n")
+            .unwrap();
+        assert_eq!(program[PIE_HEADER_LENGTH..][..4], [0, 0, 0, 72]);
+
+        let result = Assembler::new().assemble(".code\nLOAD $0 @nowhere\n");
+        assert!(matches!(
+            result,
+            Err(AssemblerError::UnknownLabel { name }) if name == "nowhere"
+        ));
+    }
+
+    #[test]
+    fn test_assemble_invalid_operands() {
+        let result = Assembler::new().assemble(
+            ".code
+end: JMP @end
+",
+        );
+        assert!(matches!(
+            result,
+            Err(AssemblerError::InvalidOperand { operand, expected: OperandKind::Register })
+                if operand == "@end"
+        ));
+        let result = Assembler::new().assemble(
+            ".code
+LOAD #1 $0
+",
+        );
+        assert!(matches!(result, Err(AssemblerError::InvalidOperand { .. })));
+        let result = Assembler::new().assemble(
+            ".code
+ADD $0 $1
+",
+        );
+        assert!(matches!(
+            result,
+            Err(AssemblerError::WrongOperandCount {
+                opcode: Opcode::ADD,
+                expected: 3,
+                found: 2
+            })
+        ));
+
+        let result = Assembler::new().assemble_at(
+            ".code
+far: LOAD $0 @far
+",
+            0x10000,
+        );
+        assert!(matches!(
+            result,
+            Err(AssemblerError::AddressTooLarge { name }) if name == "far"
+        ));
+    }
+
+    #[test]
+    fn test_assemble_at() {
+        let mut asm = Assembler::new();
+        let code = asm
+            .assemble_at(".code\nloop: DEC $0\nLOAD $1 @loop\n", 12)
+            .unwrap();
+        assert_eq!(code.len(), 2 * INSTRUCTION_LENGTH);
+        assert_eq!(asm.symbols.symbol_value("loop"), Some(12));
+        assert_eq!(code[4..], [0, 1, 0, 12]);
+    }
+
+    #[test]
+    fn test_source_map() {
+        let mut asm = Assembler::new();
+        asm.assemble(".code\nLOAD $0 #1\n\nloop:   INC $0\n    HLT\n")
+            .unwrap();
+        let location = |address| asm.source_map.location(address).map(|l| (l.line, l.column));
+        assert_eq!(location(64), Some((2, 1)));
+        assert_eq!(location(68), Some((4, 1)));
+        assert_eq!(location(72), Some((5, 5)));
+    }
+
+    #[test]
+    fn test_assemble_debug_info() {
+        let source = ".code\nLOAD $0 #1\nHLT\n";
+        let plain = Assembler::new().assemble(source).unwrap();
+        let mut asm = Assembler::new();
+        asm.emit_debug_info("one.asm");
+        let program = asm.assemble(source).unwrap();
+        assert_eq!(program[DEBUG_OFFSET_RANGE], [72, 0, 0, 0]);
+        assert_eq!(program[PIE_HEADER_LENGTH..72], plain[PIE_HEADER_LENGTH..]);
+        let info = debug_info::DebugInfo::decode(&program[72..]).unwrap();
+        assert_eq!(info.source_line(68), Some("one.asm:3".to_string()));
+    }
+
+    #[test]
+    fn test_assemble_duplicate_label() {
+        let mut asm = Assembler::new();
+        let result = asm.assemble(".code\na: HLT\na: HLT\n");
+        assert!(matches!(result, Err(AssemblerError::SymbolAlreadyDeclared)));
+    }
+}
@@ -0,0 +1,143 @@
+//! Debug section of PIE programs, mapping their instructions back to the
+//! source they were assembled from.
+//!
+//! The section follows the code. Bytes 4 to 8 of the header hold its offset
+//! in the program, or 0 when there is none. It holds, in order:
+//!
+//! - the name of the source file
+//! - the number of instructions, then the address, line and column of each
+//! - the number of labels, then the address and name of each
+//!
+//! Numbers are little endian `u32`, names are a little endian `u16` length
+//! followed by as many bytes of UTF-8.
+
+use std::io::{self, Read, Write};
+use std::ops::Range;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use super::{
+    source_map::{SourceLocation, SourceMap},
+    symbol::{Symbol, SymbolTable, SymbolType},
+};
+
+/// Where the header holds the offset of the debug section
+pub const DEBUG_OFFSET_RANGE: Range<usize> = 4..8;
+
+/// Debug information read from the debug section of a program
+#[derive(Debug)]
+pub struct DebugInfo {
+    /// Name of the source file, as given to the assembler
+    pub file: String,
+    pub source_map: SourceMap,
+    /// Labels of the instructions
+    pub symbols: SymbolTable,
+}
+
+impl DebugInfo {
+    /// Reads a debug section, or `None` if it is malformed
+    pub fn decode(mut bytes: &[u8]) -> Option<DebugInfo> {
+        let r = &mut bytes;
+        let file = read_name(r).ok()?;
+        let mut source_map = SourceMap::new();
+        for _ in 0..r.read_u32::<LittleEndian>().ok()? {
+            let address = r.read_u32::<LittleEndian>().ok()? as usize;
+            let location = SourceLocation {
+                line: r.read_u32::<LittleEndian>().ok()? as usize,
+                column: r.read_u32::<LittleEndian>().ok()? as usize,
+            };
+            source_map.add(address, location);
+        }
+        let mut symbols = SymbolTable::new();
+        for _ in 0..r.read_u32::<LittleEndian>().ok()? {
+            let address = r.read_u32::<LittleEndian>().ok()?;
+            let name = read_name(r).ok()?;
+            symbols.add_symbol(Symbol::new(name, SymbolType::Label, address));
+        }
+        Some(DebugInfo {
+            file,
+            source_map,
+            symbols,
+        })
+    }
+
+    /// Where the instruction at `pc` is, as `file:line`
+    pub fn source_line(&self, pc: usize) -> Option<String> {
+        let location = self.source_map.location(pc)?;
+        Some(format!("{}:{}", self.file, location.line))
+    }
+}
+
+/// Writes the debug section of a program assembled from `file`
+pub fn encode(file: &str, source_map: &SourceMap, symbols: &SymbolTable) -> Vec<u8> {
+    let mut section = vec![];
+    write_section(&mut section, file, source_map, symbols).expect("Writing to memory cannot fail");
+    section
+}
+
+fn write_section<W: Write>(
+    w: &mut W,
+    file: &str,
+    source_map: &SourceMap,
+    symbols: &SymbolTable,
+) -> io::Result<()> {
+    write_name(w, file)?;
+    let entries = source_map.entries();
+    w.write_u32::<LittleEndian>(entries.len() as u32)?;
+    for (address, location) in entries {
+        w.write_u32::<LittleEndian>(*address as u32)?;
+        w.write_u32::<LittleEndian>(location.line as u32)?;
+        w.write_u32::<LittleEndian>(location.column as u32)?;
+    }
+    let labels: Vec<&Symbol> = symbols
+        .symbols()
+        .filter(|symbol| *symbol.symbol_type() == SymbolType::Label)
+        .collect();
+    w.write_u32::<LittleEndian>(labels.len() as u32)?;
+    for label in labels {
+        w.write_u32::<LittleEndian>(label.offset())?;
+        write_name(w, label.name())?;
+    }
+    Ok(())
+}
+
+fn write_name<W: Write>(w: &mut W, name: &str) -> io::Result<()> {
+    let bytes = &name.as_bytes()[..name.len().min(u16::MAX as usize)];
+    w.write_u16::<LittleEndian>(bytes.len() as u16)?;
+    w.write_all(bytes)
+}
+
+fn read_name<R: Read>(r: &mut R) -> io::Result<String> {
+    let mut bytes = vec![0; r.read_u16::<LittleEndian>()? as usize];
+    r.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut source_map = SourceMap::new();
+        source_map.add(64, SourceLocation { line: 2, column: 1 });
+        source_map.add(68, SourceLocation { line: 3, column: 7 });
+        let mut symbols = SymbolTable::new();
+        symbols.add_symbol(Symbol::new("loop".to_string(), SymbolType::Label, 68));
+        symbols.add_symbol(Symbol::new("hi".to_string(), SymbolType::Constant, 0));
+
+        let section = encode("loop.asm", &source_map, &symbols);
+        let info = DebugInfo::decode(&section).unwrap();
+        assert_eq!(info.file, "loop.asm");
+        assert_eq!(
+            info.source_map.location(68),
+            Some(SourceLocation { line: 3, column: 7 })
+        );
+        assert_eq!(info.symbols.symbol_value("loop"), Some(68));
+        assert_eq!(info.symbols.symbol_value("hi"), None);
+        assert_eq!(info.source_line(64), Some("loop.asm:2".to_string()));
+        assert_eq!(info.source_line(72), None);
+
+        assert!(DebugInfo::decode(&section[..section.len() - 1]).is_none());
+    }
+}
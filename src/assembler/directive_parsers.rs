@@ -1,17 +1,18 @@
 use nom::{
     bytes::complete::tag,
-    character::complete::{alphanumeric1, multispace0},
+    character::complete::{alphanumeric1, space0},
     combinator::opt,
     IResult,
 };
 
 use super::{
     instruction_parsers::AssemblerInstruction, label_parsers::label_declaration_parser,
-    operand_parsers::operand_parser, Token,
+    operand_parsers::operand_parser, trivia_parsers::trivia_parser, Token,
 };
 
 pub fn directive_parser(input: &str) -> IResult<&str, AssemblerInstruction> {
     let (input, label) = opt(label_declaration_parser)(input)?;
+    let (input, label_trivia) = trivia_parser(input)?;
     let (input, directive_name) = directive_declaration_parser(input)?;
     let (input, operand1) = opt(operand_parser)(input)?;
     let (input, operand2) = opt(operand_parser)(input)?;
@@ -26,6 +27,8 @@ pub fn directive_parser(input: &str) -> IResult<&str, AssemblerInstruction> {
             operand1,
             operand2,
             operand3,
+            label_trivia,
+            trivia: vec![],
         },
     ))
 }
@@ -33,7 +36,7 @@ pub fn directive_parser(input: &str) -> IResult<&str, AssemblerInstruction> {
 fn directive_declaration_parser(input: &str) -> IResult<&str, Token> {
     let (input, _) = tag(".")(input)?;
     let (input, directive) = alphanumeric1(input)?;
-    let (input, _) = multispace0(input)?;
+    let (input, _) = space0(input)?;
 
     Ok((
         input,
@@ -43,6 +46,7 @@ fn directive_declaration_parser(input: &str) -> IResult<&str, Token> {
     ))
 }
 
+#[cfg(test)]
 mod tests {
 
     use super::*;
@@ -65,7 +69,7 @@ mod tests {
     fn test_directive_parser() {
         let result = directive_parser("test: .asciiz 'Hello'");
         assert!(result.is_ok());
-        let (rest, directive) = result.unwrap();
+        let (_, directive) = result.unwrap();
         assert_eq!(
             directive,
             AssemblerInstruction {
@@ -81,6 +85,8 @@ mod tests {
                 }),
                 operand2: None,
                 operand3: None,
+                label_trivia: vec![],
+                trivia: vec![],
             }
         )
     }
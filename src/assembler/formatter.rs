@@ -0,0 +1,244 @@
+//! Formatter of assembly source, behind `vm fmt`.
+//!
+//! Labels start the line and the instructions after them are indented, with
+//! their mnemonic in upper case and their operands aligned in a column.
+//! Section directives such as `.code` are not indented. Comments on the line
+//! of an instruction are aligned with those of the surrounding lines, and
+//! consecutive empty lines are merged into one.
+
+use super::{parse, source_map::SourceLocation, trivia_parsers::Trivia, AssemblerError, Token};
+use crate::instruction::Opcode;
+
+/// Indentation of the instructions when there are no labels to make room for
+const MIN_INDENT: usize = 4;
+
+/// A line of the formatted source
+enum Line {
+    Blank,
+    Comment(String),
+    Code(Code),
+}
+
+/// A line holding a label, an instruction or a directive, and a comment
+#[derive(Default)]
+struct Code {
+    label: Option<String>,
+    mnemonic: Option<String>,
+    operands: String,
+    comment: Option<String>,
+}
+
+impl Code {
+    /// Whether this is a section directive, which is not indented
+    fn is_section(&self) -> bool {
+        self.label.is_none()
+            && self.operands.is_empty()
+            && self.mnemonic.as_ref().is_some_and(|m| m.starts_with('.'))
+    }
+
+    fn is_indented(&self) -> bool {
+        self.label.is_none() && !self.is_section()
+    }
+
+    fn text(&self, label_width: usize, mnemonic_width: usize) -> String {
+        let label = self.label.as_deref().unwrap_or_default();
+        match &self.mnemonic {
+            None => label.to_string(),
+            Some(mnemonic) if self.is_section() => mnemonic.clone(),
+            Some(mnemonic) => format!(
+                "{:<label_width$}{:<mnemonic_width$}{}",
+                label, mnemonic, self.operands
+            )
+            .trim_end()
+            .to_string(),
+        }
+    }
+}
+
+/// Formats assembly source, keeping its comments
+pub fn format(source: &str) -> Result<String, AssemblerError> {
+    let program = parse(source)?;
+    let mut lines = vec![];
+    push_trivia(&mut lines, &program.trivia, true);
+    for (instruction, &offset) in program.instructions.iter().zip(&program.offsets) {
+        if instruction.opcode == Some(Token::Op { code: Opcode::IGL }) {
+            return Err(AssemblerError::UnknownOpcode {
+                line: SourceLocation::at(source, offset).line,
+            });
+        }
+        let mut code = Code {
+            label: instruction.label.as_ref().map(Token::to_string),
+            mnemonic: (instruction.opcode.as_ref())
+                .or(instruction.directive.as_ref())
+                .map(Token::to_string),
+            operands: [
+                &instruction.operand1,
+                &instruction.operand2,
+                &instruction.operand3,
+            ]
+            .into_iter()
+            .flatten()
+            .map(Token::to_string)
+            .collect::<Vec<_>>()
+            .join(" "),
+            comment: None,
+        };
+        // A label on a line of its own stays there
+        if instruction.label_trivia.contains(&Trivia::Newline) {
+            lines.push(Line::Code(Code {
+                label: code.label.take(),
+                ..Code::default()
+            }));
+            push_trivia(&mut lines, &instruction.label_trivia, false);
+        }
+        lines.push(Line::Code(code));
+        push_trivia(&mut lines, &instruction.trivia, false);
+    }
+    Ok(render(&lines))
+}
+
+/// Adds the comments and empty lines of `trivia`, which starts at the
+/// beginning of a line or at the end of the last line of code
+fn push_trivia(lines: &mut Vec<Line>, trivia: &[Trivia], mut line_start: bool) {
+    for item in trivia {
+        match item {
+            Trivia::Comment(comment) if line_start => lines.push(Line::Comment(comment.clone())),
+            Trivia::Comment(comment) => {
+                if let Some(Line::Code(code)) = lines.last_mut() {
+                    code.comment = Some(comment.clone());
+                }
+            }
+            Trivia::Newline if line_start => lines.push(Line::Blank),
+            Trivia::Newline => {}
+        }
+        line_start = *item == Trivia::Newline;
+    }
+}
+
+fn render(lines: &[Line]) -> String {
+    let codes = lines.iter().filter_map(|line| match line {
+        Line::Code(code) => Some(code),
+        _ => None,
+    });
+    let label_width = codes
+        .clone()
+        .filter(|code| code.mnemonic.is_some())
+        .filter_map(|code| code.label.as_ref())
+        .map(|label| label.chars().count() + 1)
+        .fold(MIN_INDENT, usize::max);
+    let mnemonic_width = codes
+        .filter(|code| !code.is_section())
+        .filter_map(|code| code.mnemonic.as_ref())
+        .map(|mnemonic| mnemonic.chars().count() + 1)
+        .fold(0, usize::max);
+    let texts: Vec<String> = lines
+        .iter()
+        .map(|line| match line {
+            Line::Code(code) => code.text(label_width, mnemonic_width),
+            _ => String::new(),
+        })
+        .collect();
+
+    let mut formatted: Vec<String> = vec![];
+    let mut comment_column = None;
+    for (i, line) in lines.iter().enumerate() {
+        match line {
+            Line::Blank => {
+                if formatted.last().is_some_and(|last| !last.is_empty()) {
+                    formatted.push(String::new());
+                }
+            }
+            Line::Comment(comment) => {
+                let indent = match lines[i..].iter().find_map(|line| match line {
+                    Line::Code(code) => Some(code),
+                    _ => None,
+                }) {
+                    Some(code) if code.is_indented() => label_width,
+                    _ => 0,
+                };
+                formatted.push(format!("{:indent$};{}", "", comment));
+            }
+            Line::Code(code) => {
+                let text = &texts[i];
+                match &code.comment {
+                    Some(comment) => {
+                        // Comments are aligned along each run of lines of code
+                        let column = *comment_column
+                            .get_or_insert_with(|| comment_column_at(lines, &texts, i));
+                        formatted.push(format!("{:<column$};{}", text, comment));
+                    }
+                    None => formatted.push(text.clone()),
+                }
+            }
+        }
+        if !matches!(line, Line::Code(_)) {
+            comment_column = None;
+        }
+    }
+    while formatted.last().is_some_and(|last| last.is_empty()) {
+        formatted.pop();
+    }
+    formatted.iter().map(|line| line.clone() + "\n").collect()
+}
+
+/// Column of the comments of the run of lines of code starting at `start`
+fn comment_column_at(lines: &[Line], texts: &[String], start: usize) -> usize {
+    lines[start..]
+        .iter()
+        .zip(&texts[start..])
+        .map_while(|(line, text)| match line {
+            Line::Code(code) => Some((code, text)),
+            _ => None,
+        })
+        .filter(|(code, _)| code.comment.is_some())
+        .map(|(_, text)| text.chars().count() + 1)
+        .max()
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::Assembler;
+
+    use super::*;
+
+    const SOURCE: &str = "\n; Counts down from 3\n.data\nhello: .asciiz 'Hi; there'\n.code\n\
+        load $0 #3   ;counter\n\n\n  loop:   dec $0 ; decrement\njmpb   $0\n\
+        done:\n ; the end\n  hlt\n\n";
+
+    const FORMATTED: &str = "\
+; Counts down from 3
+.data
+hello: .asciiz 'Hi; there'
+.code
+       LOAD    $0 #3 ;counter
+
+loop:  DEC     $0 ; decrement
+       JMPB    $0
+done:
+       ; the end
+       HLT
+";
+
+    #[test]
+    fn test_format() {
+        assert_eq!(format(SOURCE).unwrap(), FORMATTED);
+        assert_eq!(format(FORMATTED).unwrap(), FORMATTED);
+        assert_eq!(
+            Assembler::new().assemble(FORMATTED).unwrap(),
+            Assembler::new().assemble(SOURCE).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_format_errors() {
+        assert!(matches!(
+            format(".code\nHLT\nfoo $0\n"),
+            Err(AssemblerError::UnknownOpcode { line: 3 })
+        ));
+        assert!(matches!(
+            format(".code\nHLT\n%"),
+            Err(AssemblerError::ParseError { .. })
+        ));
+    }
+}
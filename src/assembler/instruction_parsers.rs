@@ -1,10 +1,17 @@
-use nom::{branch::alt, character::complete::newline, combinator::opt, IResult};
-
-use crate::assembler::{opcode_parsers::opcode_parser, operand_parsers::operand_parser, Token};
+use nom::{branch::alt, combinator::opt, IResult};
+
+use crate::{
+    assembler::{
+        opcode_parsers::opcode_parser, operand_parsers::operand_parser, symbol::SymbolTable,
+        AssemblerError, Token,
+    },
+    instruction::OperandKind,
+};
 
 use super::{
-    directive_parsers::directive_parser, label_parsers::label_declaration_parser,
-    symbol::SymbolTable,
+    directive_parsers::directive_parser,
+    label_parsers::label_declaration_parser,
+    trivia_parsers::{trivia_parser, Trivia},
 };
 
 #[derive(Debug, PartialEq)]
@@ -15,50 +22,75 @@ pub struct AssemblerInstruction {
     pub operand1: Option<Token>,
     pub operand2: Option<Token>,
     pub operand3: Option<Token>,
+    /// Comments and line ends between the label and the rest of the
+    /// instruction
+    pub label_trivia: Vec<Trivia>,
+    /// Comments and line ends following the instruction
+    pub trivia: Vec<Trivia>,
 }
 
 impl AssemblerInstruction {
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut results = vec![];
-        match self.opcode {
-            Some(Token::Op { code }) => {
-                results.push(code as u8);
-            }
-            _ => {
-                println!("Non opcode found in opcode field");
-                std::process::exit(1);
-            }
+    /// Encodes the instruction, replacing label usages with their address in
+    /// `symbols`. The operands must be those the opcode expects.
+    pub fn to_bytes(&self, symbols: &SymbolTable) -> Result<Vec<u8>, AssemblerError> {
+        let code = match self.opcode {
+            Some(Token::Op { code }) => code,
+            _ => return Err(AssemblerError::NoOpcode),
         };
-
-        for operand in &[&self.operand1, &self.operand2, &self.operand3] {
-            if let Some(t) = operand {
-                AssemblerInstruction::extract_operand(t, &mut results);
-            }
+        let mut results = vec![code as u8];
+
+        let operands: Vec<&Token> = [&self.operand1, &self.operand2, &self.operand3]
+            .into_iter()
+            .flatten()
+            .collect();
+        let kinds = code.operands();
+        if operands.len() != kinds.len() {
+            return Err(AssemblerError::WrongOperandCount {
+                opcode: code,
+                expected: kinds.len(),
+                found: operands.len(),
+            });
+        }
+        for (t, kind) in operands.into_iter().zip(kinds) {
+            AssemblerInstruction::extract_operand(t, *kind, symbols, &mut results)?;
         }
         while results.len() < 4 {
             results.push(0);
         }
 
-        results
+        Ok(results)
     }
 
-    fn extract_operand(token: &Token, results: &mut Vec<u8>) {
-        match token {
-            Token::Register { reg_num } => {
+    fn extract_operand(
+        token: &Token,
+        kind: OperandKind,
+        symbols: &SymbolTable,
+        results: &mut Vec<u8>,
+    ) -> Result<(), AssemblerError> {
+        match (token, kind) {
+            (Token::Register { reg_num }, OperandKind::Register) => {
                 results.push(*reg_num);
             }
-            Token::IntegerOperand { value } => {
-                let converted = *value as u16;
-                let byte1 = converted;
-                let byte2 = converted >> 8;
-                results.push(byte2 as u8);
-                results.push(byte1 as u8);
+            (Token::IntegerOperand { value }, OperandKind::Integer) => {
+                results.extend_from_slice(&(*value as u16).to_be_bytes());
+            }
+            // Labels are only known by their address, which is an integer
+            (Token::LabelUsage { name }, OperandKind::Integer) => {
+                let address = symbols
+                    .symbol_value(name)
+                    .ok_or_else(|| AssemblerError::UnknownLabel { name: name.clone() })?;
+                let address = u16::try_from(address)
+                    .map_err(|_| AssemblerError::AddressTooLarge { name: name.clone() })?;
+                results.extend_from_slice(&address.to_be_bytes());
             }
             _ => {
-                println!("Opcode found in operand field");
-                std::process::exit(1);
+                return Err(AssemblerError::InvalidOperand {
+                    operand: token.to_string(),
+                    expected: kind,
+                })
             }
         }
+        Ok(())
     }
 
     pub fn is_label(&self) -> bool {
@@ -101,14 +133,16 @@ impl AssemblerInstruction {
 }
 
 pub fn instruction_parser(input: &str) -> IResult<&str, AssemblerInstruction> {
-    let (input, instruction) = alt((instruction_combined, directive_parser))(input)?;
-    let (input, _) = opt(newline)(input)?;
+    let (input, mut instruction) = alt((instruction_combined, directive_parser))(input)?;
+    let (input, trivia) = trivia_parser(input)?;
+    instruction.trivia = trivia;
 
     Ok((input, instruction))
 }
 
 fn instruction_combined(input: &str) -> IResult<&str, AssemblerInstruction> {
     let (input, label) = opt(label_declaration_parser)(input)?;
+    let (input, label_trivia) = trivia_parser(input)?;
     let (input, opcode) = opcode_parser(input)?;
     let (input, operand1) = opt(operand_parser)(input)?;
     let (input, operand2) = opt(operand_parser)(input)?;
@@ -123,10 +157,13 @@ fn instruction_combined(input: &str) -> IResult<&str, AssemblerInstruction> {
             operand1,
             operand2,
             operand3,
+            label_trivia,
+            trivia: vec![],
         },
     ))
 }
 
+#[cfg(test)]
 mod tests {
 
     use crate::instruction::Opcode;
@@ -148,6 +185,8 @@ mod tests {
                 operand1: Some(Token::Register { reg_num: 0 }),
                 operand2: Some(Token::IntegerOperand { value: 10 }),
                 operand3: None,
+                label_trivia: vec![],
+                trivia: vec![],
             }
         );
         assert_eq!(input, "");
@@ -165,6 +204,8 @@ mod tests {
                 operand1: Some(Token::Register { reg_num: 0 }),
                 operand2: Some(Token::Register { reg_num: 1 }),
                 operand3: Some(Token::Register { reg_num: 2 }),
+                label_trivia: vec![],
+                trivia: vec![],
             }
         );
         assert_eq!(input, "");
@@ -184,6 +225,8 @@ mod tests {
                 operand1: None,
                 operand2: None,
                 operand3: None,
+                label_trivia: vec![],
+                trivia: vec![],
             }
         );
         assert_eq!(input, "");
@@ -203,6 +246,8 @@ mod tests {
                 operand1: Some(Token::Register { reg_num: 0 }),
                 operand2: Some(Token::IntegerOperand { value: 10 }),
                 operand3: None,
+                label_trivia: vec![],
+                trivia: vec![],
             }
         );
         assert_eq!(input, "");
@@ -1,6 +1,6 @@
 use nom::{
     bytes::complete::tag,
-    character::complete::{alphanumeric1, multispace0},
+    character::complete::{alphanumeric1, space0},
     IResult,
 };
 
@@ -9,7 +9,7 @@ use super::Token;
 pub fn label_declaration_parser(input: &str) -> IResult<&str, Token> {
     let (input, label) = alphanumeric1(input)?;
     let (input, _) = tag(":")(input)?;
-    let (input, _) = multispace0(input)?;
+    let (input, _) = space0(input)?;
 
     Ok((
         input,
@@ -22,7 +22,7 @@ pub fn label_declaration_parser(input: &str) -> IResult<&str, Token> {
 pub fn label_usage_parser(input: &str) -> IResult<&str, Token> {
     let (input, _) = tag("@")(input)?;
     let (input, label) = alphanumeric1(input)?;
-    let (input, _) = multispace0(input)?;
+    let (input, _) = space0(input)?;
 
     Ok((
         input,
@@ -32,6 +32,7 @@ pub fn label_usage_parser(input: &str) -> IResult<&str, Token> {
     ))
 }
 
+#[cfg(test)]
 mod tests {
 
     use super::*;
@@ -1,5 +1,5 @@
 use nom::{
-    character::complete::{alpha1, multispace0},
+    character::complete::{alpha1, space0},
     IResult,
 };
 
@@ -7,7 +7,7 @@ use crate::{assembler::Token, instruction::Opcode};
 
 pub fn opcode_parser(input: &str) -> IResult<&str, Token> {
     let (input, token) = alpha1(input)?;
-    let (input, _) = multispace0(input)?;
+    let (input, _) = space0(input)?;
 
     let token = Token::Op {
         code: Opcode::from(token),
@@ -16,6 +16,7 @@ pub fn opcode_parser(input: &str) -> IResult<&str, Token> {
     Ok((input, token))
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
 
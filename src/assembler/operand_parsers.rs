@@ -1,31 +1,32 @@
 use nom::{
     branch::alt,
     bytes::complete::{tag, take_until1},
-    character::complete::{digit1, multispace0},
+    character::complete::{digit1, space0},
+    combinator::map_res,
     IResult,
 };
 
 use crate::assembler::Token;
 
-use super::register_parsers::register_parser;
+use super::{label_parsers::label_usage_parser, register_parsers::register_parser};
 
 pub fn operand_parser(input: &str) -> IResult<&str, Token> {
-    let (input, operand) = alt((register_parser, value_parser, string_parser))(input)?;
-    let (input, _) = multispace0(input)?;
+    let (input, operand) = alt((
+        register_parser,
+        value_parser,
+        label_usage_parser,
+        string_parser,
+    ))(input)?;
+    let (input, _) = space0(input)?;
 
     Ok((input, operand))
 }
 
 fn value_parser(input: &str) -> IResult<&str, Token> {
     let (input, _) = tag("#")(input)?;
-    let (input, operand) = digit1(input)?;
+    let (input, value) = map_res(digit1, str::parse::<i32>)(input)?;
 
-    Ok((
-        input,
-        Token::IntegerOperand {
-            value: operand.parse::<i32>().unwrap(),
-        },
-    ))
+    Ok((input, Token::IntegerOperand { value }))
 }
 
 fn string_parser(input: &str) -> IResult<&str, Token> {
@@ -41,6 +42,7 @@ fn string_parser(input: &str) -> IResult<&str, Token> {
     ))
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
 
@@ -53,10 +55,13 @@ mod tests {
         assert_eq!(rest, "");
 
         let result = value_parser("10");
-        assert!(!result.is_ok());
+        assert!(result.is_err());
 
         let result = value_parser("#");
-        assert!(!result.is_ok());
+        assert!(result.is_err());
+
+        let result = value_parser("#99999999999");
+        assert!(result.is_err());
     }
 
     #[test]
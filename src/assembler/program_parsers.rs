@@ -1,28 +1,57 @@
 use nom::{multi::many1, IResult};
 
-use crate::assembler::instruction_parsers::{instruction_parser, AssemblerInstruction};
+use crate::assembler::{
+    instruction_parsers::{instruction_parser, AssemblerInstruction},
+    symbol::SymbolTable,
+    trivia_parsers::{trivia_parser, Trivia},
+    AssemblerError,
+};
 
 #[derive(Debug, PartialEq)]
 pub struct Program {
+    /// Comments and line ends before the first instruction
+    pub trivia: Vec<Trivia>,
     pub instructions: Vec<AssemblerInstruction>,
+    /// Where each instruction starts in the parsed input, in bytes
+    pub offsets: Vec<usize>,
 }
 
 impl Program {
-    pub fn to_bytes(&self) -> Vec<u8> {
+    /// Encodes every instruction, see `AssemblerInstruction::to_bytes`
+    pub fn to_bytes(&self, symbols: &SymbolTable) -> Result<Vec<u8>, AssemblerError> {
         let mut program = vec![];
         for instruction in &self.instructions {
-            program.append(&mut instruction.to_bytes());
+            program.append(&mut instruction.to_bytes(symbols)?);
         }
-        program
+        Ok(program)
     }
 }
 
 pub fn program_parser(input: &str) -> IResult<&str, Program> {
-    let (input, instructions) = many1(instruction_parser)(input)?;
+    let (rest, trivia) = trivia_parser(input)?;
+    let (rest, located) = many1(located_instruction_parser)(rest)?;
+    let (offsets, instructions) = located
+        .into_iter()
+        .map(|(remaining, instruction)| (input.len() - remaining, instruction))
+        .unzip();
 
-    Ok((input, Program { instructions }))
+    Ok((
+        rest,
+        Program {
+            trivia,
+            instructions,
+            offsets,
+        },
+    ))
 }
 
+/// Parses an instruction along with the length of the input it starts at
+fn located_instruction_parser(input: &str) -> IResult<&str, (usize, AssemblerInstruction)> {
+    let (rest, instruction) = instruction_parser(input)?;
+    Ok((rest, (input.len(), instruction)))
+}
+
+#[cfg(test)]
 mod tests {
 
     use super::*;
@@ -40,13 +69,45 @@ mod tests {
     #[test]
     fn test_program_to_bytes() {
         let result = program_parser("load $0 #100\n");
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (_, program) = result.unwrap();
-        let bytecode = program.to_bytes();
+        let bytecode = program.to_bytes(&SymbolTable::new()).unwrap();
         assert_eq!(bytecode.len(), 4);
         println!("{:?}", bytecode);
     }
 
+    #[test]
+    fn test_parse_comments() {
+        let (rest, program) = program_parser(
+            "; count down
+.code
+loop: ; decrement
+  DEC $0 ;again
+
+HLT
+",
+        )
+        .unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(
+            program.trivia,
+            vec![Trivia::Comment(" count down".to_string()), Trivia::Newline]
+        );
+        assert_eq!(program.instructions.len(), 3);
+        assert_eq!(
+            program.instructions[1].label_trivia,
+            vec![Trivia::Comment(" decrement".to_string()), Trivia::Newline]
+        );
+        assert_eq!(
+            program.instructions[1].trivia,
+            vec![
+                Trivia::Comment("again".to_string()),
+                Trivia::Newline,
+                Trivia::Newline
+            ]
+        );
+    }
+
     #[test]
     fn test_complete_program() {
         let test_program = ".data\nhello: .asciiz 'Hello everyone!'\n.code\nHLT";
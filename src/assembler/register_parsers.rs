@@ -1,20 +1,26 @@
-use nom::{bytes::complete::tag, character::complete::digit1, IResult};
+use nom::{
+    bytes::complete::tag,
+    character::complete::alphanumeric1,
+    combinator::{cut, map_opt},
+    IResult,
+};
 
-use crate::assembler::Token;
+use crate::{assembler::Token, register::register_number};
 
+/// Parses a register written either by number (`$3`) or by its conventional
+/// name (`$sp`). Once the `$` sign has been seen, an unknown or out-of-range
+/// register is a hard failure rather than a recoverable error.
 pub fn register_parser(input: &str) -> IResult<&str, Token> {
     let (input, _) = tag("$")(input)?;
-    let (input, register) = digit1(input)?;
-
-    Ok((
-        input,
-        Token::Register {
-            reg_num: register.parse::<u8>().unwrap(),
-        },
-    ))
+    let (input, reg_num) = cut(map_opt(alphanumeric1, register_number))(input)?;
+
+    Ok((input, Token::Register { reg_num }))
 }
 
+#[cfg(test)]
 mod tests {
+    use crate::register::{SP, ZERO};
+
     use super::*;
 
     #[test]
@@ -32,9 +38,27 @@ mod tests {
         assert_eq!(rest, "");
 
         let result = register_parser("0");
-        assert!(!result.is_ok());
+        assert!(result.is_err());
 
         let result = register_parser("0");
-        assert!(!result.is_ok());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_named_register() {
+        let (rest, register) = register_parser("$sp").unwrap();
+        assert_eq!(register, Token::Register { reg_num: SP });
+        assert_eq!(rest, "");
+
+        let (_, register) = register_parser("$zero").unwrap();
+        assert_eq!(register, Token::Register { reg_num: ZERO });
+    }
+
+    #[test]
+    fn test_parse_invalid_register() {
+        assert!(matches!(register_parser("$32"), Err(nom::Err::Failure(_))));
+        assert!(matches!(register_parser("$255"), Err(nom::Err::Failure(_))));
+        assert!(matches!(register_parser("$999"), Err(nom::Err::Failure(_))));
+        assert!(matches!(register_parser("$foo"), Err(nom::Err::Failure(_))));
     }
 }
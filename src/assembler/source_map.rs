@@ -0,0 +1,94 @@
+/// Position of an instruction in the assembly source, counted from 1
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct SourceLocation {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl SourceLocation {
+    /// Finds the line and column of the byte at `offset` in `source`
+    pub fn at(source: &str, offset: usize) -> SourceLocation {
+        let before = &source[..offset];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        SourceLocation {
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+}
+
+/// Maps the address of every assembled instruction to where it is in the
+/// source, for debuggers to show the line being run
+#[derive(Debug, Default)]
+pub struct SourceMap {
+    /// Instructions by increasing address
+    entries: Vec<(usize, SourceLocation)>,
+}
+
+impl SourceMap {
+    pub fn new() -> SourceMap {
+        SourceMap::default()
+    }
+
+    /// Records the instruction at `address`, which comes after the ones
+    /// already recorded
+    pub fn add(&mut self, address: usize, location: SourceLocation) {
+        self.entries.push((address, location));
+    }
+
+    /// Where the instruction at `address` is in the source
+    pub fn location(&self, address: usize) -> Option<SourceLocation> {
+        self.entries
+            .binary_search_by_key(&address, |&(a, _)| a)
+            .ok()
+            .map(|i| self.entries[i].1)
+    }
+
+    /// Every instruction by increasing address
+    pub fn entries(&self) -> &[(usize, SourceLocation)] {
+        &self.entries
+    }
+
+    /// Finds the first instruction on `line`, or on the closest line after it
+    /// when it holds none, returning its address and location
+    pub fn address(&self, line: usize) -> Option<(usize, SourceLocation)> {
+        self.entries
+            .iter()
+            .filter(|(_, location)| location.line >= line)
+            .min_by_key(|(address, location)| (location.line, *address))
+            .copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_location_at() {
+        let source = ".code\n  LOAD $0 #1\nHLT";
+        assert_eq!(
+            SourceLocation::at(source, 8),
+            SourceLocation { line: 2, column: 3 }
+        );
+        assert_eq!(
+            SourceLocation::at(source, 19),
+            SourceLocation { line: 3, column: 1 }
+        );
+    }
+
+    #[test]
+    fn test_address_of_line() {
+        let mut map = SourceMap::new();
+        map.add(64, SourceLocation { line: 2, column: 1 });
+        map.add(68, SourceLocation { line: 4, column: 1 });
+        assert_eq!(
+            map.location(68),
+            Some(SourceLocation { line: 4, column: 1 })
+        );
+        assert_eq!(map.location(66), None);
+        assert_eq!(map.address(2).map(|(a, _)| a), Some(64));
+        assert_eq!(map.address(3).map(|(a, _)| a), Some(68));
+        assert_eq!(map.address(5), None);
+    }
+}
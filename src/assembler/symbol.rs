@@ -1,5 +1,3 @@
-use std::collections::HashSet;
-
 #[derive(Debug, PartialEq, Eq, Hash)]
 pub struct Symbol {
     name: String,
@@ -15,11 +13,26 @@ impl Symbol {
             offset,
         }
     }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+
+    pub fn symbol_type(&self) -> &SymbolType {
+        &self.symbol_type
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Hash)]
 pub enum SymbolType {
+    /// Address of an instruction in the program
     Label,
+    /// Offset of a constant in the read-only section
+    Constant,
 }
 
 #[derive(Debug)]
@@ -45,18 +58,53 @@ impl SymbolTable {
             .map(|symbol| symbol.offset)
     }
 
+    /// Finds the closest label at or before `offset` in the program, returning
+    /// its name and address
+    pub fn nearest_label(&self, offset: u32) -> Option<(&str, u32)> {
+        self.symbols
+            .iter()
+            .filter(|symbol| symbol.symbol_type == SymbolType::Label && symbol.offset <= offset)
+            .max_by_key(|symbol| symbol.offset)
+            .map(|symbol| (symbol.name.as_str(), symbol.offset))
+    }
+
+    pub fn symbols(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter()
+    }
+
+    /// Names of every symbol
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.symbols.iter().map(|symbol| symbol.name.as_str())
+    }
+
+    /// Adds the symbols of `other`, replacing those with the same name
+    pub fn merge(&mut self, other: SymbolTable) {
+        for symbol in other.symbols {
+            self.symbols.retain(|s| s.name != symbol.name);
+            self.symbols.push(symbol);
+        }
+    }
+
+    /// Removes the labels at or after `offset` in the program
+    pub fn remove_labels_from(&mut self, offset: u32) {
+        self.symbols
+            .retain(|symbol| symbol.symbol_type != SymbolType::Label || symbol.offset < offset);
+    }
+
     pub fn has_symbol(&self, s: &Symbol) -> bool {
         self.symbols.contains(s)
     }
 
     pub fn set_symbol_offset(&mut self, name: &str, offset: u32) {
-        self.symbols
-            .iter_mut()
-            .find(|item| item.name == name)
-            .map(|item| {
-                item.offset = offset;
-                return item;
-            });
+        if let Some(item) = self.symbols.iter_mut().find(|item| item.name == name) {
+            item.offset = offset;
+        }
+    }
+}
+
+impl Default for SymbolTable {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -72,10 +120,38 @@ mod tests {
         sym.add_symbol(new_symbol);
         assert_eq!(sym.symbols.len(), 1);
         let v = sym.symbol_value("test");
-        assert_eq!(true, v.is_some());
+        assert!(v.is_some());
         let v = v.unwrap();
         assert_eq!(v, 12);
         let v = sym.symbol_value("does_not_exist");
-        assert_eq!(v.is_some(), false);
+        assert!(v.is_none());
+    }
+
+    #[test]
+    fn test_nearest_label() {
+        let mut sym = SymbolTable::new();
+        sym.add_symbol(Symbol::new("start".to_string(), SymbolType::Label, 64));
+        sym.add_symbol(Symbol::new("loop".to_string(), SymbolType::Label, 72));
+        sym.add_symbol(Symbol::new("msg".to_string(), SymbolType::Constant, 70));
+        assert_eq!(sym.nearest_label(60), None);
+        assert_eq!(sym.nearest_label(64), Some(("start", 64)));
+        assert_eq!(sym.nearest_label(70), Some(("start", 64)));
+        assert_eq!(sym.nearest_label(80), Some(("loop", 72)));
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut sym = SymbolTable::new();
+        sym.add_symbol(Symbol::new("a".to_string(), SymbolType::Label, 0));
+        sym.add_symbol(Symbol::new("b".to_string(), SymbolType::Label, 4));
+        let mut other = SymbolTable::new();
+        other.add_symbol(Symbol::new("b".to_string(), SymbolType::Label, 8));
+        sym.merge(other);
+        assert_eq!(sym.symbol_value("a"), Some(0));
+        assert_eq!(sym.symbol_value("b"), Some(8));
+        assert_eq!(sym.names().count(), 2);
+
+        sym.remove_labels_from(4);
+        assert_eq!(sym.names().collect::<Vec<_>>(), ["a"]);
     }
 }
@@ -0,0 +1,62 @@
+use nom::{
+    branch::alt,
+    bytes::complete::tag,
+    character::complete::{line_ending, not_line_ending, space0},
+    combinator::map,
+    multi::many0,
+    sequence::terminated,
+    IResult,
+};
+
+/// What the assembler skips between instructions, kept for tools such as the
+/// formatter to write the source back
+#[derive(Debug, PartialEq, Clone)]
+pub enum Trivia {
+    /// A comment, with whatever follows the `;` up to the end of the line
+    Comment(String),
+    /// The end of a line, two in a row making an empty line
+    Newline,
+}
+
+/// Parses the spaces, comments and line ends up to the next instruction
+pub fn trivia_parser(input: &str) -> IResult<&str, Vec<Trivia>> {
+    let (input, _) = space0(input)?;
+    many0(terminated(
+        alt((comment_parser, map(line_ending, |_| Trivia::Newline))),
+        space0,
+    ))(input)
+}
+
+fn comment_parser(input: &str) -> IResult<&str, Trivia> {
+    let (input, _) = tag(";")(input)?;
+    let (input, comment) = not_line_ending(input)?;
+
+    Ok((input, Trivia::Comment(comment.trim_end().to_string())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trivia_parser() {
+        let result = trivia_parser("  ; counter\n\n\t;done \r\nHLT");
+        assert!(result.is_ok());
+        let (rest, trivia) = result.unwrap();
+        assert_eq!(
+            trivia,
+            vec![
+                Trivia::Comment(" counter".to_string()),
+                Trivia::Newline,
+                Trivia::Newline,
+                Trivia::Comment("done".to_string()),
+                Trivia::Newline,
+            ]
+        );
+        assert_eq!(rest, "HLT");
+
+        let (rest, trivia) = trivia_parser("HLT").unwrap();
+        assert!(trivia.is_empty());
+        assert_eq!(rest, "HLT");
+    }
+}
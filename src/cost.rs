@@ -0,0 +1,187 @@
+use std::fmt::Write;
+
+use crate::instruction::Opcode;
+
+/// Highest cost an opcode may be given, which keeps cycle counts far from
+/// overflowing
+pub const MAX_COST: u64 = 1_000_000;
+
+/// Number of cycles each opcode takes to execute. Cycle counts only depend on
+/// the instructions executed, never on the speed of the host.
+///
+/// Every instruction costs one cycle by default, except multiplications and
+/// divisions which are more expensive on real hardware.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CostTable {
+    costs: [u64; 256],
+}
+
+impl CostTable {
+    pub fn new() -> CostTable {
+        let mut table = CostTable { costs: [1; 256] };
+        table.set(Opcode::MUL, 3);
+        table.set(Opcode::DIV, 10);
+        table
+    }
+
+    pub fn cost(&self, opcode: u8) -> u64 {
+        self.costs[opcode as usize]
+    }
+
+    pub fn set(&mut self, opcode: Opcode, cost: u64) {
+        self.costs[opcode as u8 as usize] = cost;
+    }
+
+    /// Reads a cost table made of `MNEMONIC COST` lines, overriding the
+    /// default costs. Empty lines and lines starting with `;` are ignored.
+    pub fn parse(input: &str) -> Result<CostTable, String> {
+        let mut table = CostTable::new();
+        for (number, line) in input.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let (Some(mnemonic), Some(cost), None) = (words.next(), words.next(), words.next())
+            else {
+                return Err(format!("line {}: expected 'MNEMONIC COST'", number + 1));
+            };
+            let opcode = match Opcode::from(mnemonic) {
+                Opcode::IGL => {
+                    return Err(format!(
+                        "line {}: unknown opcode '{}'",
+                        number + 1,
+                        mnemonic
+                    ))
+                }
+                opcode => opcode,
+            };
+            let cost = cost
+                .parse::<u64>()
+                .map_err(|_| format!("line {}: invalid cost '{}'", number + 1, cost))?;
+            if cost > MAX_COST {
+                return Err(format!(
+                    "line {}: cost {} is above the maximum of {}",
+                    number + 1,
+                    cost,
+                    MAX_COST
+                ));
+            }
+            table.set(opcode, cost);
+        }
+        Ok(table)
+    }
+}
+
+impl Default for CostTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Cycles spent by a VM, in total and per opcode
+#[derive(Debug, Clone, PartialEq)]
+pub struct CycleCounter {
+    total: u64,
+    by_opcode: [u64; 256],
+}
+
+impl CycleCounter {
+    pub fn new() -> CycleCounter {
+        CycleCounter {
+            total: 0,
+            by_opcode: [0; 256],
+        }
+    }
+
+    /// Counts cycles, stopping at `u64::MAX` rather than wrapping around
+    pub fn add(&mut self, opcode: u8, cycles: u64) {
+        self.total = self.total.saturating_add(cycles);
+        let by_opcode = &mut self.by_opcode[opcode as usize];
+        *by_opcode = by_opcode.saturating_add(cycles);
+    }
+
+    /// Takes back cycles previously added, when stepping backwards
+    pub fn remove(&mut self, opcode: u8, cycles: u64) {
+        self.total = self.total.saturating_sub(cycles);
+        let by_opcode = &mut self.by_opcode[opcode as usize];
+        *by_opcode = by_opcode.saturating_sub(cycles);
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Cycles spent per opcode byte, for the opcodes that were executed
+    pub fn by_opcode(&self) -> Vec<(u8, u64)> {
+        self.by_opcode
+            .iter()
+            .enumerate()
+            .filter(|(_, &cycles)| cycles > 0)
+            .map(|(byte, &cycles)| (byte as u8, cycles))
+            .collect()
+    }
+
+    /// Breakdown of the cycles spent per opcode, most expensive first
+    pub fn report(&self) -> String {
+        let mut by_opcode: Vec<(Opcode, u64)> = self
+            .by_opcode()
+            .into_iter()
+            .map(|(byte, cycles)| (Opcode::from(byte), cycles))
+            .collect();
+        by_opcode.sort_by_key(|&(_, cycles)| std::cmp::Reverse(cycles));
+
+        let mut report = String::new();
+        let _ = writeln!(report, "Cycles: {}", self.total);
+        for (opcode, cycles) in by_opcode {
+            let _ = writeln!(report, "  {:<8} {:>12}", opcode.to_string(), cycles);
+        }
+        report
+    }
+}
+
+impl Default for CycleCounter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_costs() {
+        let table = CostTable::new();
+        assert_eq!(table.cost(Opcode::ADD as u8), 1);
+        assert_eq!(table.cost(Opcode::MUL as u8), 3);
+        assert_eq!(table.cost(Opcode::DIV as u8), 10);
+    }
+
+    #[test]
+    fn test_parse_cost_table() {
+        let table = CostTable::parse("; memory is slow\nALOC 50\n\nadd 2\n").unwrap();
+        assert_eq!(table.cost(Opcode::ALOC as u8), 50);
+        assert_eq!(table.cost(Opcode::ADD as u8), 2);
+        assert_eq!(table.cost(Opcode::MUL as u8), 3);
+
+        assert!(CostTable::parse("NOPE 1").is_err());
+        assert!(CostTable::parse("ADD x").is_err());
+        assert!(CostTable::parse("ADD 1 2").is_err());
+        assert!(CostTable::parse("ADD 18446744073709551615").is_err());
+    }
+
+    #[test]
+    fn test_cycle_report() {
+        let mut counter = CycleCounter::new();
+        counter.add(Opcode::ADD as u8, 1);
+        counter.add(Opcode::DIV as u8, 10);
+        counter.add(Opcode::ADD as u8, 1);
+        assert_eq!(counter.total(), 12);
+        let report = counter.report();
+        assert!(report.starts_with("Cycles: 12\n  DIV"));
+
+        counter.add(Opcode::ADD as u8, u64::MAX);
+        assert_eq!(counter.total(), u64::MAX);
+    }
+}
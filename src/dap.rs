@@ -0,0 +1,561 @@
+//! Debug Adapter Protocol server, for debugging programs from an editor.
+//!
+//! The adapter talks to the editor over the standard input and output, one
+//! JSON message at a time, each preceded by a `Content-Length` header. It
+//! debugs the assembly file given as `program` to the `launch` request.
+//! Breakpoints are set on source lines, which the source map of the assembler
+//! turns into instruction addresses, and stepping runs one instruction, which
+//! is one line of source.
+//!
+//! The program runs on the thread reading the requests, which cannot read a
+//! pause request meanwhile. It runs at most `DebugAdapter::run_budget`
+//! instructions at a time instead, then stops with the `pause` reason so that
+//! a program that never ends leaves the editor able to continue or
+//! disconnect. It has no scheduler: YIELD does nothing while the other
+//! process management and messaging instructions fault, as with `VM::run`.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value as Json};
+
+use crate::{
+    assembler::{source_map::SourceMap, symbol::SymbolTable, Assembler},
+    protocol::{read_body, write_message},
+    register::{self, REGISTER_COUNT},
+    repl::dump,
+    vm::{policy::VmPolicy, ArithmeticMode, ProcessRequest, VMError, VM},
+};
+
+/// The program runs as a single thread
+const THREAD_ID: u64 = 1;
+
+/// Instructions the program runs before pausing by default
+pub const DEFAULT_RUN_BUDGET: u64 = 10_000_000;
+
+/// References of the scopes of the only stack frame
+const REGISTERS_REFERENCE: u64 = 1;
+const FLAGS_REFERENCE: u64 = 2;
+const HEAP_REFERENCE: u64 = 3;
+
+fn scope(name: &str, reference: u64, expensive: bool) -> Json {
+    json!({ "name": name, "variablesReference": reference, "expensive": expensive })
+}
+
+fn variable(name: &str, value: String) -> Json {
+    json!({ "name": name, "value": value, "variablesReference": 0 })
+}
+
+/// Why the program stopped running
+#[derive(Debug, PartialEq)]
+enum Stop {
+    Step,
+    Breakpoint,
+    /// The program ran as many instructions as it may at a time
+    Paused,
+    Halted,
+    Faulted(VMError),
+}
+
+/// A launched program
+struct Session {
+    vm: VM,
+    /// Path of the source of the program
+    path: PathBuf,
+    source_map: SourceMap,
+    symbols: SymbolTable,
+    /// Addresses of the instructions to stop at
+    breakpoints: BTreeSet<usize>,
+    /// Whether to stop before the first instruction instead of running
+    stop_on_entry: bool,
+    /// The fault the program stopped on, it cannot run any further
+    fault: Option<VMError>,
+    /// Whether the client was told the program stopped at the current
+    /// instruction, which resuming runs even if it has a breakpoint
+    stopped: bool,
+}
+
+impl Session {
+    /// Runs one instruction with `step`, or until a breakpoint otherwise,
+    /// running at most `budget` instructions
+    fn run(&mut self, step: bool, mut budget: u64) -> Stop {
+        let mut resuming = self.stopped;
+        self.stopped = true;
+        loop {
+            let pc = self.vm.pc();
+            if !resuming && self.breakpoints.contains(&pc) {
+                return Stop::Breakpoint;
+            }
+            if budget == 0 {
+                return Stop::Paused;
+            }
+            budget -= 1;
+            resuming = false;
+            match self.vm.run_once() {
+                Ok(true) => return Stop::Halted,
+                Ok(false) => {}
+                Err(e) => return Stop::Faulted(e),
+            }
+            match self.vm.take_request() {
+                None | Some(ProcessRequest::Yield) => {}
+                Some(_) => return Stop::Faulted(VMError::NoScheduler { pc }),
+            }
+            if step {
+                return Stop::Step;
+            }
+        }
+    }
+
+    /// Whether `path` is the source of the program
+    fn is_source(&self, path: &str) -> bool {
+        canonical(Path::new(path)) == self.path
+    }
+
+    fn stack_trace(&self) -> Json {
+        let pc = self.vm.pc();
+        let name = match self.symbols.nearest_label(pc as u32) {
+            Some((label, address)) if address == pc as u32 => label.to_string(),
+            Some((label, address)) => format!("{}+{}", label, pc as u32 - address),
+            None => format!("{:06x}", pc),
+        };
+        let mut frame = json!({
+            "id": 0,
+            "name": name,
+            "line": 0,
+            "column": 0,
+            "instructionPointerReference": format!("0x{:x}", pc),
+        });
+        if let Some(location) = self.source_map.location(pc) {
+            frame["line"] = json!(location.line);
+            frame["column"] = json!(location.column);
+            frame["source"] = json!({
+                "name": self.path.file_name().map(|name| name.to_string_lossy()),
+                "path": self.path,
+            });
+        }
+        json!({ "stackFrames": [frame], "totalFrames": 1 })
+    }
+
+    fn variables(&self, reference: u64) -> Vec<Json> {
+        match reference {
+            REGISTERS_REFERENCE => (0..REGISTER_COUNT)
+                .map(|r| {
+                    let value = self.vm.register_value(r);
+                    variable(
+                        &register::register_name(r as u8),
+                        format!("{} (0x{:08x})", value, value.bits()),
+                    )
+                })
+                .collect(),
+            FLAGS_REFERENCE => {
+                let flags = self.vm.flags();
+                [
+                    ("zero", flags.zero),
+                    ("negative", flags.negative),
+                    ("carry", flags.carry),
+                    ("overflow", flags.overflow),
+                ]
+                .into_iter()
+                .map(|(name, set)| variable(name, set.to_string()))
+                .collect()
+            }
+            HEAP_REFERENCE => dump::heap(self.vm.heap(), 0..usize::MAX)
+                .lines()
+                .filter_map(|line| line.split_once("  "))
+                .map(|(address, bytes)| variable(address, bytes.to_string()))
+                .collect(),
+            _ => vec![],
+        }
+    }
+}
+
+pub struct DebugAdapter<W: Write> {
+    /// Where the messages to the editor are written
+    out: W,
+    /// Sequence number of the last message sent
+    seq: u64,
+    /// The program being debugged, once launched
+    session: Option<Session>,
+    /// Restrictions on the debugged program
+    pub policy: VmPolicy,
+    pub arithmetic_mode: ArithmeticMode,
+    /// Instructions the program runs before pausing
+    pub run_budget: u64,
+}
+
+impl<W: Write> DebugAdapter<W> {
+    pub fn new(out: W) -> DebugAdapter<W> {
+        DebugAdapter {
+            out,
+            seq: 0,
+            session: None,
+            policy: VmPolicy::new(),
+            arithmetic_mode: ArithmeticMode::default(),
+            run_budget: DEFAULT_RUN_BUDGET,
+        }
+    }
+
+    /// Handles requests until the editor disconnects or the input ends
+    pub fn run(&mut self, input: &mut impl BufRead) -> io::Result<()> {
+        while let Some(body) = read_body(input)? {
+            let message: Json = match serde_json::from_slice(&body) {
+                Ok(message) => message,
+                Err(e) => {
+                    self.respond(&Json::Null, Err(format!("Invalid message: {}", e)))?;
+                    continue;
+                }
+            };
+            if message["type"] == "request" && !self.handle_request(&message)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Answers a request, returning whether to keep on handling requests
+    fn handle_request(&mut self, request: &Json) -> io::Result<bool> {
+        let command = request["command"].as_str().unwrap_or_default();
+        let arguments = &request["arguments"];
+        let result = match command {
+            "initialize" => Ok(json!({ "supportsConfigurationDoneRequest": true })),
+            "launch" => self.launch(arguments),
+            "setBreakpoints" => Ok(self.set_breakpoints(arguments)),
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] })),
+            "stackTrace" => self.session().map(Session::stack_trace),
+            "scopes" => Ok(json!({ "scopes": [
+                scope("Registers", REGISTERS_REFERENCE, false),
+                scope("Flags", FLAGS_REFERENCE, false),
+                scope("Heap", HEAP_REFERENCE, true),
+            ] })),
+            "variables" => self.session().map(|session| {
+                let reference = arguments["variablesReference"].as_u64().unwrap_or(0);
+                json!({ "variables": session.variables(reference) })
+            }),
+            "continue" => Ok(json!({ "allThreadsContinued": true })),
+            "configurationDone" | "next" | "stepIn" | "disconnect" => Ok(Json::Null),
+            _ => Err(format!("Unsupported request {}", command)),
+        };
+        let succeeded = result.is_ok();
+        self.respond(request, result)?;
+
+        // Events caused by the request follow its response
+        match command {
+            "launch" if succeeded => self.send_event("initialized", json!({}))?,
+            "configurationDone" => match &self.session {
+                Some(session) if session.stop_on_entry => self.stopped("entry", None)?,
+                Some(_) => self.resume(false)?,
+                None => {}
+            },
+            "continue" => self.resume(false)?,
+            "next" | "stepIn" => self.resume(true)?,
+            "disconnect" => return Ok(false),
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    fn session(&self) -> Result<&Session, String> {
+        self.session
+            .as_ref()
+            .ok_or_else(|| "No program was launched".to_string())
+    }
+
+    /// Assembles and loads the program, without running it until the
+    /// configuration is done
+    fn launch(&mut self, arguments: &Json) -> Result<Json, String> {
+        let path = arguments["program"]
+            .as_str()
+            .ok_or("The launch arguments have no program")?;
+        let source =
+            fs::read_to_string(path).map_err(|e| format!("Unable to read {}: {}", path, e))?;
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble(&source)
+            .map_err(|e| format!("Unable to assemble {}: {:?}", path, e))?;
+        let mut vm = VM::new();
+        vm.arithmetic_mode = self.arithmetic_mode;
+        vm.set_policy(self.policy.clone());
+        // The output is sent in events, the standard output is for messages
+        vm.capture_output();
+        vm.add_bytes(program);
+        vm.verify_header();
+        let stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or(false);
+        self.session = Some(Session {
+            vm,
+            path: canonical(Path::new(path)),
+            source_map: asm.source_map,
+            symbols: asm.symbols,
+            breakpoints: BTreeSet::new(),
+            stop_on_entry,
+            fault: None,
+            stopped: stop_on_entry,
+        });
+        Ok(Json::Null)
+    }
+
+    /// Replaces the breakpoints of a source, moving each of them to the first
+    /// line holding an instruction
+    fn set_breakpoints(&mut self, arguments: &Json) -> Json {
+        let lines: Vec<u64> = arguments["breakpoints"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|breakpoint| breakpoint["line"].as_u64())
+            .collect();
+        let path = arguments["source"]["path"].as_str().unwrap_or_default();
+        let breakpoints: Vec<Json> = match &mut self.session {
+            Some(session) if session.is_source(path) => {
+                session.breakpoints.clear();
+                lines
+                    .iter()
+                    .map(|&line| match session.source_map.address(line as usize) {
+                        Some((address, location)) => {
+                            session.breakpoints.insert(address);
+                            json!({ "verified": true, "line": location.line })
+                        }
+                        None => json!({
+                            "verified": false,
+                            "line": line,
+                            "message": "No instruction on or after this line",
+                        }),
+                    })
+                    .collect()
+            }
+            _ => lines
+                .iter()
+                .map(|&line| {
+                    json!({
+                        "verified": false,
+                        "line": line,
+                        "message": "Not the source of the launched program",
+                    })
+                })
+                .collect(),
+        };
+        json!({ "breakpoints": breakpoints })
+    }
+
+    /// Runs the program until it stops, and tells the editor why
+    fn resume(&mut self, step: bool) -> io::Result<()> {
+        let Some(session) = &mut self.session else {
+            return Ok(());
+        };
+        let stop = match &session.fault {
+            Some(_) => Stop::Halted,
+            None => session.run(step, self.run_budget),
+        };
+        let output = session.vm.take_output();
+        if let Stop::Faulted(e) = &stop {
+            session.fault = Some(e.clone());
+        }
+        let failed = session.fault.is_some();
+        if !output.is_empty() {
+            let output = String::from_utf8_lossy(&output).into_owned();
+            self.send_event("output", json!({ "category": "stdout", "output": output }))?;
+        }
+        match stop {
+            Stop::Step => self.stopped("step", None),
+            Stop::Breakpoint => self.stopped("breakpoint", None),
+            Stop::Paused => {
+                let text = format!("Paused after {} instructions", self.run_budget);
+                self.stopped("pause", Some(text))
+            }
+            Stop::Faulted(e) => self.stopped("exception", Some(format!("The VM faulted: {:?}", e))),
+            Stop::Halted => {
+                self.send_event("exited", json!({ "exitCode": failed as i32 }))?;
+                self.send_event("terminated", json!({}))
+            }
+        }
+    }
+
+    fn stopped(&mut self, reason: &str, text: Option<String>) -> io::Result<()> {
+        let mut body = json!({ "reason": reason, "threadId": THREAD_ID });
+        if let Some(text) = text {
+            body["description"] = json!(text);
+            body["text"] = json!(text);
+        }
+        self.send_event("stopped", body)
+    }
+
+    fn respond(&mut self, request: &Json, result: Result<Json, String>) -> io::Result<()> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(Json::Null) => {}
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.send(response)
+    }
+
+    fn send_event(&mut self, event: &str, body: Json) -> io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn send(&mut self, mut message: Json) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        write_message(&mut self.out, &message)
+    }
+}
+
+/// Resolves `path` so that different spellings of it compare equal
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::read_message;
+    use std::io::Cursor;
+
+    fn messages(output: &[u8]) -> Vec<Json> {
+        let mut input = Cursor::new(output);
+        std::iter::from_fn(|| read_message(&mut input).unwrap()).collect()
+    }
+
+    /// Framed requests of the given commands and arguments
+    fn framed(requests: &[(&str, Json)]) -> Vec<u8> {
+        let mut input = vec![];
+        for (seq, (command, arguments)) in requests.iter().enumerate() {
+            let request = json!({
+                "seq": seq + 1,
+                "type": "request",
+                "command": command,
+                "arguments": arguments,
+            });
+            write_message(&mut input, &request).unwrap();
+        }
+        input
+    }
+
+    #[test]
+    fn test_debug_session() {
+        let path = std::env::temp_dir().join(format!("vm-dap-test-{}.asm", std::process::id()));
+        fs::write(
+            &path,
+            ".code\nLOAD $0 #1\nLOAD $1 #2\n\nADD $0 $1 $2\n\
+             LOAD $3 #33\nSYSCALL $0 $3 $zero\nHLT\n",
+        )
+        .unwrap();
+        let path = path.to_str().unwrap();
+        let requests = [
+            ("initialize", json!({})),
+            ("launch", json!({ "program": path })),
+            (
+                "setBreakpoints",
+                json!({
+                    "source": { "path": path },
+                    "breakpoints": [{ "line": 2 }, { "line": 4 }, { "line": 20 }],
+                }),
+            ),
+            ("configurationDone", json!({})),
+            ("stackTrace", json!({ "threadId": THREAD_ID })),
+            ("continue", json!({ "threadId": THREAD_ID })),
+            ("stackTrace", json!({ "threadId": THREAD_ID })),
+            (
+                "variables",
+                json!({ "variablesReference": REGISTERS_REFERENCE }),
+            ),
+            ("next", json!({ "threadId": THREAD_ID })),
+            ("stackTrace", json!({ "threadId": THREAD_ID })),
+            ("continue", json!({ "threadId": THREAD_ID })),
+            ("disconnect", json!({})),
+            ("threads", json!({})),
+        ];
+        let mut adapter = DebugAdapter::new(vec![]);
+        adapter.run(&mut Cursor::new(framed(&requests))).unwrap();
+        let _ = fs::remove_file(path);
+
+        let messages = messages(&adapter.out);
+        let response = |command: &str, nth: usize| {
+            messages
+                .iter()
+                .filter(|m| m["type"] == "response" && m["command"] == command)
+                .nth(nth)
+                .unwrap_or_else(|| panic!("No response to {}", command))
+        };
+        let events: Vec<&str> = messages
+            .iter()
+            .filter_map(|m| m["event"].as_str())
+            .collect();
+        assert_eq!(
+            events,
+            [
+                "initialized",
+                "stopped",
+                "stopped",
+                "stopped",
+                "output",
+                "exited",
+                "terminated"
+            ]
+        );
+        assert!(messages.iter().all(|m| m["command"] != "threads"));
+
+        let breakpoints = &response("setBreakpoints", 0)["body"]["breakpoints"];
+        assert_eq!(breakpoints[0], json!({ "verified": true, "line": 2 }));
+        assert_eq!(breakpoints[1], json!({ "verified": true, "line": 5 }));
+        assert_eq!(breakpoints[2]["verified"], false);
+
+        // The breakpoint on the first instruction stops before it runs, and
+        // continuing from it goes on to the next one
+        assert_eq!(
+            response("stackTrace", 0)["body"]["stackFrames"][0]["line"],
+            2
+        );
+        assert_eq!(
+            response("stackTrace", 1)["body"]["stackFrames"][0]["line"],
+            5
+        );
+        let registers = &response("variables", 0)["body"]["variables"];
+        assert_eq!(registers[1]["name"], "$1");
+        assert_eq!(registers[1]["value"], "2 (0x00000002)");
+        assert_eq!(
+            response("stackTrace", 2)["body"]["stackFrames"][0]["line"],
+            6
+        );
+        let output = messages.iter().find(|m| m["event"] == "output").unwrap();
+        assert_eq!(output["body"]["output"], "!");
+    }
+
+    #[test]
+    fn test_endless_program_and_invalid_message() {
+        let path = std::env::temp_dir().join(format!("vm-dap-loop-{}.asm", std::process::id()));
+        fs::write(&path, ".code\nloop: LOAD $1 @loop\nJMP $1\n").unwrap();
+        let path = path.to_str().unwrap();
+        // A malformed message is answered, and the adapter keeps going
+        let mut input = b"Content-Length: 9\r\n\r\n{not json".to_vec();
+        input.extend(framed(&[
+            ("launch", json!({ "program": path })),
+            ("configurationDone", json!({})),
+            ("continue", json!({ "threadId": THREAD_ID })),
+            ("disconnect", json!({})),
+        ]));
+        let mut adapter = DebugAdapter::new(vec![]);
+        adapter.run_budget = 1000;
+        adapter.run(&mut Cursor::new(input)).unwrap();
+        let _ = fs::remove_file(path);
+
+        let messages = messages(&adapter.out);
+        assert_eq!(messages[0]["success"], false);
+        assert!(messages[0]["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("Invalid message"));
+        let stops: Vec<&Json> = messages
+            .iter()
+            .filter(|m| m["event"] == "stopped")
+            .collect();
+        assert_eq!(stops.len(), 2);
+        assert_eq!(stops[0]["body"]["reason"], "pause");
+        assert_eq!(adapter.session.unwrap().vm.cycles().total(), 2000);
+    }
+}
@@ -0,0 +1,163 @@
+use std::fmt;
+
+/// Status register holding the condition flags set by the last comparison or
+/// arithmetic instruction. Conditional jumps are decided on these flags only.
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct Flags {
+    /// The result was zero
+    pub zero: bool,
+    /// The result was negative when read as a signed integer
+    pub negative: bool,
+    /// The operation overflowed (or borrowed) when read as unsigned integers
+    pub carry: bool,
+    /// The operation overflowed when read as signed integers
+    pub overflow: bool,
+}
+
+impl Flags {
+    pub fn new(result: i32, carry: bool, overflow: bool) -> Flags {
+        Flags {
+            zero: result == 0,
+            negative: result < 0,
+            carry,
+            overflow,
+        }
+    }
+
+    /// Packs the flags in the low nibble of a byte, as `NZCV`
+    pub fn bits(&self) -> u8 {
+        (self.negative as u8) << 3
+            | (self.zero as u8) << 2
+            | (self.carry as u8) << 1
+            | self.overflow as u8
+    }
+
+    pub fn from_bits(bits: u8) -> Flags {
+        Flags {
+            negative: bits & 0b1000 != 0,
+            zero: bits & 0b0100 != 0,
+            carry: bits & 0b0010 != 0,
+            overflow: bits & 0b0001 != 0,
+        }
+    }
+
+    /// The compared operands were equal
+    pub fn equal(&self) -> bool {
+        self.zero
+    }
+
+    /// The first compared operand was less than the second, as signed integers
+    pub fn less_than(&self) -> bool {
+        self.negative != self.overflow
+    }
+
+    /// The first compared operand was greater than the second, as signed integers
+    pub fn greater_than(&self) -> bool {
+        !self.zero && !self.less_than()
+    }
+
+    /// The first compared operand was below the second, as unsigned integers
+    pub fn below(&self) -> bool {
+        self.carry
+    }
+
+    /// The first compared operand was above the second, as unsigned integers
+    pub fn above(&self) -> bool {
+        !self.zero && !self.carry
+    }
+
+    /// Flags for `a + b`, along with the wrapped result
+    pub fn add(a: i32, b: i32) -> (i32, Flags) {
+        let (result, overflow) = a.overflowing_add(b);
+        let (_, carry) = (a as u32).overflowing_add(b as u32);
+        (result, Flags::new(result, carry, overflow))
+    }
+
+    /// Flags for `a - b`, along with the wrapped result. The carry flag is set
+    /// when the subtraction borrows.
+    pub fn sub(a: i32, b: i32) -> (i32, Flags) {
+        let (result, overflow) = a.overflowing_sub(b);
+        let (_, carry) = (a as u32).overflowing_sub(b as u32);
+        (result, Flags::new(result, carry, overflow))
+    }
+
+    /// Flags for `a * b`, along with the wrapped result
+    pub fn mul(a: i32, b: i32) -> (i32, Flags) {
+        let (result, overflow) = a.overflowing_mul(b);
+        let (_, carry) = (a as u32).overflowing_mul(b as u32);
+        (result, Flags::new(result, carry, overflow))
+    }
+}
+
+impl fmt::Display for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Z={} N={} C={} V={}",
+            self.zero as u8, self.negative as u8, self.carry as u8, self.overflow as u8
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_flags() {
+        let (result, flags) = Flags::add(2, 3);
+        assert_eq!(result, 5);
+        assert_eq!(flags, Flags::default());
+
+        let (result, flags) = Flags::add(i32::MAX, 1);
+        assert_eq!(result, i32::MIN);
+        assert!(flags.overflow && flags.negative && !flags.carry);
+
+        let (result, flags) = Flags::add(-1, 1);
+        assert_eq!(result, 0);
+        assert!(flags.zero && flags.carry && !flags.overflow);
+    }
+
+    #[test]
+    fn test_sub_flags() {
+        let (result, flags) = Flags::sub(1, 2);
+        assert_eq!(result, -1);
+        assert!(flags.negative && flags.carry && !flags.overflow);
+
+        let (result, flags) = Flags::sub(i32::MIN, 1);
+        assert_eq!(result, i32::MAX);
+        assert!(flags.overflow && !flags.carry);
+    }
+
+    #[test]
+    fn test_mul_flags() {
+        let (result, flags) = Flags::mul(1 << 16, 1 << 16);
+        assert_eq!(result, 0);
+        assert!(flags.zero && flags.carry && flags.overflow);
+    }
+
+    #[test]
+    fn test_bits_round_trip() {
+        let flags = Flags {
+            zero: true,
+            negative: false,
+            carry: true,
+            overflow: false,
+        };
+        assert_eq!(flags.bits(), 0b0110);
+        assert_eq!(Flags::from_bits(flags.bits()), flags);
+        assert_eq!(flags.to_string(), "Z=1 N=0 C=1 V=0");
+    }
+
+    #[test]
+    fn test_comparisons() {
+        let (_, flags) = Flags::sub(i32::MIN, 1);
+        assert!(flags.less_than());
+        assert!(flags.above());
+
+        let (_, flags) = Flags::sub(3, 3);
+        assert!(flags.equal());
+        assert!(!flags.less_than() && !flags.greater_than());
+        assert!(!flags.above() && !flags.below());
+    }
+}
@@ -1,21 +1,62 @@
+use std::fmt;
+
+use crate::register;
+
+/// Size in bytes of every encoded instruction: the opcode followed by up to
+/// three bytes of operands, padded with zeroes
+pub const INSTRUCTION_LENGTH: usize = 4;
+
+/// Instruction opcodes. The discriminant of each variant is the byte it is
+/// encoded as.
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Opcode {
-    LOAD,
-    ADD,
-    SUB,
-    MUL,
-    DIV,
-    HLT,
-    JMP,
-    JMPF,
-    JMPB,
-    EQ,
-    JEQ,
-    JNEQ,
-    ALOC,
-    INC,
-    DEC,
-    IGL,
+    LOAD = 0,
+    ADD = 1,
+    SUB = 2,
+    MUL = 3,
+    DIV = 4,
+    JMP = 5,
+    JMPF = 6,
+    JMPB = 7,
+    EQ = 8,
+    JEQ = 9,
+    JNEQ = 10,
+    HLT = 11,
+    ALOC = 12,
+    INC = 13,
+    DEC = 14,
+    JZ = 15,
+    JN = 16,
+    JC = 17,
+    JO = 18,
+    JGT = 19,
+    JLT = 20,
+    JGE = 21,
+    JLE = 22,
+    JA = 23,
+    JB = 24,
+    CYC = 25,
+    SPAWN = 26,
+    YIELD = 27,
+    JOIN = 28,
+    SEND = 29,
+    SENDB = 30,
+    RECV = 31,
+    TRYRECV = 32,
+    NEWARR = 33,
+    NEWSTR = 34,
+    NEWREC = 35,
+    GET = 36,
+    SET = 37,
+    LEN = 38,
+    TYPE = 39,
+    MOV = 40,
+    PUSH = 41,
+    POP = 42,
+    GC = 43,
+    FREE = 44,
+    SYSCALL = 45,
+    IGL = 255,
 }
 
 impl From<u8> for Opcode {
@@ -36,6 +77,37 @@ impl From<u8> for Opcode {
             12 => Opcode::ALOC,
             13 => Opcode::INC,
             14 => Opcode::DEC,
+            15 => Opcode::JZ,
+            16 => Opcode::JN,
+            17 => Opcode::JC,
+            18 => Opcode::JO,
+            19 => Opcode::JGT,
+            20 => Opcode::JLT,
+            21 => Opcode::JGE,
+            22 => Opcode::JLE,
+            23 => Opcode::JA,
+            24 => Opcode::JB,
+            25 => Opcode::CYC,
+            26 => Opcode::SPAWN,
+            27 => Opcode::YIELD,
+            28 => Opcode::JOIN,
+            29 => Opcode::SEND,
+            30 => Opcode::SENDB,
+            31 => Opcode::RECV,
+            32 => Opcode::TRYRECV,
+            33 => Opcode::NEWARR,
+            34 => Opcode::NEWSTR,
+            35 => Opcode::NEWREC,
+            36 => Opcode::GET,
+            37 => Opcode::SET,
+            38 => Opcode::LEN,
+            39 => Opcode::TYPE,
+            40 => Opcode::MOV,
+            41 => Opcode::PUSH,
+            42 => Opcode::POP,
+            43 => Opcode::GC,
+            44 => Opcode::FREE,
+            45 => Opcode::SYSCALL,
             _ => Opcode::IGL,
         }
     }
@@ -57,18 +129,258 @@ impl From<&str> for Opcode {
             "JNEQ" => Opcode::JNEQ,
             "HLT" => Opcode::HLT,
             "ALOC" => Opcode::ALOC,
+            "INC" => Opcode::INC,
+            "DEC" => Opcode::DEC,
+            "JZ" => Opcode::JZ,
+            "JN" => Opcode::JN,
+            "JC" => Opcode::JC,
+            "JO" => Opcode::JO,
+            "JGT" => Opcode::JGT,
+            "JLT" => Opcode::JLT,
+            "JGE" => Opcode::JGE,
+            "JLE" => Opcode::JLE,
+            "JA" => Opcode::JA,
+            "JB" => Opcode::JB,
+            "CYC" => Opcode::CYC,
+            "SPAWN" => Opcode::SPAWN,
+            "YIELD" => Opcode::YIELD,
+            "JOIN" => Opcode::JOIN,
+            "SEND" => Opcode::SEND,
+            "SENDB" => Opcode::SENDB,
+            "RECV" => Opcode::RECV,
+            "TRYRECV" => Opcode::TRYRECV,
+            "NEWARR" => Opcode::NEWARR,
+            "NEWSTR" => Opcode::NEWSTR,
+            "NEWREC" => Opcode::NEWREC,
+            "GET" => Opcode::GET,
+            "SET" => Opcode::SET,
+            "LEN" => Opcode::LEN,
+            "TYPE" => Opcode::TYPE,
+            "MOV" => Opcode::MOV,
+            "PUSH" => Opcode::PUSH,
+            "POP" => Opcode::POP,
+            "GC" => Opcode::GC,
+            "FREE" => Opcode::FREE,
+            "SYSCALL" => Opcode::SYSCALL,
             _ => Opcode::IGL,
         }
     }
 }
 
+impl Opcode {
+    /// Operands expected by the instruction, in the order they are encoded
+    pub fn operands(&self) -> &'static [OperandKind] {
+        use OperandKind::*;
+        match self {
+            Opcode::LOAD => &[Register, Integer],
+            Opcode::ADD
+            | Opcode::SUB
+            | Opcode::MUL
+            | Opcode::DIV
+            | Opcode::SENDB
+            | Opcode::RECV
+            | Opcode::TRYRECV
+            | Opcode::NEWREC
+            | Opcode::GET
+            | Opcode::SET
+            | Opcode::SYSCALL => &[Register, Register, Register],
+            Opcode::EQ
+            | Opcode::SPAWN
+            | Opcode::SEND
+            | Opcode::NEWARR
+            | Opcode::NEWSTR
+            | Opcode::LEN
+            | Opcode::TYPE
+            | Opcode::MOV
+            | Opcode::ALOC => &[Register, Register],
+            Opcode::HLT | Opcode::YIELD | Opcode::GC | Opcode::IGL => &[],
+            _ => &[Register],
+        }
+    }
+}
+
+impl Opcode {
+    /// How the instruction is written in assembly, naming its register
+    /// operands `$a`, `$b` and `$c` and its integer operand `#n`
+    pub fn signature(&self) -> String {
+        let mut signature = self.to_string();
+        for (kind, name) in self.operands().iter().zip(["a", "b", "c"]) {
+            match kind {
+                OperandKind::Register => signature.push_str(&format!(" ${}", name)),
+                OperandKind::Integer => signature.push_str(" #n"),
+            }
+        }
+        signature
+    }
+
+    /// What the instruction does, with its operands named as in `signature`
+    pub fn description(&self) -> &'static str {
+        match self {
+            Opcode::LOAD => "Loads the integer or label address #n in $a",
+            Opcode::ADD => "Stores $a + $b in $c and sets the flags",
+            Opcode::SUB => "Stores $a - $b in $c and sets the flags",
+            Opcode::MUL => "Stores $a * $b in $c and sets the flags",
+            Opcode::DIV => {
+                "Stores $a / $b in $c and sets the flags, keeping the remainder. \
+                 Faults when $b is 0."
+            }
+            Opcode::JMP => "Jumps to the address held in $a",
+            Opcode::JMPF => "Jumps $a bytes forward from the next instruction",
+            Opcode::JMPB => "Jumps $a bytes backward from the next instruction",
+            Opcode::EQ => "Compares $a with $b, setting the flags as for $a - $b",
+            Opcode::JEQ => "Jumps to the address held in $a if the compared values were equal",
+            Opcode::JNEQ => "Jumps to the address held in $a if the compared values differed",
+            Opcode::HLT => "Stops the program",
+            Opcode::ALOC => "Allocates $a bytes of heap and stores their address in $b",
+            Opcode::INC => "Adds 1 to $a, leaving the carry flag untouched",
+            Opcode::DEC => "Subtracts 1 from $a, leaving the carry flag untouched",
+            Opcode::JZ => "Jumps to the address held in $a if the zero flag is set",
+            Opcode::JN => "Jumps to the address held in $a if the negative flag is set",
+            Opcode::JC => "Jumps to the address held in $a if the carry flag is set",
+            Opcode::JO => "Jumps to the address held in $a if the overflow flag is set",
+            Opcode::JGT => {
+                "Jumps to the address held in $a if the first compared value was \
+                 greater, as signed integers"
+            }
+            Opcode::JLT => {
+                "Jumps to the address held in $a if the first compared value was \
+                 less, as signed integers"
+            }
+            Opcode::JGE => {
+                "Jumps to the address held in $a if the first compared value was \
+                 greater or equal, as signed integers"
+            }
+            Opcode::JLE => {
+                "Jumps to the address held in $a if the first compared value was \
+                 less or equal, as signed integers"
+            }
+            Opcode::JA => {
+                "Jumps to the address held in $a if the first compared value was \
+                 above, as unsigned integers"
+            }
+            Opcode::JB => {
+                "Jumps to the address held in $a if the first compared value was \
+                 below, as unsigned integers"
+            }
+            Opcode::CYC => "Stores the cycles spent before this instruction in $a",
+            Opcode::SPAWN => "Starts a process at the address held in $a, storing its pid in $b",
+            Opcode::YIELD => "Gives the rest of the time slice to the other processes",
+            Opcode::JOIN => {
+                "Waits for the process whose pid is held in $a to end, storing its \
+                 exit value in $a"
+            }
+            Opcode::SEND => "Sends the word held in $b to the process whose pid is held in $a",
+            Opcode::SENDB => {
+                "Sends a copy of the $c heap bytes at the address held in $b to the \
+                 process whose pid is held in $a"
+            }
+            Opcode::RECV => {
+                "Waits for a message, storing the word or the heap address of the \
+                 buffer in $a, the pid of the sender in $b and the length of the \
+                 buffer, or -1 for a word, in $c"
+            }
+            Opcode::TRYRECV => {
+                "Receives a message like RECV, or sets the zero flag without waiting \
+                 when there is none"
+            }
+            Opcode::NEWARR => "Allocates an array of $a values, storing a reference to it in $b",
+            Opcode::NEWSTR => "Allocates a string of $a bytes, storing a reference to it in $b",
+            Opcode::NEWREC => {
+                "Allocates a record of type $a with $b fields, storing a reference \
+                 to it in $c"
+            }
+            Opcode::GET => "Stores element $b of the object referenced by $a in $c",
+            Opcode::SET => "Sets element $b of the object referenced by $a to the value of $c",
+            Opcode::LEN => "Stores the length of the object referenced by $a in $b",
+            Opcode::TYPE => "Stores the type of the object referenced by $a in $b",
+            Opcode::MOV => "Copies the number or reference held in $a to $b",
+            Opcode::PUSH => "Pushes the value of $a on the stack",
+            Opcode::POP => "Pops the value on top of the stack into $a",
+            Opcode::GC => "Frees the objects no register or stack value refers to",
+            Opcode::FREE => "Frees the heap block ALOC allocated at the address held in $a",
+            Opcode::SYSCALL => "Runs syscall number $a with argument $b, storing its result in $c",
+            Opcode::IGL => "Illegal instruction, faults the VM",
+        }
+    }
+}
+
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Kind of an encoded operand
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum OperandKind {
+    /// A register number, on one byte
+    Register,
+    /// An integer, on two big endian bytes
+    Integer,
+}
+
+impl OperandKind {
+    /// Number of bytes the operand is encoded on
+    pub fn size(&self) -> usize {
+        match self {
+            OperandKind::Register => 1,
+            OperandKind::Integer => 2,
+        }
+    }
+}
+
+/// A decoded instruction, as found in the program of the VM
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub struct Instruction {
     opcode: Opcode,
+    operands: [u8; INSTRUCTION_LENGTH - 1],
 }
 
 impl Instruction {
     pub fn new(opcode: Opcode) -> Instruction {
-        Instruction { opcode }
+        Instruction {
+            opcode,
+            operands: [0; INSTRUCTION_LENGTH - 1],
+        }
+    }
+
+    /// Decodes the instruction at the start of `bytes`, if there are enough of them
+    pub fn decode(bytes: &[u8]) -> Option<Instruction> {
+        if bytes.len() < INSTRUCTION_LENGTH {
+            return None;
+        }
+        let mut operands = [0; INSTRUCTION_LENGTH - 1];
+        operands.copy_from_slice(&bytes[1..INSTRUCTION_LENGTH]);
+        Some(Instruction {
+            opcode: Opcode::from(bytes[0]),
+            operands,
+        })
+    }
+
+    pub fn opcode(&self) -> Opcode {
+        self.opcode
+    }
+}
+
+/// Disassembles the instruction, the way it would be written in assembly
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.opcode)?;
+        let mut offset = 0;
+        for kind in self.opcode.operands() {
+            match kind {
+                OperandKind::Register => {
+                    write!(f, " {}", register::register_name(self.operands[offset]))?
+                }
+                OperandKind::Integer => {
+                    let value =
+                        u16::from_be_bytes([self.operands[offset], self.operands[offset + 1]]);
+                    write!(f, " #{}", value)?
+                }
+            }
+            offset += kind.size();
+        }
+        Ok(())
     }
 }
 
@@ -99,4 +411,32 @@ mod tests {
         let str = "illegal";
         assert_eq!(Opcode::IGL, Opcode::from(str));
     }
+
+    #[test]
+    fn test_opcode_encoding_round_trip() {
+        for byte in 0..=u8::MAX {
+            let opcode = Opcode::from(byte);
+            if opcode != Opcode::IGL {
+                assert_eq!(opcode as u8, byte);
+            }
+        }
+    }
+
+    #[test]
+    fn test_signature() {
+        assert_eq!(Opcode::LOAD.signature(), "LOAD $a #n");
+        assert_eq!(Opcode::ADD.signature(), "ADD $a $b $c");
+        assert_eq!(Opcode::HLT.signature(), "HLT");
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let instruction = Instruction::decode(&[0, 3, 1, 244]).unwrap();
+        assert_eq!(instruction.to_string(), "LOAD $3 #500");
+        let instruction = Instruction::decode(&[1, 0, 28, 31]).unwrap();
+        assert_eq!(instruction.to_string(), "ADD $0 $sp $zero");
+        let instruction = Instruction::decode(&[11, 0, 0, 0]).unwrap();
+        assert_eq!(instruction.to_string(), "HLT");
+        assert!(Instruction::decode(&[11, 0]).is_none());
+    }
 }
@@ -0,0 +1,724 @@
+//! Language server for the assembly language, speaking the Language Server
+//! Protocol over the standard input and output.
+//!
+//! Every open document is assembled whenever it changes. The error of the
+//! assembler is published as a diagnostic, while its symbol table and source
+//! map serve the navigation between label declarations and usages. Positions
+//! count characters, which are the UTF-16 code units of the protocol for the
+//! ASCII the language is written in.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+use serde_json::{json, Value as Json};
+
+use crate::{
+    assembler::{
+        source_map::SourceMap,
+        symbol::{SymbolTable, SymbolType},
+        Assembler, AssemblerError,
+    },
+    instruction::{Opcode, OperandKind},
+    protocol::{read_body, write_message},
+    register::{self, REGISTER_COUNT},
+    repl::helper::mnemonics,
+};
+
+/// Error code of a request for a method the server does not implement
+const METHOD_NOT_FOUND: i64 = -32601;
+/// Error code of a message that is not valid JSON
+const PARSE_ERROR: i64 = -32700;
+
+/// Severity of the diagnostics of assembler errors
+const SEVERITY_ERROR: u64 = 1;
+
+/// Kinds of completion items
+const KIND_VARIABLE: u64 = 6;
+const KIND_KEYWORD: u64 = 14;
+const KIND_REFERENCE: u64 = 18;
+
+/// Directives, without their `.`, and what they do
+const DIRECTIVES: [(&str, &str); 3] = [
+    (
+        "asciiz",
+        "Declares a null terminated string in the read-only section, as in \
+         `hello: .asciiz 'Hello'`",
+    ),
+    ("code", "Starts the section of instructions"),
+    ("data", "Starts the section of constants"),
+];
+
+/// Characters on one line of a document, from `start` to before `end`
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct Span {
+    line: usize,
+    start: usize,
+    end: usize,
+}
+
+impl Span {
+    fn range(&self) -> Json {
+        json!({
+            "start": { "line": self.line, "character": self.start },
+            "end": { "line": self.line, "character": self.end },
+        })
+    }
+}
+
+/// A word of the language, without its sign
+#[derive(Debug, PartialEq)]
+enum Word<'a> {
+    /// A label, where it is declared with `name:` or used with `@name`
+    Label(&'a str),
+    Register(&'a str),
+    Directive(&'a str),
+    Mnemonic(Opcode),
+}
+
+struct Document {
+    text: String,
+    /// Why the document does not assemble
+    error: Option<AssemblerError>,
+    symbols: SymbolTable,
+    source_map: SourceMap,
+}
+
+impl Document {
+    fn new(text: String) -> Document {
+        let mut asm = Assembler::new();
+        let error = asm.assemble(&text).err();
+        Document {
+            text,
+            error,
+            symbols: asm.symbols,
+            source_map: asm.source_map,
+        }
+    }
+
+    /// Finds the word at `character` on `line`, or right before it
+    fn word_at(&self, line: usize, character: usize) -> Option<(Word<'_>, Span)> {
+        let text = self.text.lines().nth(line)?;
+        let chars: Vec<char> = text.chars().collect();
+        let is_word = |i: usize| chars.get(i).is_some_and(|c| c.is_ascii_alphanumeric());
+        let mut start = character.min(chars.len());
+        if !is_word(start) && start > 0 && is_word(start - 1) {
+            start -= 1;
+        }
+        if !is_word(start) {
+            return None;
+        }
+        while start > 0 && is_word(start - 1) {
+            start -= 1;
+        }
+        let mut end = start;
+        while is_word(end) {
+            end += 1;
+        }
+        let (byte_start, byte_end) = (byte_offset(text, start), byte_offset(text, end));
+        let name = &text[byte_start..byte_end];
+        let span = Span { line, start, end };
+        let sign = start.checked_sub(1).map(|i| chars[i]);
+        let word = match sign {
+            Some('@') => Word::Label(name),
+            Some('$') => Word::Register(name),
+            Some('.') => Word::Directive(name),
+            _ if chars.get(end) == Some(&':') => Word::Label(name),
+            _ => match Opcode::from(name) {
+                Opcode::IGL => return None,
+                opcode => Word::Mnemonic(opcode),
+            },
+        };
+        Some((word, span))
+    }
+
+    /// Every label of the document, with whether it is declared there
+    fn labels(&self) -> Vec<(&str, Span, bool)> {
+        let mut labels = vec![];
+        for (line, text) in self.text.lines().enumerate() {
+            let chars: Vec<(usize, char)> = text.char_indices().collect();
+            let mut i = 0;
+            while i < chars.len() {
+                if !chars[i].1.is_ascii_alphanumeric() {
+                    i += 1;
+                    continue;
+                }
+                let start = i;
+                while i < chars.len() && chars[i].1.is_ascii_alphanumeric() {
+                    i += 1;
+                }
+                let name = &text[chars[start].0..chars.get(i).map_or(text.len(), |c| c.0)];
+                let span = Span {
+                    line,
+                    start,
+                    end: i,
+                };
+                if chars.get(i).is_some_and(|c| c.1 == ':') {
+                    labels.push((name, span, true));
+                } else if start > 0 && chars[start - 1].1 == '@' {
+                    labels.push((name, span, false));
+                }
+            }
+        }
+        labels
+    }
+
+    /// Finds where `name` is declared: on the line of the instruction it
+    /// labels when it is in the symbol table, otherwise on its first
+    /// declaration
+    fn declaration(&self, name: &str) -> Option<Span> {
+        let declarations: Vec<Span> = self
+            .labels()
+            .into_iter()
+            .filter(|(label, _, declared)| *label == name && *declared)
+            .map(|(_, span, _)| span)
+            .collect();
+        let line = self
+            .symbols
+            .symbols()
+            .find(|symbol| symbol.name() == name && *symbol.symbol_type() == SymbolType::Label)
+            .and_then(|symbol| self.source_map.location(symbol.offset() as usize))
+            .map(|location| location.line - 1);
+        line.and_then(|line| declarations.iter().find(|span| span.line == line))
+            .or(declarations.first())
+            .copied()
+    }
+
+    fn references(&self, name: &str, include_declaration: bool) -> Vec<Span> {
+        self.labels()
+            .into_iter()
+            .filter(|(label, _, declared)| *label == name && (include_declaration || !declared))
+            .map(|(_, span, _)| span)
+            .collect()
+    }
+
+    fn hover(&self, word: &Word) -> Option<String> {
+        match word {
+            Word::Mnemonic(opcode) => Some(format!(
+                "```\n{}\n```\n{}",
+                opcode.signature(),
+                opcode.description()
+            )),
+            Word::Register(name) => register::register_number(name)
+                .map(|n| format!("`{}`: register {}", register::register_name(n), n)),
+            Word::Directive(name) => DIRECTIVES
+                .iter()
+                .find(|(directive, _)| directive == name)
+                .map(|(directive, description)| format!("`.{}`: {}", directive, description)),
+            Word::Label(name) => {
+                let symbol = self.symbols.symbols().find(|s| s.name() == *name)?;
+                Some(match symbol.symbol_type() {
+                    SymbolType::Label => {
+                        format!("Label `{}` at address {:06x}", name, symbol.offset())
+                    }
+                    SymbolType::Constant => format!(
+                        "Constant `{}` at offset {} of the read-only section",
+                        name,
+                        symbol.offset()
+                    ),
+                })
+            }
+        }
+    }
+
+    /// Completes the word ending at `character` on `line`, according to the
+    /// sign before it
+    fn completion(&self, line: usize, character: usize) -> Vec<Json> {
+        let text = self.text.lines().nth(line).unwrap_or_default();
+        let before: Vec<char> = text.chars().take(character).collect();
+        let start = before
+            .iter()
+            .rposition(|c| !c.is_ascii_alphanumeric())
+            .map_or(0, |i| i + 1);
+        match start.checked_sub(1).map(|i| before[i]) {
+            Some('$') => (0..REGISTER_COUNT as u8)
+                .map(|r| {
+                    let name = register::register_name(r);
+                    completion_item(name.clone(), &name[1..], KIND_VARIABLE, "Register")
+                })
+                .collect(),
+            Some('@') => self
+                .symbols
+                .names()
+                .map(|name| completion_item(format!("@{}", name), name, KIND_REFERENCE, "Label"))
+                .collect(),
+            Some('.') => DIRECTIVES
+                .iter()
+                .map(|(name, description)| {
+                    completion_item(format!(".{}", name), name, KIND_KEYWORD, description)
+                })
+                .collect(),
+            _ => mnemonics()
+                .into_iter()
+                .map(|mnemonic| {
+                    let opcode = Opcode::from(mnemonic.as_str());
+                    let mut item = completion_item(mnemonic.clone(), &mnemonic, KIND_KEYWORD, "");
+                    item["detail"] = json!(opcode.signature());
+                    item["documentation"] = json!(opcode.description());
+                    item
+                })
+                .collect(),
+        }
+    }
+
+    fn diagnostics(&self) -> Vec<Json> {
+        let Some(error) = &self.error else {
+            return vec![];
+        };
+        let span = self.error_span(error).unwrap_or(Span {
+            line: 0,
+            start: 0,
+            end: 0,
+        });
+        vec![json!({
+            "range": span.range(),
+            "severity": SEVERITY_ERROR,
+            "source": "vm",
+            "message": describe(error),
+        })]
+    }
+
+    /// Finds where the error is, from what the assembler says about it
+    fn error_span(&self, error: &AssemblerError) -> Option<Span> {
+        match error {
+            AssemblerError::UnknownLabel { name } => self.references(name, false).first().copied(),
+            AssemblerError::SymbolAlreadyDeclared => {
+                let labels = self.labels();
+                let declarations: Vec<_> = labels.iter().filter(|(_, _, d)| *d).collect();
+                declarations
+                    .iter()
+                    .enumerate()
+                    .find(|(i, (name, _, _))| declarations[..*i].iter().any(|d| d.0 == *name))
+                    .map(|(_, (_, span, _))| *span)
+            }
+            AssemblerError::InvalidRegister { register } => self.find(register),
+            AssemblerError::InvalidOperand { operand, .. } => self.find(operand),
+            AssemblerError::AddressTooLarge { name } => {
+                self.references(name, false).first().copied()
+            }
+            AssemblerError::UnknownDirectiveFound { directive } => {
+                self.find(&format!(".{}", directive))
+            }
+            AssemblerError::ParseError { error } => {
+                if let Some(line) = error
+                    .strip_prefix("Unexpected input at line ")
+                    .and_then(|rest| rest.split(':').next())
+                    .and_then(|line| line.parse::<usize>().ok())
+                {
+                    let text = self.text.lines().nth(line - 1)?;
+                    let indent = text.chars().take_while(|c| c.is_whitespace()).count();
+                    return Some(Span {
+                        line: line - 1,
+                        start: indent,
+                        end: text.chars().count(),
+                    });
+                }
+                let (_, input) = error.split_once(" at '")?;
+                self.find(input.strip_suffix('\'')?)
+            }
+            _ => None,
+        }
+    }
+
+    /// Finds the first occurrence of `needle` on a line
+    fn find(&self, needle: &str) -> Option<Span> {
+        if needle.is_empty() {
+            return None;
+        }
+        self.text.lines().enumerate().find_map(|(line, text)| {
+            let start = text[..text.find(needle)?].chars().count();
+            Some(Span {
+                line,
+                start,
+                end: start + needle.chars().count(),
+            })
+        })
+    }
+}
+
+/// Byte offset of the character at `index` in `text`
+fn byte_offset(text: &str, index: usize) -> usize {
+    text.char_indices()
+        .nth(index)
+        .map_or(text.len(), |(i, _)| i)
+}
+
+fn describe(error: &AssemblerError) -> String {
+    match error {
+        AssemblerError::SymbolAlreadyDeclared => "This label is already declared".to_string(),
+        AssemblerError::UnknownLabel { name } => format!("Unknown label @{}", name),
+        AssemblerError::InvalidRegister { register } => format!("Unknown register {}", register),
+        AssemblerError::InvalidOperand { operand, expected } => match expected {
+            OperandKind::Register => format!("Expected a register, found {}", operand),
+            OperandKind::Integer => format!("Expected an integer or a label, found {}", operand),
+        },
+        AssemblerError::WrongOperandCount {
+            opcode,
+            expected,
+            found,
+        } => format!("{} takes {} operands, found {}", opcode, expected, found),
+        AssemblerError::AddressTooLarge { name } => {
+            format!("@{} is past the addresses an integer can hold", name)
+        }
+        AssemblerError::UnknownDirectiveFound { directive } => {
+            format!("Unknown directive .{}", directive)
+        }
+        AssemblerError::UnknownSectionFound => {
+            "Unknown section, sections are .code and .data".to_string()
+        }
+        AssemblerError::ParseError { error } => error.clone(),
+        _ => format!("{:?}", error),
+    }
+}
+
+pub struct LanguageServer<W: Write> {
+    /// Where the messages to the editor are written
+    out: W,
+    /// Open documents by URI
+    documents: HashMap<String, Document>,
+}
+
+impl<W: Write> LanguageServer<W> {
+    pub fn new(out: W) -> LanguageServer<W> {
+        LanguageServer {
+            out,
+            documents: HashMap::new(),
+        }
+    }
+
+    /// Handles messages until the editor asks the server to exit or the
+    /// input ends
+    pub fn run(&mut self, input: &mut impl BufRead) -> io::Result<()> {
+        while let Some(body) = read_body(input)? {
+            let message: Json = match serde_json::from_slice(&body) {
+                Ok(message) => message,
+                Err(e) => {
+                    self.respond_error(&Json::Null, PARSE_ERROR, &e.to_string())?;
+                    continue;
+                }
+            };
+            let method = message["method"].as_str().unwrap_or_default();
+            if method == "exit" {
+                break;
+            }
+            let params = &message["params"];
+            match message.get("id") {
+                Some(id) if message.get("method").is_some() => {
+                    let result = self.handle_request(method, params);
+                    self.respond(id, result)?;
+                }
+                // Responses to requests of the server, which sends none
+                Some(_) => {}
+                None => self.handle_notification(method, params)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_request(&mut self, method: &str, params: &Json) -> Result<Json, String> {
+        match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                    "hoverProvider": true,
+                    "completionProvider": { "triggerCharacters": ["$", "@", "."] },
+                },
+                "serverInfo": { "name": "vm" },
+            })),
+            "shutdown" => Ok(Json::Null),
+            "textDocument/definition" => Ok(self.definition(params)),
+            "textDocument/references" => Ok(self.references(params)),
+            "textDocument/hover" => Ok(self.hover(params)),
+            "textDocument/completion" => Ok(self.completion(params)),
+            _ => Err(format!("Unsupported method {}", method)),
+        }
+    }
+
+    fn handle_notification(&mut self, method: &str, params: &Json) -> io::Result<()> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        let text = match method {
+            "textDocument/didOpen" => params["textDocument"]["text"].as_str(),
+            // The whole text is synchronized, the last change holds it
+            "textDocument/didChange" => params["contentChanges"]
+                .as_array()
+                .and_then(|changes| changes.last())
+                .and_then(|change| change["text"].as_str()),
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                return self.publish_diagnostics(uri, vec![]);
+            }
+            _ => return Ok(()),
+        };
+        let Some(text) = text else {
+            return Ok(());
+        };
+        let document = Document::new(text.to_string());
+        let diagnostics = document.diagnostics();
+        self.documents.insert(uri.to_string(), document);
+        self.publish_diagnostics(uri, diagnostics)
+    }
+
+    /// Finds the document and the word a request is about
+    fn word<'a>(&'a self, params: &'a Json) -> Option<(&'a str, &'a Document, Word<'a>, Span)> {
+        let uri = params["textDocument"]["uri"].as_str()?;
+        let document = self.documents.get(uri)?;
+        let (line, character) = position(params)?;
+        let (word, span) = document.word_at(line, character)?;
+        Some((uri, document, word, span))
+    }
+
+    fn definition(&self, params: &Json) -> Json {
+        match self.word(params) {
+            Some((uri, document, Word::Label(name), _)) => document
+                .declaration(name)
+                .map_or(Json::Null, |span| location(uri, span)),
+            _ => Json::Null,
+        }
+    }
+
+    fn references(&self, params: &Json) -> Json {
+        let include_declaration = params["context"]["includeDeclaration"]
+            .as_bool()
+            .unwrap_or(true);
+        match self.word(params) {
+            Some((uri, document, Word::Label(name), _)) => document
+                .references(name, include_declaration)
+                .into_iter()
+                .map(|span| location(uri, span))
+                .collect(),
+            _ => json!([]),
+        }
+    }
+
+    fn hover(&self, params: &Json) -> Json {
+        self.word(params)
+            .and_then(|(_, document, word, span)| {
+                let value = document.hover(&word)?;
+                Some(json!({
+                    "contents": { "kind": "markdown", "value": value },
+                    "range": span.range(),
+                }))
+            })
+            .unwrap_or(Json::Null)
+    }
+
+    fn completion(&self, params: &Json) -> Json {
+        let document = params["textDocument"]["uri"]
+            .as_str()
+            .and_then(|uri| self.documents.get(uri));
+        match (document, position(params)) {
+            (Some(document), Some((line, character))) => {
+                json!(document.completion(line, character))
+            }
+            _ => json!([]),
+        }
+    }
+
+    fn publish_diagnostics(&mut self, uri: &str, diagnostics: Vec<Json>) -> io::Result<()> {
+        let notification = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": uri, "diagnostics": diagnostics },
+        });
+        write_message(&mut self.out, &notification)
+    }
+
+    fn respond(&mut self, id: &Json, result: Result<Json, String>) -> io::Result<()> {
+        match result {
+            Ok(result) => write_message(
+                &mut self.out,
+                &json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            ),
+            Err(message) => self.respond_error(id, METHOD_NOT_FOUND, &message),
+        }
+    }
+
+    fn respond_error(&mut self, id: &Json, code: i64, message: &str) -> io::Result<()> {
+        let error = json!({ "code": code, "message": message });
+        write_message(
+            &mut self.out,
+            &json!({ "jsonrpc": "2.0", "id": id, "error": error }),
+        )
+    }
+}
+
+/// A completion inserting `insert` after the sign the user typed
+fn completion_item(label: String, insert: &str, kind: u64, detail: &str) -> Json {
+    json!({ "label": label, "insertText": insert, "kind": kind, "detail": detail })
+}
+
+fn position(params: &Json) -> Option<(usize, usize)> {
+    let position = &params["position"];
+    Some((
+        position["line"].as_u64()? as usize,
+        position["character"].as_u64()? as usize,
+    ))
+}
+
+fn location(uri: &str, span: Span) -> Json {
+    json!({ "uri": uri, "range": span.range() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::read_message;
+    use std::io::{Cursor, Write};
+
+    const SOURCE: &str = ".data\n\
+                          hello: .asciiz 'Hi'\n\
+                          .code\n\
+                          LOAD $1 @loop\n\
+                          loop: DEC $0\n\
+                          JMP $1\n\
+                          LOAD $2 @loop\n";
+
+    fn span(line: usize, start: usize, end: usize) -> Span {
+        Span { line, start, end }
+    }
+
+    #[test]
+    fn test_word_at() {
+        let document = Document::new(SOURCE.to_string());
+        assert_eq!(
+            document.word_at(3, 10),
+            Some((Word::Label("loop"), span(3, 9, 13)))
+        );
+        assert_eq!(
+            document.word_at(4, 4),
+            Some((Word::Label("loop"), span(4, 0, 4)))
+        );
+        assert_eq!(
+            document.word_at(4, 6),
+            Some((Word::Mnemonic(Opcode::DEC), span(4, 6, 9)))
+        );
+        assert_eq!(
+            document.word_at(5, 5),
+            Some((Word::Register("1"), span(5, 5, 6)))
+        );
+        assert_eq!(
+            document.word_at(2, 1),
+            Some((Word::Directive("code"), span(2, 1, 5)))
+        );
+        assert_eq!(document.word_at(3, 8), None);
+    }
+
+    #[test]
+    fn test_declaration_and_references() {
+        let document = Document::new(SOURCE.to_string());
+        assert_eq!(document.declaration("loop"), Some(span(4, 0, 4)));
+        assert_eq!(document.declaration("hello"), Some(span(1, 0, 5)));
+        assert_eq!(
+            document.references("loop", false),
+            [span(3, 9, 13), span(6, 9, 13)]
+        );
+        assert_eq!(document.references("loop", true).len(), 3);
+    }
+
+    #[test]
+    fn test_hover() {
+        let document = Document::new(SOURCE.to_string());
+        assert_eq!(
+            document.hover(&Word::Mnemonic(Opcode::DEC)).unwrap(),
+            "```\nDEC $a\n```\nSubtracts 1 from $a, leaving the carry flag untouched"
+        );
+        assert_eq!(
+            document.hover(&Word::Register("sp")).unwrap(),
+            "`$sp`: register 28"
+        );
+        assert_eq!(
+            document.hover(&Word::Label("loop")).unwrap(),
+            "Label `loop` at address 000044"
+        );
+        assert!(document.hover(&Word::Label("nowhere")).is_none());
+    }
+
+    #[test]
+    fn test_completion() {
+        let document = Document::new(SOURCE.to_string());
+        let labels = |items: Vec<Json>| -> Vec<String> {
+            items
+                .iter()
+                .map(|item| item["label"].as_str().unwrap().to_string())
+                .collect()
+        };
+        assert!(labels(document.completion(5, 5)).contains(&"$sp".to_string()));
+        assert_eq!(labels(document.completion(3, 10)), ["@hello", "@loop"]);
+        assert_eq!(
+            labels(document.completion(2, 1)),
+            [".asciiz", ".code", ".data"]
+        );
+        let mnemonics = document.completion(5, 2);
+        let jmp = mnemonics
+            .iter()
+            .find(|item| item["label"] == "JMP")
+            .unwrap();
+        assert_eq!(jmp["detail"], "JMP $a");
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let document = Document::new(".code\nLOAD $0 @nowhere\nJMP $0\n".to_string());
+        let diagnostics = document.diagnostics();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0]["message"], "Unknown label @nowhere");
+        assert_eq!(diagnostics[0]["range"], span(1, 9, 16).range());
+
+        let document = Document::new(".code\nJMP @end\nend: HLT\n".to_string());
+        let diagnostics = document.diagnostics();
+        assert_eq!(diagnostics[0]["message"], "Expected a register, found @end");
+        assert_eq!(diagnostics[0]["range"], span(1, 4, 8).range());
+
+        let document = Document::new(".code\nHLT\n  ???\n".to_string());
+        assert_eq!(document.diagnostics()[0]["range"], span(2, 2, 5).range());
+
+        let document = Document::new(".code\na: HLT\na: HLT\n".to_string());
+        assert_eq!(document.diagnostics()[0]["range"], span(2, 0, 1).range());
+
+        assert!(Document::new(SOURCE.to_string()).diagnostics().is_empty());
+    }
+
+    #[test]
+    fn test_session() {
+        let messages = [
+            json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {} }),
+            json!({ "jsonrpc": "2.0", "method": "textDocument/didOpen", "params": {
+                "textDocument": { "uri": "file:///a.asm", "text": ".code\nLOAD $0 @end\n" },
+            } }),
+            json!({ "jsonrpc": "2.0", "method": "textDocument/didChange", "params": {
+                "textDocument": { "uri": "file:///a.asm" },
+                "contentChanges": [{ "text": ".code\nLOAD $0 @end\nend: HLT\n" }],
+            } }),
+            json!({ "jsonrpc": "2.0", "id": 2, "method": "textDocument/definition", "params": {
+                "textDocument": { "uri": "file:///a.asm" },
+                "position": { "line": 1, "character": 10 },
+            } }),
+            json!({ "jsonrpc": "2.0", "id": 3, "method": "workspace/symbol", "params": {} }),
+            json!({ "jsonrpc": "2.0", "method": "exit" }),
+            json!({ "jsonrpc": "2.0", "id": 4, "method": "shutdown" }),
+        ];
+        let mut input = vec![];
+        // A malformed message is answered, and the server keeps going
+        write!(input, "Content-Length: 9\r\n\r\n{{not json").unwrap();
+        for message in &messages {
+            write_message(&mut input, message).unwrap();
+        }
+        let mut server = LanguageServer::new(vec![]);
+        server.run(&mut Cursor::new(input)).unwrap();
+
+        let mut output = Cursor::new(server.out);
+        let replies: Vec<Json> =
+            std::iter::from_fn(|| read_message(&mut output).unwrap()).collect();
+        assert_eq!(replies.len(), 6);
+        assert_eq!(replies[0]["error"]["code"], PARSE_ERROR);
+        assert_eq!(replies[0]["id"], Json::Null);
+        let replies = &replies[1..];
+        assert_eq!(replies[0]["result"]["capabilities"]["hoverProvider"], true);
+        let diagnostics = &replies[1]["params"]["diagnostics"];
+        assert_eq!(diagnostics[0]["message"], "Unknown label @end");
+        assert_eq!(replies[2]["params"]["diagnostics"], json!([]));
+        assert_eq!(replies[3]["result"]["range"], span(2, 0, 3).range());
+        assert_eq!(replies[4]["error"]["code"], METHOD_NOT_FOUND);
+    }
+}
@@ -1,56 +1,482 @@
 pub mod assembler;
+pub mod cost;
+pub mod dap;
+pub mod flags;
 pub mod instruction;
+pub mod lsp;
+pub mod pool;
+pub mod profiler;
+pub mod protocol;
+pub mod register;
 pub mod repl;
+pub mod scheduler;
+pub mod trace;
 pub mod vm;
-use std::{fs::File, io::Read, path::Path};
+use std::path::Path;
 
-use clap::{Arg, Parser, Subcommand};
+use clap::{Parser, Subcommand};
+use repl::server::{ListenAddress, ReplServer};
+use trace::{TraceFilter, TraceFormat, Tracer};
+
+/// Environment variable holding the secret of the remote REPL
+const REPL_SECRET_VARIABLE: &str = "VM_REPL_SECRET";
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
     /// Path to the file to run
     #[arg(short, long)]
     file: Option<String>,
+    /// Fault on signed arithmetic overflow instead of wrapping around
+    #[arg(long, global = true)]
+    trap_overflow: bool,
+    /// Print every executed instruction to the standard error
+    #[arg(long)]
+    trace: bool,
+    /// Format of the trace
+    #[arg(long, value_enum, default_value_t)]
+    trace_format: TraceFormat,
+    /// Only trace instructions in this address range, e.g. 64..128 or 0x40..0x80
+    #[arg(long, value_parser = trace::parse_pc_range)]
+    trace_pc: Option<std::ops::Range<usize>>,
+    /// Only trace these opcodes, e.g. ADD,JMP
+    #[arg(long, value_delimiter = ',', value_parser = trace::parse_opcode)]
+    trace_opcode: Vec<instruction::Opcode>,
+    /// Count executed instructions and print a profile to the standard error
+    /// once the program ends
+    #[arg(long)]
+    profile: bool,
+    /// Write the profile in the folded stacks format used by flamegraph tools
+    #[arg(long)]
+    profile_folded: Option<String>,
+    /// Read per-opcode cycle costs from a file of `MNEMONIC COST` lines
+    #[arg(long, global = true)]
+    costs: Option<String>,
+    /// Run the program with a restrictive policy: no syscalls and limited
+    /// heap, stack and output. The other policy options loosen or tighten it.
+    #[arg(long, global = true)]
+    sandbox: bool,
+    /// Only allow these syscalls, e.g. print-int,clock
+    #[arg(long, global = true, value_enum, value_delimiter = ',')]
+    allow_syscalls: Option<Vec<vm::syscall::Syscall>>,
+    /// Maximum size of the heap and the objects in bytes, past which
+    /// allocations fault
+    #[arg(long, global = true)]
+    max_heap: Option<usize>,
+    /// Maximum number of values on the stack, past which PUSH faults
+    #[arg(long, global = true)]
+    max_stack_depth: Option<usize>,
+    /// Maximum number of bytes the program may write to its output
+    #[arg(long, global = true)]
+    output_quota: Option<usize>,
+    /// Print the cycles spent by the program to the standard error once it ends
+    #[arg(long)]
+    cycles: bool,
+    /// File the REPL keeps its history in, ~/.vm_history by default
+    #[arg(long, global = true)]
+    history: Option<String>,
+    /// Instructions a process runs before the scheduler switches to the next one
+    #[arg(long, default_value_t = scheduler::DEFAULT_TIME_SLICE)]
+    time_slice: usize,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Assemble a program into a PIE file
+    Assemble(AssembleArgs),
+    /// Format assembly files in place
+    Fmt(FmtArgs),
+    /// Run many programs in parallel and write a JSON report of their results
+    Batch(BatchArgs),
+    /// Start the REPL, or run a script of REPL commands and code
+    Repl(ReplArgs),
+    /// Serve the Debug Adapter Protocol on the standard input and output, for
+    /// debugging programs from an editor
+    Dap,
+    /// Serve the Language Server Protocol on the standard input and output,
+    /// for editing assembly in an editor
+    Lsp,
+}
+
+#[derive(clap::Args, Debug)]
+struct ReplArgs {
+    /// Run this file of REPL commands and code instead of reading from the
+    /// terminal, exiting with a failure if an assertion fails
+    #[arg(long, conflicts_with = "listen")]
+    script: Option<String>,
+    /// Serve the REPL to clients on this local address instead of the
+    /// terminal, `HOST:PORT` on the loopback interface or `unix:PATH`
+    #[arg(long)]
+    listen: Option<ListenAddress>,
+    /// File holding the secret the clients of `--listen` must send as
+    /// `AUTH <secret>` first, required to listen on a TCP address. The secret
+    /// can also be set in the VM_REPL_SECRET environment variable.
+    #[arg(long, requires = "listen")]
+    secret_file: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct AssembleArgs {
+    /// Path to the assembly source
+    file: String,
+    /// Path of the PIE file to write, the source with a `.pie` extension by
+    /// default
+    #[arg(short, long)]
+    output: Option<String>,
+    /// Add a debug section mapping the instructions back to the source, for
+    /// error messages, traces and profiles to show source lines
+    #[arg(long)]
+    debug_info: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct FmtArgs {
+    /// Paths to the assembly files
+    #[arg(required = true)]
+    files: Vec<String>,
+    /// Only check that the files are formatted, exiting with a failure if
+    /// one is not
+    #[arg(long)]
+    check: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct BatchArgs {
+    /// Paths to the programs to run
+    #[arg(required = true)]
+    files: Vec<String>,
+    /// Number of threads running programs, the number of CPUs by default
+    #[arg(long)]
+    threads: Option<usize>,
+    /// Maximum number of cycles each program may spend
+    #[arg(long)]
+    fuel: Option<u64>,
+    /// Write the report to this file instead of the standard output
+    #[arg(long)]
+    report: Option<String>,
 }
 
 fn main() {
+    env_logger::init();
     let args = Args::parse();
 
+    match &args.command {
+        Some(Command::Assemble(assemble_args)) => {
+            assemble(assemble_args);
+            return;
+        }
+        Some(Command::Fmt(fmt_args)) => {
+            fmt(fmt_args);
+            return;
+        }
+        Some(Command::Batch(batch_args)) => {
+            batch(&args, batch_args);
+            return;
+        }
+        Some(Command::Dap) => {
+            debug_adapter(&args);
+            return;
+        }
+        Some(Command::Lsp) => {
+            let mut server = lsp::LanguageServer::new(std::io::stdout());
+            if let Err(e) = server.run(&mut std::io::stdin().lock()) {
+                eprintln!("The language server stopped: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Repl(repl_args)) => {
+            repl::install_quiet_panic_hook();
+            match (&repl_args.script, &repl_args.listen) {
+                (Some(path), _) => run_script(path, policy(&args)),
+                (None, Some(address)) => {
+                    serve_repl(address, repl_secret(repl_args), remote_policy(&args))
+                }
+                (None, None) => start_repl(args.history.clone(), policy(&args)),
+            }
+            return;
+        }
+        None => {}
+    }
+    let policy = policy(&args);
     match args.file {
         Some(file) => {
-            let program = read_file(&file);
             let mut asm = assembler::Assembler::new();
             let mut vm = vm::VM::new();
-            let program = asm.assemble(&program);
-            match program {
-                Ok(p) => {
-                    vm.add_bytes(p);
-                    vm.run();
-                    std::process::exit(0);
+            if args.trap_overflow {
+                vm.arithmetic_mode = vm::ArithmeticMode::Trap;
+            }
+            if args.trace {
+                let filter = TraceFilter {
+                    pc_range: args.trace_pc,
+                    opcodes: args.trace_opcode,
+                };
+                vm.set_tracer(Tracer::stderr(args.trace_format, filter));
+            }
+            if args.profile || args.profile_folded.is_some() {
+                vm.enable_profiling();
+            }
+            if let Some(path) = &args.costs {
+                vm.cost_table = read_cost_table(path);
+            }
+            vm.set_policy(policy);
+            vm.add_bytes(read_program(&file, &mut asm, true));
+            vm.verify_header();
+            let mut scheduler = scheduler::Scheduler::new(args.time_slice);
+            let pid = scheduler.spawn(vm);
+            let result = scheduler.run();
+            let process = scheduler
+                .process(pid)
+                .expect("The main process is never removed");
+            let vm = &process.vm;
+            if args.cycles {
+                eprint!("{}", vm.cycles().report());
+            }
+            if let Some(profiler) = vm.profiler() {
+                let symbols = vm.debug_info().map_or(&asm.symbols, |info| &info.symbols);
+                if args.profile {
+                    eprint!("{}", profiler.report(&vm.program, symbols, vm.debug_info()));
+                }
+                if let Some(path) = &args.profile_folded {
+                    if let Err(e) = std::fs::write(path, profiler.folded(symbols)) {
+                        println!("Unable to write the folded profile: {}", e);
+                    }
                 }
-                Err(e) => {
-                    println!("An error occured while assembling the code: {:?}", e);
+            }
+            if let Err(e) = result {
+                println!("The scheduler stopped: {:?}", e);
+                std::process::exit(1);
+            }
+            match &process.state {
+                scheduler::ProcessState::Faulted(e) => {
+                    println!("The VM faulted at {}: {:?}", vm.describe_pc(e.pc()), e);
                     std::process::exit(1);
                 }
+                _ => std::process::exit(0),
             }
         }
         None => {
-            start_repl();
+            repl::install_quiet_panic_hook();
+            start_repl(args.history, policy);
+        }
+    }
+}
+
+fn policy(args: &Args) -> vm::policy::VmPolicy {
+    let mut policy = if args.sandbox {
+        vm::policy::VmPolicy::sandbox()
+    } else {
+        vm::policy::VmPolicy::new()
+    };
+    if let Some(syscalls) = &args.allow_syscalls {
+        policy.allowed_syscalls = syscalls.iter().copied().collect();
+    }
+    policy.max_heap = args.max_heap.or(policy.max_heap);
+    policy.max_stack_depth = args.max_stack_depth.or(policy.max_stack_depth);
+    policy.output_quota = args.output_quota.or(policy.output_quota);
+    policy
+}
+
+/// Policy of the remote REPL, whose clients share the memory of the server.
+/// The heap and the stack are bounded as in the sandbox unless the command
+/// line sets their limits.
+fn remote_policy(args: &Args) -> vm::policy::VmPolicy {
+    let sandbox = vm::policy::VmPolicy::sandbox();
+    let mut policy = policy(args);
+    policy.max_heap = policy.max_heap.or(sandbox.max_heap);
+    policy.max_stack_depth = policy.max_stack_depth.or(sandbox.max_stack_depth);
+    policy
+}
+
+fn debug_adapter(args: &Args) {
+    let mut adapter = dap::DebugAdapter::new(std::io::stdout());
+    if args.trap_overflow {
+        adapter.arithmetic_mode = vm::ArithmeticMode::Trap;
+    }
+    adapter.policy = policy(args);
+    if let Err(e) = adapter.run(&mut std::io::stdin().lock()) {
+        eprintln!("The debug adapter stopped: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn assemble(args: &AssembleArgs) {
+    let mut asm = assembler::Assembler::new();
+    let program = read_program(&args.file, &mut asm, args.debug_info);
+    let output = match &args.output {
+        Some(output) => output.clone(),
+        None => Path::new(&args.file)
+            .with_extension("pie")
+            .to_string_lossy()
+            .into_owned(),
+    };
+    if let Err(e) = std::fs::write(&output, program) {
+        println!("Unable to write {}: {}", output, e);
+        std::process::exit(1);
+    }
+}
+
+fn fmt(args: &FmtArgs) {
+    let mut success = true;
+    for file in &args.files {
+        let source = match std::fs::read_to_string(file) {
+            Ok(source) => source,
+            Err(e) => {
+                println!("Unable to read {}: {}", file, e);
+                std::process::exit(1);
+            }
+        };
+        let formatted = match assembler::formatter::format(&source) {
+            Ok(formatted) => formatted,
+            Err(e) => {
+                println!("Unable to format {}: {:?}", file, e);
+                success = false;
+                continue;
+            }
+        };
+        if formatted == source {
+            continue;
+        }
+        if args.check {
+            println!("{} is not formatted", file);
+            success = false;
+        } else if let Err(e) = std::fs::write(file, formatted) {
+            println!("Unable to write {}: {}", file, e);
+            success = false;
         }
     }
+    if !success {
+        std::process::exit(1);
+    }
 }
 
-fn start_repl() {
-    let mut repl = repl::REPL::new();
+/// Reads the PIE program at `path`, assembling it first if it is assembly
+/// source, with a debug section when `debug_info` is set
+fn read_program(path: &str, asm: &mut assembler::Assembler, debug_info: bool) -> Vec<u8> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            println!("Unable to read {}: {}", path, e);
+            std::process::exit(1);
+        }
+    };
+    if bytes.starts_with(&assembler::PIE_HEADER_PREFIX) {
+        return bytes;
+    }
+    if debug_info {
+        asm.emit_debug_info(path);
+    }
+    match asm.assemble(&String::from_utf8_lossy(&bytes)) {
+        Ok(program) => program,
+        Err(e) => {
+            println!("An error occured while assembling the code: {:?}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn batch(args: &Args, batch_args: &BatchArgs) {
+    let mut pool = match batch_args.threads {
+        Some(threads) => pool::VmPool::new(threads),
+        None => pool::VmPool::default(),
+    };
+    if args.trap_overflow {
+        pool.arithmetic_mode = vm::ArithmeticMode::Trap;
+    }
+    if let Some(path) = &args.costs {
+        pool.cost_table = read_cost_table(path);
+    }
+    pool.policy = policy(args);
+    let mut jobs = vec![];
+    for file in &batch_args.files {
+        match std::fs::read_to_string(file) {
+            Ok(source) => jobs.push(pool::Job {
+                name: file.clone(),
+                source,
+                fuel: batch_args.fuel,
+            }),
+            Err(e) => {
+                println!("Unable to read {}: {}", file, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let report = pool::BatchReport::new(pool.run(&jobs));
+    let json = serde_json::to_string_pretty(&report).expect("A report is always valid JSON");
+    match &batch_args.report {
+        Some(path) => {
+            if let Err(e) = std::fs::write(path, json + "\n") {
+                println!("Unable to write the report: {}", e);
+                std::process::exit(1);
+            }
+        }
+        None => println!("{}", json),
+    }
+}
+
+fn read_cost_table(path: &str) -> cost::CostTable {
+    match cost::CostTable::parse(&read_file(path)) {
+        Ok(table) => table,
+        Err(e) => {
+            println!("Invalid cost table: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn start_repl(history: Option<String>, policy: vm::policy::VmPolicy) {
+    let mut repl = match history {
+        Some(path) => repl::REPL::with_history(Some(path.into())),
+        None => repl::REPL::new(),
+    };
+    repl.set_policy(policy);
     repl.run();
 }
 
-fn read_file(tmp: &str) -> String {
-    let mut contents = String::new();
-    File::open(Path::new(&tmp))
-        .and_then(|mut fh| fh.read_to_string(&mut contents))
-        .unwrap();
+fn serve_repl(address: &ListenAddress, secret: Option<String>, policy: vm::policy::VmPolicy) {
+    let mut repl = repl::REPL::with_history(None);
+    repl.set_policy(policy);
+    let server = ReplServer::new(repl, secret);
+    println!("Serving the REPL on {}", address);
+    if let Err(e) = server.serve(address) {
+        println!("Unable to serve the REPL: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Secret of the remote REPL, kept off the command line where other users
+/// could read it
+fn repl_secret(args: &ReplArgs) -> Option<String> {
+    let secret = match &args.secret_file {
+        Some(path) => read_file(path).trim_end().to_string(),
+        None => std::env::var(REPL_SECRET_VARIABLE).unwrap_or_default(),
+    };
+    if secret.is_empty() && args.secret_file.is_some() {
+        println!("The secret file is empty");
+        std::process::exit(1);
+    }
+    Some(secret).filter(|secret| !secret.is_empty())
+}
 
-    contents
+fn run_script(path: &str, policy: vm::policy::VmPolicy) {
+    let script = read_file(path);
+    let mut repl = repl::REPL::with_history(None);
+    repl.set_policy(policy);
+    if !repl.run_script(&script) {
+        std::process::exit(1);
+    }
+}
+
+/// Reads a text file, exiting with a failure if it cannot be read
+fn read_file(path: &str) -> String {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            println!("Unable to read {}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
 }
@@ -0,0 +1,255 @@
+//! Running many independent programs in parallel, for batch jobs such as
+//! grading.
+//!
+//! Every job gets its own `VM` with its own policy and captured output.
+//! Jobs are shared among a fixed number of OS threads, and their results come
+//! back in the order the jobs were given.
+
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+};
+
+use serde::Serialize;
+
+use crate::{
+    assembler::Assembler,
+    cost::CostTable,
+    vm::{
+        policy::{Violation, VmPolicy},
+        ArithmeticMode, VMError, VM,
+    },
+};
+
+/// A program to run
+#[derive(Debug, Clone)]
+pub struct Job {
+    /// Name of the job in the report, such as the path of the program
+    pub name: String,
+    /// Assembly source of the program
+    pub source: String,
+    /// Maximum number of cycles the program may spend, instead of the fuel of
+    /// the policy of the pool
+    pub fuel: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum JobStatus {
+    Halted,
+    Faulted {
+        error: String,
+    },
+    /// The program spent all of its fuel
+    OutOfFuel,
+    /// The program could not be assembled
+    InvalidProgram {
+        error: String,
+    },
+}
+
+#[derive(Debug, Serialize)]
+pub struct JobResult {
+    pub name: String,
+    #[serde(flatten)]
+    pub status: JobStatus,
+    pub cycles: u64,
+    /// Value of `$0` when the program stopped
+    pub exit_value: i32,
+    pub output: String,
+}
+
+/// Results of a batch, with the number of jobs that ended in each way
+#[derive(Debug, Serialize)]
+pub struct BatchReport {
+    pub total: usize,
+    pub halted: usize,
+    pub faulted: usize,
+    pub out_of_fuel: usize,
+    pub invalid: usize,
+    pub results: Vec<JobResult>,
+}
+
+impl BatchReport {
+    pub fn new(results: Vec<JobResult>) -> BatchReport {
+        let count = |f: fn(&JobStatus) -> bool| results.iter().filter(|r| f(&r.status)).count();
+        BatchReport {
+            total: results.len(),
+            halted: count(|s| matches!(s, JobStatus::Halted)),
+            faulted: count(|s| matches!(s, JobStatus::Faulted { .. })),
+            out_of_fuel: count(|s| matches!(s, JobStatus::OutOfFuel)),
+            invalid: count(|s| matches!(s, JobStatus::InvalidProgram { .. })),
+            results,
+        }
+    }
+
+    /// Whether every job halted normally
+    pub fn all_halted(&self) -> bool {
+        self.halted == self.total
+    }
+}
+
+/// Runs jobs on a fixed number of threads
+pub struct VmPool {
+    threads: usize,
+    /// Configuration given to the VM of every job
+    pub arithmetic_mode: ArithmeticMode,
+    pub cost_table: CostTable,
+    pub policy: VmPolicy,
+}
+
+impl VmPool {
+    pub fn new(threads: usize) -> VmPool {
+        VmPool {
+            threads: threads.max(1),
+            arithmetic_mode: ArithmeticMode::default(),
+            cost_table: CostTable::default(),
+            policy: VmPolicy::new(),
+        }
+    }
+
+    /// Runs every job and returns their results, in the order of `jobs`
+    pub fn run(&self, jobs: &[Job]) -> Vec<JobResult> {
+        let next_job = AtomicUsize::new(0);
+        let results: Mutex<Vec<Option<JobResult>>> =
+            Mutex::new(jobs.iter().map(|_| None).collect());
+        thread::scope(|scope| {
+            for _ in 0..self.threads.min(jobs.len()) {
+                scope.spawn(|| loop {
+                    let index = next_job.fetch_add(1, Ordering::Relaxed);
+                    let Some(job) = jobs.get(index) else {
+                        break;
+                    };
+                    let result = self.run_guarded(job);
+                    results.lock().expect("A worker panicked")[index] = Some(result);
+                });
+            }
+        });
+        results
+            .into_inner()
+            .expect("A worker panicked")
+            .into_iter()
+            .map(|result| result.expect("Every job has run"))
+            .collect()
+    }
+
+    /// Runs a job, a bug in the VM failing that job rather than the whole batch
+    fn run_guarded(&self, job: &Job) -> JobResult {
+        panic::catch_unwind(AssertUnwindSafe(|| self.run_job(job))).unwrap_or_else(|payload| {
+            let reason = (payload.downcast_ref::<&str>().copied())
+                .or(payload.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown error");
+            JobResult {
+                name: job.name.clone(),
+                status: JobStatus::Faulted {
+                    error: format!("The VM panicked: {}", reason),
+                },
+                cycles: 0,
+                exit_value: 0,
+                output: String::new(),
+            }
+        })
+    }
+
+    fn run_job(&self, job: &Job) -> JobResult {
+        let mut vm = VM::new();
+        vm.arithmetic_mode = self.arithmetic_mode;
+        vm.cost_table = self.cost_table.clone();
+        vm.set_policy(VmPolicy {
+            fuel: job.fuel.or(self.policy.fuel),
+            ..self.policy.clone()
+        });
+        vm.capture_output();
+
+        let mut asm = Assembler::new();
+        asm.emit_debug_info(&job.name);
+        let status = match asm.assemble(&job.source) {
+            Ok(program) => {
+                vm.add_bytes(program);
+                match vm.run() {
+                    Ok(()) => JobStatus::Halted,
+                    Err(VMError::PolicyViolation {
+                        violation: Violation::Fuel,
+                        ..
+                    }) => JobStatus::OutOfFuel,
+                    Err(e) => JobStatus::Faulted {
+                        error: format!("{:?} at {}", e, vm.describe_pc(e.pc())),
+                    },
+                }
+            }
+            Err(e) => JobStatus::InvalidProgram {
+                error: format!("{:?}", e),
+            },
+        };
+        JobResult {
+            name: job.name.clone(),
+            status,
+            cycles: vm.cycles().total(),
+            exit_value: vm.registers[0],
+            output: String::from_utf8_lossy(&vm.take_output()).into_owned(),
+        }
+    }
+}
+
+impl Default for VmPool {
+    fn default() -> Self {
+        Self::new(thread::available_parallelism().map_or(1, |n| n.get()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(name: &str, source: &str, fuel: Option<u64>) -> Job {
+        Job {
+            name: name.to_string(),
+            source: source.to_string(),
+            fuel,
+        }
+    }
+
+    #[test]
+    fn test_run_batch() {
+        let jobs: Vec<Job> = (0..20)
+            .map(|i| {
+                job(
+                    &format!("job{}", i),
+                    &format!(".code\nLOAD $0 #{}\nHLT\n", i),
+                    None,
+                )
+            })
+            .chain([
+                job("loop", ".code\nLOAD $2 #64\nINC $1\nJMP $2\n", Some(100)),
+                job("div", ".code\nDIV $1 $2 $3\nHLT\n", None),
+                job("invalid", ".code\nLOAD $99 #1\n", None),
+            ])
+            .collect();
+        let report = BatchReport::new(VmPool::new(4).run(&jobs));
+
+        assert_eq!(report.total, 23);
+        assert_eq!(report.halted, 20);
+        assert_eq!(report.out_of_fuel, 1);
+        assert_eq!(report.faulted, 1);
+        assert_eq!(report.invalid, 1);
+        assert!(!report.all_halted());
+        for (i, result) in report.results.iter().take(20).enumerate() {
+            assert_eq!(result.name, format!("job{}", i));
+            assert_eq!(result.exit_value, i as i32);
+            assert_eq!(result.output, "");
+        }
+        assert_eq!(report.results[20].cycles, 100);
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["results"][0]["status"], "halted");
+        assert_eq!(json["results"][21]["status"], "faulted");
+        assert_eq!(
+            json["results"][21]["error"],
+            "DivideByZero { pc: 64 } at div:2 DIV $1 $2 $3"
+        );
+    }
+}
@@ -0,0 +1,244 @@
+use std::{cmp::Reverse, collections::HashMap, fmt::Write};
+
+use crate::{
+    assembler::{debug_info::DebugInfo, symbol::SymbolTable},
+    instruction::{Instruction, Opcode},
+};
+
+/// Number of hottest instructions listed in a report
+const HOT_PC_COUNT: usize = 10;
+
+/// Name given to instructions that come before any label
+const UNLABELLED: &str = "<unlabelled>";
+
+/// Counts how many times each opcode and each instruction was executed, and
+/// the cycles each instruction took
+#[derive(Debug, Clone)]
+pub struct Profiler {
+    opcode_counts: [u64; 256],
+    pc_counts: HashMap<usize, u64>,
+    pc_cycles: HashMap<usize, u64>,
+    total: u64,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            opcode_counts: [0; 256],
+            pc_counts: HashMap::new(),
+            pc_cycles: HashMap::new(),
+            total: 0,
+        }
+    }
+
+    /// Records the execution of the instruction at `pc`, which took `cycles`
+    pub fn record(&mut self, pc: usize, opcode: u8, cycles: u64) {
+        self.opcode_counts[opcode as usize] += 1;
+        *self.pc_counts.entry(pc).or_insert(0) += 1;
+        *self.pc_cycles.entry(pc).or_insert(0) += cycles;
+        self.total += 1;
+    }
+
+    /// Number of instructions executed since profiling started
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Executions per opcode, most executed first
+    pub fn opcode_counts(&self) -> Vec<(Opcode, u64)> {
+        let mut counts: Vec<(Opcode, u64)> = self
+            .opcode_counts
+            .iter()
+            .enumerate()
+            .filter(|(_, &count)| count > 0)
+            .map(|(byte, &count)| (Opcode::from(byte as u8), count))
+            .collect();
+        counts.sort_by_key(|&(_, count)| Reverse(count));
+        counts
+    }
+
+    /// Executions per instruction address, most executed first
+    pub fn pc_counts(&self) -> Vec<(usize, u64)> {
+        let mut counts: Vec<(usize, u64)> =
+            self.pc_counts.iter().map(|(&pc, &c)| (pc, c)).collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        counts
+    }
+
+    /// Executions attributed to the closest label preceding each instruction,
+    /// most executed first
+    pub fn label_counts(&self, symbols: &SymbolTable) -> Vec<(String, u64)> {
+        by_label(&self.pc_counts, symbols)
+    }
+
+    /// Cycles attributed to the closest label preceding each instruction,
+    /// most expensive first
+    pub fn label_cycles(&self, symbols: &SymbolTable) -> Vec<(String, u64)> {
+        by_label(&self.pc_cycles, symbols)
+    }
+
+    /// Human readable report of where the program spent its time, giving the
+    /// source line of the hottest instructions when there is `debug_info`
+    pub fn report(
+        &self,
+        program: &[u8],
+        symbols: &SymbolTable,
+        debug_info: Option<&DebugInfo>,
+    ) -> String {
+        let mut report = String::new();
+        let _ = writeln!(report, "Instructions executed: {}", self.total);
+
+        let _ = writeln!(report, "\nBy opcode:");
+        for (opcode, count) in self.opcode_counts() {
+            let _ = writeln!(
+                report,
+                "  {:<8} {:>12} {:>6.1}%",
+                opcode.to_string(),
+                count,
+                self.percentage(count)
+            );
+        }
+
+        let _ = writeln!(report, "\nHottest instructions:");
+        for (pc, count) in self.pc_counts().into_iter().take(HOT_PC_COUNT) {
+            let location = match symbols.nearest_label(pc as u32) {
+                Some((name, offset)) => format!("{}+{}", name, pc as u32 - offset),
+                None => UNLABELLED.to_string(),
+            };
+            let instruction = program
+                .get(pc..)
+                .and_then(Instruction::decode)
+                .map(|i| i.to_string())
+                .unwrap_or_default();
+            let source = debug_info
+                .and_then(|info| info.source_line(pc))
+                .map(|line| format!("{:<16} ", line))
+                .unwrap_or_default();
+            let _ = writeln!(
+                report,
+                "  {:06x} {}{:<16} {:<20} {:>12} {:>6.1}%",
+                pc,
+                source,
+                location,
+                instruction,
+                count,
+                self.percentage(count)
+            );
+        }
+
+        let _ = writeln!(report, "\nCycles by label:");
+        let cycles = self.label_cycles(symbols);
+        let total: u64 = cycles.iter().map(|(_, c)| c).sum();
+        for (label, count) in cycles {
+            let percentage = if total == 0 {
+                0.0
+            } else {
+                count as f64 * 100.0 / total as f64
+            };
+            let _ = writeln!(report, "  {:<16} {:>12} {:>6.1}%", label, count, percentage);
+        }
+        report
+    }
+
+    /// Profile in the folded stacks format read by flamegraph tools: one line
+    /// per stack, frames separated by `;`, followed by the cycles spent in it.
+    /// There is no call stack yet, so each stack is the single label executing.
+    pub fn folded(&self, symbols: &SymbolTable) -> String {
+        let mut folded = String::new();
+        for (label, count) in self.label_cycles(symbols) {
+            let _ = writeln!(folded, "{} {}", label, count);
+        }
+        folded
+    }
+
+    fn percentage(&self, count: u64) -> f64 {
+        if self.total == 0 {
+            0.0
+        } else {
+            count as f64 * 100.0 / self.total as f64
+        }
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Sums per instruction counts per label, largest first
+fn by_label(pc_counts: &HashMap<usize, u64>, symbols: &SymbolTable) -> Vec<(String, u64)> {
+    let mut by_label: HashMap<String, u64> = HashMap::new();
+    for (&pc, &count) in pc_counts {
+        *by_label.entry(label_of(symbols, pc)).or_insert(0) += count;
+    }
+    let mut counts: Vec<(String, u64)> = by_label.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    counts
+}
+
+fn label_of(symbols: &SymbolTable, pc: usize) -> String {
+    symbols
+        .nearest_label(pc as u32)
+        .map(|(name, _)| name.to_string())
+        .unwrap_or_else(|| UNLABELLED.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::Assembler;
+
+    use super::*;
+
+    fn profile_loop() -> (Profiler, Vec<u8>, SymbolTable) {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble(".code\nLOAD $0 #3\nloop: DEC $0\nHLT\n")
+            .unwrap();
+        let mut profiler = Profiler::new();
+        profiler.record(64, Opcode::LOAD as u8, 1);
+        for _ in 0..3 {
+            profiler.record(68, Opcode::DEC as u8, 1);
+        }
+        profiler.record(72, Opcode::HLT as u8, 5);
+        (profiler, program, asm.symbols)
+    }
+
+    #[test]
+    fn test_counts() {
+        let (profiler, _, symbols) = profile_loop();
+        assert_eq!(profiler.total(), 5);
+        assert_eq!(profiler.opcode_counts()[0], (Opcode::DEC, 3));
+        assert_eq!(profiler.pc_counts()[0], (68, 3));
+        assert_eq!(
+            profiler.label_counts(&symbols),
+            vec![("loop".to_string(), 4), (UNLABELLED.to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn test_report() {
+        let (profiler, program, symbols) = profile_loop();
+        let report = profiler.report(&program, &symbols, None);
+        assert!(report.contains("Instructions executed: 5"));
+        assert!(report.contains("loop+0"));
+        assert!(report.contains("DEC $0"));
+        assert_eq!(profiler.folded(&symbols), "loop 8\n<unlabelled> 1\n");
+    }
+
+    #[test]
+    fn test_report_source_lines() {
+        let (profiler, _, _) = profile_loop();
+        let mut asm = Assembler::new();
+        asm.emit_debug_info("loop.asm");
+        let program = asm
+            .assemble(".code\nLOAD $0 #3\nloop: DEC $0\nHLT\n")
+            .unwrap();
+        let mut vm = crate::vm::VM::new();
+        vm.add_bytes(program);
+        vm.verify_header();
+        let info = vm.debug_info().unwrap();
+        let report = profiler.report(&vm.program, &info.symbols, Some(info));
+        assert!(report.contains(&format!("{:<16} loop+0", "loop.asm:3")));
+    }
+}
@@ -0,0 +1,85 @@
+//! Framing of the JSON messages of the Debug Adapter and Language Server
+//! protocols: each message is preceded by a `Content-Length` header giving
+//! the size of its body in bytes, and a blank line.
+
+use std::io::{self, BufRead, Write};
+
+use serde_json::Value as Json;
+
+/// Largest body a message may have, which keeps a broken or hostile client
+/// from making us allocate whatever it claims to send
+pub const MAX_MESSAGE_LENGTH: usize = 64 * 1024 * 1024;
+
+/// Reads the next message, or `None` at the end of the input
+pub fn read_message(input: &mut impl BufRead) -> io::Result<Option<Json>> {
+    let Some(body) = read_body(input)? else {
+        return Ok(None);
+    };
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Reads the body of the next message without parsing it, or `None` at the
+/// end of the input. Only a broken header or input is an error.
+pub fn read_body(input: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse().ok();
+        }
+    }
+    let length = length.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "Missing Content-Length header")
+    })?;
+    if length > MAX_MESSAGE_LENGTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Content-Length of {} bytes is above the maximum of {}",
+                length, MAX_MESSAGE_LENGTH
+            ),
+        ));
+    }
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    Ok(Some(body))
+}
+
+/// Writes a message with its header
+pub fn write_message(out: &mut impl Write, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_message_round_trip() {
+        let mut framed = vec![];
+        write_message(&mut framed, &json!({ "seq": 1, "type": "request" })).unwrap();
+        write_message(&mut framed, &json!({ "seq": 2 })).unwrap();
+        let mut input = Cursor::new(framed);
+        assert_eq!(read_message(&mut input).unwrap().unwrap()["seq"], 1);
+        assert_eq!(read_message(&mut input).unwrap().unwrap()["seq"], 2);
+        assert!(read_message(&mut input).unwrap().is_none());
+        assert!(read_message(&mut Cursor::new("Content-Type: json\r\n\r\n")).is_err());
+
+        let header = format!("Content-Length: {}\r\n\r\n", MAX_MESSAGE_LENGTH + 1);
+        let error = read_body(&mut Cursor::new(header)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
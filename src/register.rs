@@ -0,0 +1,84 @@
+//! Register file conventions shared by the assembler and the VM.
+//!
+//! The VM has `REGISTER_COUNT` general purpose registers, numbered from `$0`.
+//! The last four registers are reserved by convention and can be referred to
+//! by name in assembly source:
+//!
+//! | Name    | Number | Purpose                                         |
+//! |---------|--------|-------------------------------------------------|
+//! | `$sp`   | 28     | Stack pointer                                   |
+//! | `$fp`   | 29     | Frame pointer                                   |
+//! | `$ra`   | 30     | Return address                                  |
+//! | `$zero` | 31     | Always reads as 0, writes to it are discarded   |
+//!
+//! Registers `$0` to `$27` are free for general use.
+
+/// Number of registers available in the VM
+pub const REGISTER_COUNT: usize = 32;
+
+/// Stack pointer
+pub const SP: u8 = 28;
+/// Frame pointer
+pub const FP: u8 = 29;
+/// Return address
+pub const RA: u8 = 30;
+/// Hardwired zero register
+pub const ZERO: u8 = 31;
+
+const NAMED_REGISTERS: [(&str, u8); 4] = [("sp", SP), ("fp", FP), ("ra", RA), ("zero", ZERO)];
+
+/// Returns whether `register` designates one of the VM's registers
+pub fn is_valid(register: u8) -> bool {
+    (register as usize) < REGISTER_COUNT
+}
+
+/// Resolves a register name as written after the `$` sign, either a number
+/// (`"3"`) or one of the conventional aliases (`"sp"`)
+pub fn register_number(name: &str) -> Option<u8> {
+    if let Some((_, number)) = NAMED_REGISTERS
+        .iter()
+        .find(|(alias, _)| alias.eq_ignore_ascii_case(name))
+    {
+        return Some(*number);
+    }
+    match name.parse::<u8>() {
+        Ok(number) if is_valid(number) => Some(number),
+        _ => None,
+    }
+}
+
+/// Returns the name a register is usually written with in assembly source
+pub fn register_name(register: u8) -> String {
+    match NAMED_REGISTERS
+        .iter()
+        .find(|(_, number)| *number == register)
+    {
+        Some((alias, _)) => format!("${}", alias),
+        None => format!("${}", register),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_number() {
+        assert_eq!(register_number("0"), Some(0));
+        assert_eq!(register_number("27"), Some(27));
+        assert_eq!(register_number("31"), Some(ZERO));
+        assert_eq!(register_number("sp"), Some(SP));
+        assert_eq!(register_number("ZERO"), Some(ZERO));
+        assert_eq!(register_number("32"), None);
+        assert_eq!(register_number("255"), None);
+        assert_eq!(register_number("999"), None);
+        assert_eq!(register_number("foo"), None);
+    }
+
+    #[test]
+    fn test_register_name() {
+        assert_eq!(register_name(3), "$3");
+        assert_eq!(register_name(FP), "$fp");
+        assert_eq!(register_name(ZERO), "$zero");
+    }
+}
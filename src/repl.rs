@@ -1,115 +1,1075 @@
-use crate::assembler::program_parsers::program_parser;
-use crate::vm::VM;
+/// Writes a line of command output to the client of the REPL, ignoring a
+/// client that left
+macro_rules! say {
+    ($repl:expr, $($arg:tt)*) => {{
+        let _ = writeln!($repl.out, $($arg)*);
+    }};
+}
+
+pub mod dump;
+pub mod helper;
+pub mod inspect;
+pub mod server;
+
+use crate::assembler::program_parsers::{program_parser, Program};
+use crate::assembler::symbol::{Symbol, SymbolTable, SymbolType};
+use crate::assembler::Assembler;
+use crate::instruction::{Instruction, INSTRUCTION_LENGTH};
+use crate::register;
+use crate::register::REGISTER_COUNT;
+use crate::scheduler::{Message, Scheduler, EXTERNAL_SENDER, FAILED_EXIT_VALUE};
+use crate::trace::{parse_address, parse_pc_range};
+use crate::vm::objects::Value;
+use crate::vm::policy::VmPolicy;
+use crate::vm::recorder::{Watchpoint, DEFAULT_RECORDING_CAPACITY};
+use crate::vm::{snapshot::SnapshotError, ProcessRequest, VM};
+use helper::ReplHelper;
+use rustyline::config::Configurer;
+use rustyline::error::ReadlineError;
+use rustyline::history::FileHistory;
+use rustyline::{CompletionType, Editor};
 use std;
 use std::fs::File;
-use std::io::{self, Read, Write};
-use std::num::ParseIntError;
-use std::path::Path;
+use std::io::{self, Write};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+/// Number of lines kept in the history
+pub const HISTORY_SIZE: usize = 1000;
+
+/// Greeting shown when the REPL starts
+const WELCOME: &str = "Welcome to the VM! Let's be productive!";
+
+/// What belongs to the client entering commands rather than to the VM, so
+/// that clients of a remote REPL each have their own
+#[derive(Default)]
+pub struct Session {
+    // Lines entered, for `.history`
+    history: Vec<String>,
+    // Whether lines are hex bytes rather than assembly, see `.hex`
+    hex: bool,
+    // Lines of the block being entered, between `.begin` and `.end`
+    block: Option<Vec<String>>,
+    // Instructions a line may run, without limit when None
+    line_budget: Option<u64>,
+    // Whether the client is remote, and so may not read or write files
+    remote: bool,
+}
+
+impl Session {
+    /// Prompt for the next line, showing whether a block or hex bytes are
+    /// being entered
+    pub fn prompt(&self) -> &'static str {
+        match (&self.block, self.hex) {
+            (Some(_), _) => "... ",
+            (None, true) => "hex> ",
+            (None, false) => ">>> ",
+        }
+    }
+}
 
 pub struct REPL {
-    command_buffer: Vec<String>,
+    // Line editor, keeping the history of the entered lines
+    editor: Editor<ReplHelper, FileHistory>,
+    // File the history is kept in between sessions
+    history_path: Option<PathBuf>,
     vm: VM,
+    // Where the output of commands goes, the terminal or a remote client
+    out: Box<dyn Write + Send>,
+    // Output of the program not checked by `.expect_output` yet, only kept
+    // while running a script
+    output: Option<Vec<u8>>,
+    // Number of failed assertions
+    failures: usize,
+    // Labels of the code entered so far, by address in the program
+    symbols: SymbolTable,
+    // Last file loaded, with the address it was loaded at
+    loaded: Option<(PathBuf, usize)>,
+    // Registers before the last command that ran code
+    previous_registers: [i32; REGISTER_COUNT],
+    // State of the client entering commands
+    session: Session,
+    // Locations stopping a reverse continue
+    watchpoints: Vec<Watchpoint>,
+    // Processes spawned by the program of the REPL
+    scheduler: Scheduler,
+    // Instructions the current line may still run, see `Session::line_budget`
+    budget: Option<u64>,
 }
 
 impl REPL {
     pub fn new() -> REPL {
-        REPL {
-            vm: VM::new(),
-            command_buffer: vec![],
+        REPL::with_history(default_history_path())
+    }
+
+    /// Creates a REPL keeping its history in `history_path`, or only for the
+    /// session with `None`
+    pub fn with_history(history_path: Option<PathBuf>) -> REPL {
+        let mut editor = Editor::new().expect("Unable to create the line editor");
+        editor.set_helper(Some(ReplHelper::default()));
+        editor.set_completion_type(CompletionType::List);
+        editor
+            .set_max_history_size(HISTORY_SIZE)
+            .expect("The history size is valid");
+        editor.set_history_ignore_dups(true).expect("Always valid");
+        let load_error = match history_path.as_ref().map(|path| editor.load_history(path)) {
+            Some(Err(ReadlineError::Io(e))) if e.kind() == io::ErrorKind::NotFound => None,
+            Some(Err(e)) => Some(e),
+            _ => None,
+        };
+        let history = editor.history().iter().cloned().collect();
+        // The output of the program is printed by the REPL, see `flush_output`
+        let mut vm = VM::new();
+        vm.capture_output();
+        let mut repl = REPL {
+            editor,
+            history_path,
+            vm,
+            out: Box::new(io::stdout()),
+            output: None,
+            failures: 0,
+            symbols: SymbolTable::new(),
+            loaded: None,
+            previous_registers: [0; REGISTER_COUNT],
+            session: Session {
+                history,
+                ..Session::default()
+            },
+            watchpoints: vec![],
+            scheduler: Scheduler::default(),
+            budget: None,
+        };
+        if let Some(e) = load_error {
+            say!(repl, "Unable to load the history: {}", e);
         }
+        repl
+    }
+
+    /// Restricts what the code entered may do, see `VmPolicy`
+    pub fn set_policy(&mut self, policy: VmPolicy) {
+        self.vm.set_policy(policy);
     }
 
     pub fn run(&mut self) {
-        println!("Welcome to the VM! Let's be productive!");
+        say!(self, "{}", WELCOME);
         loop {
-            let mut buffer = String::new();
+            let buffer = match self.editor.readline(self.session.prompt()) {
+                Ok(buffer) => buffer,
+                // Ctrl-C gives up the line or the block being edited
+                Err(ReadlineError::Interrupted) => {
+                    if self.session.block.take().is_some() {
+                        say!(self, "Block discarded");
+                    }
+                    continue;
+                }
+                Err(ReadlineError::Eof) => self.quit(),
+                Err(e) => {
+                    say!(self, "Unable to read line from user: {}", e);
+                    self.quit()
+                }
+            };
+            let buffer = buffer.trim();
+            if !buffer.is_empty() {
+                let _ = self.editor.add_history_entry(buffer);
+            }
+            self.handle_line(buffer);
+        }
+    }
+
+    /// Runs a script of commands and code, one per line, as if they were
+    /// typed in. Lines starting with `#` are comments. Returns whether every
+    /// assertion held.
+    pub fn run_script(&mut self, script: &str) -> bool {
+        self.output = Some(vec![]);
+        for line in script.lines().map(str::trim) {
+            if line.starts_with('#') {
+                continue;
+            }
+            let prompt = if self.session.block.is_some() {
+                "..."
+            } else {
+                ">>>"
+            };
+            say!(self, "{} {}", prompt, line);
+            self.handle_line(line);
+        }
+        if self.session.block.take().is_some() {
+            say!(self, "The script ends inside a block, missing .end");
+            self.failures += 1;
+        }
+        self.output = None;
+        if self.failures > 0 {
+            say!(self, "{} assertion(s) failed", self.failures);
+        }
+        self.failures == 0
+    }
 
-            let stdin = io::stdin();
-            print!(">>> ");
-            io::stdout().flush().expect("Unable to flush stdout");
+    /// Runs a line, either part of a block or a command. The registers are
+    /// remembered when it runs code, for `.registers` to show what changed.
+    fn handle_line(&mut self, line: &str) {
+        if !line.is_empty() {
+            self.session.history.push(line.to_string());
+        }
+        let registers = self.vm.registers;
+        let cycles = self.vm.cycles().total();
+        self.budget = self.session.line_budget;
+        // Commands report their errors, this only keeps the session alive
+        // if one of them still has a bug
+        let result = panic::catch_unwind(AssertUnwindSafe(|| self.handle_input(line)));
+        if let Err(payload) = result {
+            let reason = (payload.downcast_ref::<&str>().copied())
+                .or(payload.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown error");
+            say!(
+                self,
+                "The command failed unexpectedly ({}), the VM may be left half \
+                 updated. Restart the REPL if it misbehaves.",
+                reason
+            );
+        }
+        if self.vm.cycles().total() != cycles {
+            self.previous_registers = registers;
+        }
+        self.flush_output();
+    }
 
-            stdin
-                .read_line(&mut buffer)
-                .expect("Unable to read line from user");
-            let buffer = buffer.trim();
+    fn handle_input(&mut self, line: &str) {
+        if let Some(block) = &mut self.session.block {
+            if line != ".end" {
+                block.push(line.to_string());
+                return;
+            }
+            let block = self.session.block.take().unwrap_or_default();
+            self.run_block(&block.join("\n"));
+            return;
+        }
+        self.execute(line);
+    }
 
-            self.command_buffer.push(buffer.to_string());
+    /// Prints the output of the program, keeping it for `.expect_output`
+    /// when running a script
+    fn flush_output(&mut self) {
+        let output = self.vm.take_output();
+        if output.is_empty() {
+            return;
+        }
+        let _ = self.out.write_all(&output).and_then(|_| self.out.flush());
+        if let Some(kept) = &mut self.output {
+            kept.extend(output);
+        }
+    }
+
+    /// Runs a line for a client of the remote REPL, with the session of the
+    /// client and its output going to `out`. Returns the updated session.
+    fn handle_remote_line(
+        &mut self,
+        session: Session,
+        out: Box<dyn Write + Send>,
+        line: &str,
+    ) -> Session {
+        let local_session = mem::replace(&mut self.session, session);
+        let local_out = mem::replace(&mut self.out, out);
+        self.handle_line(line);
+        self.out = local_out;
+        mem::replace(&mut self.session, local_session)
+    }
 
-            match buffer {
-                ".program" => {
-                    println!("Listing instructions currentli in VM's program vector:");
-                    for instruction in &self.vm.program {
-                        println!("{}", instruction);
+    /// Runs a command or a line of code
+    fn execute(&mut self, buffer: &str) {
+        let (command, argument) = match buffer.split_once(char::is_whitespace) {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (buffer, ""),
+        };
+        match command {
+            ".load_file" | ".reload" | ".save_state" | ".load_state" if self.session.remote => {
+                say!(
+                    self,
+                    "Files are out of reach of remote clients, {} is not available",
+                    command
+                );
+            }
+            ".program" => {
+                let _ = write!(
+                    self.out,
+                    "{}",
+                    dump::program(&self.vm.program, 0..usize::MAX)
+                );
+            }
+            ".register" | ".registers" => {
+                let values: Vec<Value> = (0..REGISTER_COUNT)
+                    .map(|register| self.vm.register_value(register))
+                    .collect();
+                let _ = write!(
+                    self.out,
+                    "{}",
+                    inspect::registers(&values, &self.previous_registers)
+                );
+            }
+            ".flags" => say!(self, "{}", inspect::flags(self.vm.flags())),
+            ".pc" => self.print_position(),
+            ".symbols" => {
+                let _ = write!(self.out, "{}", inspect::symbols(&self.symbols));
+            }
+            ".set" => self.set(argument),
+            ".assert" => self.assert(argument),
+            ".expect_output" => self.expect_output(argument),
+            ".load_file" => self.load_file(argument),
+            ".reload" => self.reload(),
+            ".hex" if argument.is_empty() => {
+                self.session.hex = !self.session.hex;
+                match self.session.hex {
+                    true => say!(self, "Entering hex mode, leave it with .hex"),
+                    false => say!(self, "Leaving hex mode"),
+                }
+            }
+            ".hex" => self.run_hex(argument),
+            ".dump" => self.dump(argument),
+            ".begin" => {
+                say!(self, "Entering block mode, finish the block with .end");
+                self.session.block = Some(vec![]);
+            }
+            ".save_state" => self.save_state(argument),
+            ".load_state" => self.load_state(argument),
+            ".record" => self.record(argument),
+            ".step" => {
+                self.run_once();
+                self.print_position();
+            }
+            ".reverse-step" => match self.vm.reverse_step() {
+                Some(_) => self.print_position(),
+                None => say!(self, "No recorded history to step back into"),
+            },
+            ".reverse-continue" => {
+                match self.vm.reverse_continue(&self.watchpoints) {
+                    Some(watchpoint) => say!(self, "Watchpoint {:?} hit", watchpoint),
+                    None => say!(self, "Reached the start of the recorded history"),
+                }
+                self.print_position();
+            }
+            ".watch" => self.watch(argument),
+            ".unwatch" => match parse_watchpoint(argument) {
+                Some(watchpoint) => self.watchpoints.retain(|w| *w != watchpoint),
+                None => say!(self, "Usage: .unwatch <$register|heap address>"),
+            },
+            ".heap" if argument.is_empty() => self.print_heap(),
+            ".heap" => self.dump_heap(argument),
+            ".ps" => {
+                say!(self, "REPL VM at {:06x}", self.vm.pc());
+                let _ = write!(self.out, "{}", self.scheduler.table());
+            }
+            ".clear" => {
+                self.vm.program.clear();
+                self.vm.set_pc(0);
+                self.symbols = SymbolTable::new();
+                self.loaded = None;
+            }
+            ".quit" => self.quit(),
+            ".history" => {
+                for command in &self.session.history {
+                    say!(self, "{}", command);
+                }
+            }
+            _ if command.starts_with('.') => {
+                say!(self, "Unknown command {}", command);
+            }
+            _ if self.session.hex => self.run_hex(buffer),
+            _ => {
+                let program = match program_parser(buffer) {
+                    Ok((rest, program)) if rest.trim().is_empty() => program,
+                    Ok((rest, _)) => {
+                        say!(self, "Unable to parse input at '{}'", rest.trim());
+                        return;
+                    }
+                    Err(_) => {
+                        say!(self, "Unable to parse input");
+                        return;
                     }
-                    println!("End of Program Listing");
+                };
+                // The line runs even if the pc was moved elsewhere
+                let start = self.vm.program.len();
+                if self.add_program(&program) {
+                    self.vm.set_pc(start);
+                    self.run_once();
+                }
+            }
+        }
+    }
+
+    /// Assembles a file, appends it to the program and moves execution to its
+    /// start. The file is remembered for `.reload`.
+    fn load_file(&mut self, path: &str) {
+        if path.is_empty() {
+            say!(self, "Usage: .load_file <path>");
+            return;
+        }
+        let path = PathBuf::from(path);
+        let start = self.vm.program.len();
+        if let Some((code, symbols)) = self.assemble_file(&path, start) {
+            self.add_file(&path, code, symbols, start);
+            self.loaded = Some((path, start));
+        }
+    }
+
+    /// Assembles the last loaded file again in place of its previous code.
+    /// Code added after it is dropped, the registers and the heap are kept.
+    fn reload(&mut self) {
+        let Some((path, start)) = self.loaded.clone() else {
+            say!(self, "No file loaded yet, use .load_file <path>");
+            return;
+        };
+        if start > self.vm.program.len() {
+            say!(self, "The program no longer holds {}", path.display());
+            self.loaded = None;
+            return;
+        }
+        // The previous code is kept if the file no longer assembles
+        if let Some((code, symbols)) = self.assemble_file(&path, start) {
+            self.vm.program.truncate(start);
+            self.symbols.remove_labels_from(start as u32);
+            self.add_file(&path, code, symbols, start);
+        }
+    }
+
+    fn add_file(&mut self, path: &Path, mut code: Vec<u8>, symbols: SymbolTable, start: usize) {
+        say!(
+            self,
+            "Loaded {} instructions from {} at {:06x}",
+            code.len() / INSTRUCTION_LENGTH,
+            path.display(),
+            start
+        );
+        self.vm.program.append(&mut code);
+        self.add_symbols(symbols);
+        self.vm.set_pc(start);
+    }
+
+    /// Assembles a file to be loaded at `start`, printing what went wrong if it
+    /// cannot be read or assembled
+    fn assemble_file(&mut self, path: &Path, start: usize) -> Option<(Vec<u8>, SymbolTable)> {
+        let source = match std::fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(e) => {
+                say!(self, "Unable to read {}: {}", path.display(), e);
+                return None;
+            }
+        };
+        let mut asm = Assembler::new();
+        match asm.assemble_at(&source, start) {
+            Ok(code) => Some((code, asm.symbols)),
+            Err(e) => {
+                say!(self, "Unable to assemble {}: {:?}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Saves the history and exits, with a failure if an assertion failed
+    fn quit(&mut self) -> ! {
+        if let Some(path) = &self.history_path {
+            if let Err(e) = self.editor.save_history(path) {
+                say!(self, "Unable to save the history: {}", e);
+            }
+        }
+        say!(self, "Farewell! Have a great day!");
+        // Failed assertions make the session fail, as a script would
+        std::process::exit(if self.failures > 0 { 1 } else { 0 });
+    }
+
+    /// Appends parsed code to the program, returning whether it could be
+    /// encoded. Labels it declares are added to the known ones.
+    fn add_program(&mut self, program: &Program) -> bool {
+        let mut offset = self.vm.program.len();
+        let mut labels = SymbolTable::new();
+        for instruction in program.instructions.iter().filter(|i| i.is_opcode()) {
+            if let Some(name) = instruction.get_label_name() {
+                labels.add_symbol(Symbol::new(name, SymbolType::Label, offset as u32));
+            }
+            offset += INSTRUCTION_LENGTH;
+        }
+        self.add_symbols(labels);
+        match program.to_bytes(&self.symbols) {
+            Ok(mut bytes) => {
+                self.vm.program.append(&mut bytes);
+                true
+            }
+            Err(e) => {
+                say!(self, "Unable to assemble input: {:?}", e);
+                false
+            }
+        }
+    }
+
+    /// Assembles a block entered between `.begin` and `.end` with the full
+    /// assembler, appends it to the program and runs it until it halts. The
+    /// block is in the code section unless it declares its sections.
+    fn run_block(&mut self, block: &str) {
+        let has_sections = block
+            .lines()
+            .any(|line| matches!(line.trim(), ".code" | ".data"));
+        let source = if has_sections {
+            block.to_string()
+        } else {
+            format!(".code\n{}", block)
+        };
+        let start = self.vm.program.len();
+        let mut asm = Assembler::new();
+        match asm.assemble_at(&source, start) {
+            Ok(mut code) => {
+                self.vm.program.append(&mut code);
+                self.add_symbols(asm.symbols);
+                self.vm.set_pc(start);
+                while self.run_once() {}
+            }
+            Err(e) => say!(self, "Unable to assemble the block: {:?}", e),
+        }
+    }
+
+    /// Appends hex encoded instructions to the program and runs them
+    fn run_hex(&mut self, hex: &str) {
+        let bytes = dump::parse_hex(hex).and_then(|bytes| {
+            dump::validate(&bytes)?;
+            Ok(bytes)
+        });
+        match bytes {
+            Ok(mut bytes) => {
+                let start = self.vm.program.len();
+                self.vm.program.append(&mut bytes);
+                self.vm.set_pc(start);
+                while self.vm.pc() < self.vm.program.len() && self.run_once() {}
+            }
+            Err(e) => say!(self, "Invalid bytecode: {}", e),
+        }
+    }
+
+    /// Dumps the program, the heap or both, optionally limited to a range
+    /// of addresses
+    fn dump(&mut self, argument: &str) {
+        let (target, range) = match argument.split_once(char::is_whitespace) {
+            Some((target, range)) => (target, range.trim()),
+            None if matches!(argument, "program" | "heap") => (argument, ""),
+            None => ("", argument),
+        };
+        let range = match range {
+            "" => 0..usize::MAX,
+            range => match parse_pc_range(range) {
+                Ok(range) => range,
+                Err(e) => {
+                    say!(self, "Usage: .dump [program|heap] [start..end]: {}", e);
+                    return;
                 }
-                ".register" => {
-                    println!("Listing registers and all contents:");
-                    println!("{:#?}", self.vm.registers);
-                    println!("End of Register Listing")
+            },
+        };
+        if matches!(target, "" | "program") {
+            say!(self, "Program:");
+            let _ = write!(
+                self.out,
+                "{}",
+                dump::program(&self.vm.program, range.clone())
+            );
+        }
+        if matches!(target, "" | "heap") {
+            say!(self, "Heap:");
+            let _ = write!(self.out, "{}", dump::heap(self.vm.heap(), range));
+        }
+        if !matches!(target, "" | "program" | "heap") {
+            say!(self, "Usage: .dump [program|heap] [start..end]");
+        }
+    }
+
+    /// Remembers labels, replacing those with the same name
+    fn add_symbols(&mut self, symbols: SymbolTable) {
+        self.symbols.merge(symbols);
+        if let Some(helper) = self.editor.helper_mut() {
+            helper.labels = self.symbols.names().map(String::from).collect();
+        }
+    }
+
+    /// Executes the next instruction, handing SPAWN, YIELD and JOIN over to
+    /// the scheduler. The REPL VM is not a process of the scheduler: spawned
+    /// processes only run when it yields or joins, and cannot send it
+    /// messages.
+    /// Returns whether the VM can go on, having neither halted nor faulted.
+    fn run_once(&mut self) -> bool {
+        match &mut self.budget {
+            Some(0) => {
+                say!(
+                    self,
+                    "Stopped at {:06x}, the line ran as many instructions as it may",
+                    self.vm.pc()
+                );
+                return false;
+            }
+            Some(budget) => *budget -= 1,
+            None => {}
+        }
+        match self.vm.run_once() {
+            Ok(halted) => {
+                self.handle_request();
+                !halted
+            }
+            Err(e) => {
+                say!(self, "The VM faulted: {:?}", e);
+                false
+            }
+        }
+    }
+
+    fn handle_request(&mut self) {
+        match self.vm.take_request() {
+            None => {}
+            Some(ProcessRequest::Spawn { pc, register }) => {
+                let pid = self.scheduler.spawn(self.vm.spawn_child(pc));
+                self.vm.set_register(register, pid as i32);
+                say!(self, "Spawned process {}", pid);
+            }
+            Some(ProcessRequest::Yield) => {
+                if let Err(e) = self.scheduler.round() {
+                    say!(self, "The scheduler stopped: {:?}", e);
                 }
-                ".load_file" => {
-                    print!("Please enter the path to the file you wish to load: ");
-                    io::stdout().flush().expect("Unable to flush stdout");
-                    let mut tmp = String::new();
-                    stdin
-                        .read_line(&mut tmp)
-                        .expect("Unable to read line from user");
-                    let tmp = tmp.trim();
-                    // let filename = Path::new(&tmp);
-                    let mut f = File::open(Path::new(&tmp)).expect("File not found");
-                    let mut contents = String::new();
-                    f.read_to_string(&mut contents)
-                        .expect("There was an error reading from the file");
-                    let program = match program_parser(&contents) {
-                        Ok((_, program)) => program,
+            }
+            Some(ProcessRequest::Join { register }) => {
+                let pid = self.vm.registers[register];
+                let value = match usize::try_from(pid) {
+                    Ok(pid) => match self.scheduler.run_until_done(pid) {
+                        Ok(()) => self.scheduler.exit_value(pid),
                         Err(e) => {
-                            println!("Unable to parse input: {:?}", e);
-                            continue;
+                            say!(self, "The scheduler stopped: {:?}", e);
+                            None
                         }
-                    };
-                    self.vm.program.append(&mut program.to_bytes());
-                }
-                ".clear" => {
-                    self.vm.program.clear();
+                    },
+                    Err(_) => None,
+                };
+                self.vm
+                    .set_register(register, value.unwrap_or(FAILED_EXIT_VALUE));
+            }
+            Some(ProcessRequest::Send { pid, payload }) => {
+                let message = Message {
+                    sender: EXTERNAL_SENDER,
+                    payload,
+                };
+                if !self.scheduler.send(pid, message) {
+                    say!(self, "No process {} to send the message to", pid);
                 }
-                ".quit" => {
-                    println!("Farewell! Have a great day!");
-                    std::process::exit(0);
+            }
+            Some(ProcessRequest::Receive { block, .. }) => {
+                // No process knows the REPL VM, so its mailbox stays empty
+                if block {
+                    say!(self, "The REPL VM has no mailbox, RECV would wait forever");
                 }
-                ".history" => {
-                    for command in &self.command_buffer {
-                        println!("{}", command);
-                    }
+                self.vm.mailbox_empty();
+            }
+        }
+    }
+
+    /// Checkpoints the VM to a file, see `VM::save_state`
+    fn save_state(&mut self, path: &str) {
+        if path.is_empty() {
+            say!(self, "Usage: .save_state <path>");
+            return;
+        }
+        let result = File::create(Path::new(path)).and_then(|mut f| self.vm.save_state(&mut f));
+        match result {
+            Ok(()) => say!(self, "VM state saved to {}", path),
+            Err(e) => say!(self, "Unable to save the VM state: {}", e),
+        }
+    }
+
+    /// Replaces the VM with one restored from a file written by `.save_state`
+    fn load_state(&mut self, path: &str) {
+        if path.is_empty() {
+            say!(self, "Usage: .load_state <path>");
+            return;
+        }
+        let result = File::open(Path::new(path))
+            .map_err(SnapshotError::from)
+            .and_then(|mut f| VM::load_state(&mut f));
+        match result {
+            Ok(vm) => {
+                self.vm = vm;
+                self.vm.capture_output();
+                say!(self, "VM state loaded from {}", path);
+            }
+            Err(e) => say!(self, "Unable to load the VM state: {:?}", e),
+        }
+    }
+
+    /// Turns recording on, with an optional capacity, or off
+    fn record(&mut self, argument: &str) {
+        match argument {
+            "off" => {
+                self.vm.disable_recording();
+                say!(self, "Recording stopped");
+            }
+            "" | "on" => {
+                self.vm.enable_recording(DEFAULT_RECORDING_CAPACITY);
+                say!(
+                    self,
+                    "Recording the last {} steps",
+                    DEFAULT_RECORDING_CAPACITY
+                );
+            }
+            capacity => match capacity.parse::<usize>() {
+                Ok(capacity) => {
+                    self.vm.enable_recording(capacity);
+                    say!(self, "Recording the last {} steps", capacity);
                 }
-                _ => {
-                    let program = match program_parser(buffer) {
-                        Ok((_, program)) => program,
-                        Err(_) => {
-                            println!("Unable to parse input");
-                            continue;
-                        }
-                    };
-                    self.vm.program.append(&mut program.to_bytes());
-                    self.vm.run_once();
+                Err(_) => say!(self, "Usage: .record [on|off|<capacity>]"),
+            },
+        }
+    }
+
+    /// Adds a watchpoint, or lists them without an argument
+    fn watch(&mut self, argument: &str) {
+        if argument.is_empty() {
+            for watchpoint in &self.watchpoints {
+                say!(self, "{:?}", watchpoint);
+            }
+            return;
+        }
+        match parse_watchpoint(argument) {
+            Some(watchpoint) => {
+                if !self.watchpoints.contains(&watchpoint) {
+                    self.watchpoints.push(watchpoint);
                 }
             }
+            None => say!(self, "Usage: .watch <$register|heap address>"),
+        }
+    }
+
+    /// Prints heap usage
+    fn print_heap(&mut self) {
+        let stats = self.vm.heap_stats();
+        let max_size = match stats.max_size {
+            Some(max_size) => max_size.to_string(),
+            None => "unlimited".to_string(),
+        };
+        say!(
+            self,
+            "Heap size:   {} bytes (peak {}, max {})",
+            stats.size,
+            stats.peak,
+            max_size
+        );
+        say!(
+            self,
+            "Allocated:   {} bytes in {} blocks",
+            stats.allocated_bytes,
+            stats.allocated_blocks
+        );
+        say!(
+            self,
+            "Free:        {} bytes in {} blocks (largest {})",
+            stats.free_bytes,
+            stats.free_blocks,
+            stats.largest_free_block
+        );
+        let objects = self.vm.objects();
+        say!(
+            self,
+            "Objects:     {} live in {} slots",
+            objects.live(),
+            objects.capacity()
+        );
+    }
+
+    /// Dumps `len` bytes of the heap from `address`, as in `.heap 16 32`
+    fn dump_heap(&mut self, argument: &str) {
+        let range = argument
+            .split_once(char::is_whitespace)
+            .and_then(|(address, len)| Some((parse_address(address).ok()?, len.trim())))
+            .and_then(|(address, len)| {
+                Some(address..address.checked_add(parse_address(len).ok()?)?)
+            });
+        match range {
+            Some(range) => {
+                let _ = write!(self.out, "{}", dump::heap(self.vm.heap(), range));
+            }
+            None => say!(self, "Usage: .heap [address len]"),
+        }
+    }
+
+    /// Sets a register or the pc, as in `.set $3 42` or `.set pc 0x40`
+    fn set(&mut self, argument: &str) {
+        let Some((target, value)) = argument.split_once(char::is_whitespace) else {
+            say!(self, "Usage: .set <$register|pc> <value>");
+            return;
+        };
+        let Some(value) = parse_value(value.trim()) else {
+            say!(self, "Invalid value '{}'", value.trim());
+            return;
+        };
+        if target == "pc" {
+            match usize::try_from(value) {
+                Ok(pc) => self.vm.set_pc(pc),
+                Err(_) => say!(self, "The pc cannot be negative"),
+            }
+            return;
+        }
+        match target.strip_prefix('$').and_then(register::register_number) {
+            Some(register::ZERO) => say!(self, "$zero always reads as 0"),
+            Some(register) => self.vm.set_register(register as usize, value),
+            None => say!(self, "Unknown register {}", target),
+        }
+    }
+
+    /// Checks a comparison of registers, the pc and numbers, as in
+    /// `.assert $2 == 25`
+    fn assert(&mut self, argument: &str) {
+        let operands: Vec<&str> = argument.split_whitespace().collect();
+        let [left, operator, right] = operands[..] else {
+            say!(self, "Usage: .assert <operand> <==|!=|<|<=|>|>=> <operand>");
+            return;
+        };
+        let (Some(l), Some(r)) = (self.operand_value(left), self.operand_value(right)) else {
+            say!(self, "Operands are $registers, pc or numbers");
+            return;
+        };
+        let holds = match operator {
+            "==" => l == r,
+            "!=" => l != r,
+            "<" => l < r,
+            "<=" => l <= r,
+            ">" => l > r,
+            ">=" => l >= r,
+            _ => {
+                say!(self, "Unknown comparison {}", operator);
+                return;
+            }
+        };
+        if !holds {
+            say!(
+                self,
+                "Assertion failed: {} {} {} ({} is {}, {} is {})",
+                left,
+                operator,
+                right,
+                left,
+                l,
+                right,
+                r
+            );
+            self.failures += 1;
         }
     }
 
-    #[allow(dead_code)]
-    fn parse_hex(&mut self, i: &str) -> Result<Vec<u8>, ParseIntError> {
-        let bytes = i.split(' ');
-        let results: Result<Vec<u8>, ParseIntError> = bytes
-            .into_iter()
-            .map(|byte| u8::from_str_radix(byte, 16))
-            .collect();
-        results
+    fn operand_value(&self, operand: &str) -> Option<i32> {
+        if operand == "pc" {
+            return i32::try_from(self.vm.pc()).ok();
+        }
+        match operand.strip_prefix('$') {
+            Some(name) => register::register_number(name)
+                .map(|register| self.vm.register_value(register as usize).bits()),
+            None => parse_value(operand),
+        }
+    }
+
+    /// Checks that the program wrote exactly the quoted string since the
+    /// last check, as in `.expect_output "42\n"`
+    fn expect_output(&mut self, argument: &str) {
+        let Some(expected) = parse_quoted(argument) else {
+            say!(self, "Usage: .expect_output \"<output>\"");
+            return;
+        };
+        let Some(output) = self.output.as_mut().map(std::mem::take) else {
+            say!(self, "The output is only checked when running a script");
+            return;
+        };
+        if output != expected.as_bytes() {
+            say!(
+                self,
+                "Output assertion failed: expected {:?}, got {:?}",
+                expected,
+                String::from_utf8_lossy(&output)
+            );
+            self.failures += 1;
+        }
+    }
+
+    /// Prints the address and disassembly of the next instruction, with the
+    /// label it follows
+    fn print_position(&mut self) {
+        let pc = self.vm.pc();
+        let label = match self.symbols.nearest_label(pc as u32) {
+            Some((name, offset)) => format!("  <{}+{}>", name, pc as u32 - offset),
+            None => String::new(),
+        };
+        match self.vm.program.get(pc..).and_then(Instruction::decode) {
+            Some(instruction) => say!(self, "{:06x}  {}{}", pc, instruction, label),
+            None => say!(self, "{:06x}  <end of program>{}", pc, label),
+        }
     }
 }
 
+/// Parses a value given to `.set`, in decimal or `0x` prefixed hexadecimal
+fn parse_value(s: &str) -> Option<i32> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok().map(|v| v as i32),
+        None => s.parse::<i32>().ok(),
+    }
+}
+
+/// Logs panics instead of printing them on the standard error, where they
+/// would garble the session. `handle_line` tells the user about them.
+pub fn install_quiet_panic_hook() {
+    panic::set_hook(Box::new(|info| log::error!("{}", info)));
+}
+
+/// Parses a double quoted string, with `\n`, `\t`, `\"` and `\\` escapes
+fn parse_quoted(s: &str) -> Option<String> {
+    let inner = s.strip_prefix('"')?.strip_suffix('"')?;
+    let mut parsed = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        parsed.push(match c {
+            '\\' => match chars.next()? {
+                'n' => '\n',
+                't' => '\t',
+                '0' => '\0',
+                escaped @ ('"' | '\\') => escaped,
+                _ => return None,
+            },
+            '"' => return None,
+            c => c,
+        });
+    }
+    Some(parsed)
+}
+
+/// `~/.vm_history`, if the home directory is known
+fn default_history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| Path::new(&home).join(".vm_history"))
+}
+
+/// Parses a watchpoint, either a register (`$3`, `$sp`) or a heap address
+fn parse_watchpoint(s: &str) -> Option<Watchpoint> {
+    if let Some(name) = s.strip_prefix('$') {
+        return register::register_number(name).map(Watchpoint::Register);
+    }
+    let address = match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok()?,
+        None => s.parse::<usize>().ok()?,
+    };
+    Some(Watchpoint::Heap(address))
+}
+
 impl Default for REPL {
     fn default() -> Self {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    /// Output of a REPL, kept to be checked by the test
+    #[derive(Clone, Default)]
+    struct Sink(Arc<Mutex<Vec<u8>>>);
+
+    impl Sink {
+        fn text(&self) -> String {
+            String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+        }
+    }
+
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn repl_with_sink() -> (REPL, Sink) {
+        let sink = Sink::default();
+        let mut repl = REPL::with_history(None);
+        repl.out = Box::new(sink.clone());
+        (repl, sink)
+    }
+
+    #[test]
+    fn test_parse_quoted() {
+        assert_eq!(parse_quoted(r#""a\n\"b\"""#), Some("a\n\"b\"".to_string()));
+        assert_eq!(parse_quoted(r#""""#), Some(String::new()));
+        assert_eq!(parse_quoted("no quotes"), None);
+        assert_eq!(parse_quoted(r#""a"b""#), None);
+        assert_eq!(parse_quoted(r#""\q""#), None);
+    }
+
+    #[test]
+    fn test_script_assertions() {
+        let (mut repl, sink) = repl_with_sink();
+        let script = "# Counts down from 3\n\
+                      .begin\n\
+                      LOAD $0 #3\n\
+                      loop: DEC $0\n\
+                      LOAD $1 @loop\n\
+                      JGT $1\n\
+                      SYSCALL $zero $0 $2\n\
+                      HLT\n\
+                      .end\n\
+                      .assert $0 == 0\n\
+                      .assert pc > $1\n\
+                      .expect_output \"0\\n\"\n";
+        assert!(repl.run_script(script));
+        assert_eq!(
+            sink.text(),
+            ">>> .begin\n\
+             Entering block mode, finish the block with .end\n\
+             ... LOAD $0 #3\n\
+             ... loop: DEC $0\n\
+             ... LOAD $1 @loop\n\
+             ... JGT $1\n\
+             ... SYSCALL $zero $0 $2\n\
+             ... HLT\n\
+             ... .end\n\
+             0\n\
+             >>> .assert $0 == 0\n\
+             >>> .assert pc > $1\n\
+             >>> .expect_output \"0\\n\"\n"
+        );
+
+        assert!(!repl.run_script(".assert $0 != 0\n.expect_output \"more\"\n"));
+        assert_eq!(repl.failures, 2);
+        assert!(sink.text().ends_with(
+            "Output assertion failed: expected \"more\", got \"\"\n2 assertion(s) failed\n"
+        ));
+
+        // Outside of scripts the output is not kept
+        repl.handle_line(".expect_output \"\"");
+        assert!(repl.output.is_none());
+        assert!(sink
+            .text()
+            .ends_with("The output is only checked when running a script\n"));
+    }
+
+    #[test]
+    fn test_dump_heap() {
+        let (mut repl, sink) = repl_with_sink();
+        repl.handle_line(&format!(".heap 1 {}", usize::MAX));
+        assert_eq!(sink.text(), "Usage: .heap [address len]\n");
+    }
+
+    #[test]
+    fn test_parse_value() {
+        assert_eq!(parse_value("42"), Some(42));
+        assert_eq!(parse_value("-7"), Some(-7));
+        assert_eq!(parse_value("0xffffffff"), Some(-1));
+        assert_eq!(parse_value("$3"), None);
+    }
+
+    #[test]
+    fn test_parse_watchpoint() {
+        assert_eq!(parse_watchpoint("$3"), Some(Watchpoint::Register(3)));
+        assert_eq!(parse_watchpoint("$sp"), Some(Watchpoint::Register(28)));
+        assert_eq!(parse_watchpoint("$99"), None);
+        assert_eq!(parse_watchpoint("16"), Some(Watchpoint::Heap(16)));
+        assert_eq!(parse_watchpoint("0x10"), Some(Watchpoint::Heap(16)));
+        assert_eq!(parse_watchpoint("nope"), None);
+    }
+}
@@ -0,0 +1,132 @@
+//! Hexdumps shown by `.dump`.
+//!
+//! The program is dumped one instruction per line, with its disassembly
+//! alongside. The heap is dumped 16 bytes per line, with the printable ASCII
+//! characters alongside.
+
+use std::fmt::Write;
+use std::ops::Range;
+
+use crate::instruction::{Instruction, Opcode, INSTRUCTION_LENGTH};
+
+/// Bytes per line of a heap dump
+const HEAP_LINE_LENGTH: usize = 16;
+
+/// Dumps the instructions of `program` starting in `range`, which is clamped
+/// to the program and aligned on instructions
+pub fn program(program: &[u8], range: Range<usize>) -> String {
+    let start = range.start - range.start % INSTRUCTION_LENGTH;
+    let end = range.end.min(program.len());
+    let mut dump = String::new();
+    for address in (start..end).step_by(INSTRUCTION_LENGTH) {
+        let bytes = &program[address..program.len().min(address + INSTRUCTION_LENGTH)];
+        let disassembly = match Instruction::decode(bytes) {
+            Some(instruction) => instruction.to_string(),
+            None => "<truncated>".to_string(),
+        };
+        let _ = writeln!(dump, "{:06x}  {:<11}  {}", address, hex(bytes), disassembly);
+    }
+    dump
+}
+
+/// Dumps the bytes of `heap` in `range`, which is clamped to the heap and
+/// aligned on lines
+pub fn heap(heap: &[u8], range: Range<usize>) -> String {
+    let start = range.start - range.start % HEAP_LINE_LENGTH;
+    let end = range.end.min(heap.len());
+    let mut dump = String::new();
+    for address in (start..end).step_by(HEAP_LINE_LENGTH) {
+        let bytes = &heap[address..end.min(address + HEAP_LINE_LENGTH)];
+        let ascii: String = bytes
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        let _ = writeln!(dump, "{:06x}  {:<47}  |{}|", address, hex(bytes), ascii);
+    }
+    dump
+}
+
+/// Parses space separated hex bytes, such as `0c 01 05 00`
+pub fn parse_hex(s: &str) -> Result<Vec<u8>, String> {
+    s.split_whitespace()
+        .map(|byte| {
+            u8::from_str_radix(byte.trim_start_matches("0x"), 16)
+                .map_err(|_| format!("'{}' is not a hex byte", byte))
+        })
+        .collect()
+}
+
+/// Checks that bytes are whole instructions with known opcodes
+pub fn validate(bytes: &[u8]) -> Result<(), String> {
+    if !bytes.len().is_multiple_of(INSTRUCTION_LENGTH) {
+        return Err(format!(
+            "{} bytes are not whole instructions of {} bytes",
+            bytes.len(),
+            INSTRUCTION_LENGTH
+        ));
+    }
+    for (index, chunk) in bytes.chunks(INSTRUCTION_LENGTH).enumerate() {
+        let opcode = Instruction::decode(chunk).map(|i| i.opcode());
+        // IGL decodes every unknown byte, only its own encoding is accepted
+        if opcode == Some(Opcode::IGL) && chunk[0] != Opcode::IGL as u8 {
+            return Err(format!(
+                "unknown opcode {:02x} in instruction {}",
+                chunk[0], index
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_program_dump() {
+        let bytes = [0, 1, 0, 5, 11, 0, 0, 0, 13];
+        assert_eq!(
+            program(&bytes, 2..usize::MAX),
+            "000000  00 01 00 05  LOAD $1 #5\n\
+             000004  0b 00 00 00  HLT\n\
+             000008  0d           <truncated>\n"
+        );
+        assert_eq!(program(&bytes, 4..8), "000004  0b 00 00 00  HLT\n");
+    }
+
+    #[test]
+    fn test_heap_dump() {
+        let mut bytes = b"Hello".to_vec();
+        bytes.resize(18, 0);
+        let dump = heap(&bytes, 0..usize::MAX);
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("000000  48 65 6c 6c 6f 00"));
+        assert!(lines[0].ends_with("|Hello...........|"));
+        assert!(lines[1].starts_with("000010  00 00 "));
+    }
+
+    #[test]
+    fn test_parse_and_validate_hex() {
+        assert_eq!(parse_hex("0c 01  05 0x00"), Ok(vec![12, 1, 5, 0]));
+        assert!(parse_hex("0c zz").is_err());
+        assert!(validate(&[12, 1, 5, 0]).is_ok());
+        assert!(validate(&[255, 0, 0, 0]).is_ok());
+        assert!(validate(&[12, 1, 5]).is_err());
+        assert!(validate(&[200, 0, 0, 0]).is_err());
+    }
+}
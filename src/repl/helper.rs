@@ -0,0 +1,163 @@
+//! Tab completion for the line editor of the REPL.
+//!
+//! The word under the cursor is completed according to its first character:
+//! dot-commands after `.`, register names after `$` and known labels after
+//! `@`. Any other word is completed as an opcode mnemonic.
+
+use std::collections::BTreeSet;
+
+use rustyline::{
+    completion::Completer, highlight::Highlighter, hint::Hinter, validate::Validator, Context,
+    Helper,
+};
+
+use crate::{
+    instruction::Opcode,
+    register::{self, REGISTER_COUNT},
+};
+
+/// Commands of the REPL, for completion
+pub const COMMANDS: [&str; 28] = [
+    ".assert",
+    ".begin",
+    ".clear",
+    ".dump",
+    ".end",
+    ".expect_output",
+    ".flags",
+    ".heap",
+    ".hex",
+    ".history",
+    ".load_file",
+    ".load_state",
+    ".pc",
+    ".program",
+    ".ps",
+    ".quit",
+    ".record",
+    ".register",
+    ".registers",
+    ".reload",
+    ".reverse-continue",
+    ".reverse-step",
+    ".save_state",
+    ".set",
+    ".step",
+    ".symbols",
+    ".unwatch",
+    ".watch",
+];
+
+#[derive(Default)]
+pub struct ReplHelper {
+    /// Labels known to the REPL, without the `@` sign
+    pub labels: BTreeSet<String>,
+}
+
+impl ReplHelper {
+    /// Returns where the word ending at `pos` starts, and the words it could
+    /// be completed to
+    pub fn candidates(&self, line: &str, pos: usize) -> (usize, Vec<String>) {
+        let start = line[..pos]
+            .rfind(|c: char| c.is_whitespace() || c == ',')
+            .map_or(0, |i| i + 1);
+        let word = &line[start..pos];
+        let words: Vec<String> = if word.starts_with('.') {
+            if start > 0 {
+                // Only the first word is a command
+                return (start, vec![]);
+            }
+            COMMANDS.iter().map(|c| c.to_string()).collect()
+        } else if word.starts_with('$') {
+            (0..REGISTER_COUNT as u8)
+                .map(register::register_name)
+                .collect()
+        } else if word.starts_with('@') {
+            self.labels.iter().map(|l| format!("@{}", l)).collect()
+        } else {
+            mnemonics()
+        };
+        let mut matches: Vec<String> = words
+            .into_iter()
+            .filter(|w| w.len() > word.len() && starts_with_ignore_case(w, word))
+            .collect();
+        matches.sort();
+        matches.dedup();
+        (start, matches)
+    }
+}
+
+fn starts_with_ignore_case(word: &str, prefix: &str) -> bool {
+    word.get(..prefix.len())
+        .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+}
+
+/// Mnemonics of every opcode but IGL
+pub fn mnemonics() -> Vec<String> {
+    (0..=u8::MAX)
+        .map(Opcode::from)
+        .filter(|opcode| *opcode != Opcode::IGL)
+        .map(|opcode| opcode.to_string())
+        .collect()
+}
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(self.candidates(line, pos))
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ReplHelper {}
+
+impl Validator for ReplHelper {}
+
+impl Helper for ReplHelper {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_candidates() {
+        let mut helper = ReplHelper::default();
+        helper.labels.insert("loop".to_string());
+        helper.labels.insert("done".to_string());
+
+        assert_eq!(
+            helper.candidates(".re", 3),
+            (
+                0,
+                vec![
+                    ".record".to_string(),
+                    ".register".to_string(),
+                    ".registers".to_string(),
+                    ".reload".to_string(),
+                    ".reverse-continue".to_string(),
+                    ".reverse-step".to_string()
+                ]
+            )
+        );
+        assert_eq!(
+            helper.candidates("LOAD $s", 7),
+            (5, vec!["$sp".to_string()])
+        );
+        assert_eq!(helper.candidates("jm", 2).1, vec!["JMP", "JMPB", "JMPF"]);
+        assert_eq!(
+            helper.candidates("LOAD $1 @l", 10),
+            (8, vec!["@loop".to_string()])
+        );
+        assert!(helper.candidates(".watch .re", 10).1.is_empty());
+        assert!(helper.candidates("HLT", 3).1.is_empty());
+    }
+}
@@ -0,0 +1,118 @@
+//! Formatting of the VM state shown by the inspection commands of the REPL.
+
+use std::fmt::Write;
+
+use crate::{
+    assembler::symbol::SymbolTable,
+    flags::Flags,
+    register::{self, REGISTER_COUNT},
+    vm::objects::Value,
+};
+
+/// Registers shown on each line of the register table
+const REGISTERS_PER_LINE: usize = 2;
+
+/// Formats the registers as a table with their hex, unsigned and signed
+/// values. Registers holding a reference show the object instead of the
+/// numbers, and registers that differ from `previous` are marked with `*`.
+pub fn registers(values: &[Value], previous: &[i32]) -> String {
+    let mut table = String::new();
+    for (register, value) in values.iter().enumerate().take(REGISTER_COUNT) {
+        let bits = value.bits();
+        let changed = previous.get(register).is_some_and(|&p| p != bits);
+        let _ = write!(
+            table,
+            "{:<5} 0x{:08x} {:>11} {:>11}{}",
+            register::register_name(register as u8),
+            bits,
+            match value {
+                Value::Int(_) => (bits as u32).to_string(),
+                Value::Ref(_) => "object".to_string(),
+            },
+            value.to_string(),
+            if changed { " *" } else { "  " }
+        );
+        if (register + 1) % REGISTERS_PER_LINE == 0 {
+            table.push('\n');
+        } else {
+            table.push_str("   ");
+        }
+    }
+    table
+}
+
+/// Describes the flags and the comparisons they encode
+pub fn flags(flags: Flags) -> String {
+    let comparisons: Vec<&str> = [
+        (flags.equal(), "equal"),
+        (flags.less_than(), "less than"),
+        (flags.greater_than(), "greater than"),
+        (flags.below(), "below"),
+        (flags.above(), "above"),
+    ]
+    .into_iter()
+    .filter_map(|(set, name)| set.then_some(name))
+    .collect();
+    format!("{}  ({})", flags, comparisons.join(", "))
+}
+
+/// Lists the symbols by address
+pub fn symbols(symbols: &SymbolTable) -> String {
+    let mut symbols: Vec<_> = symbols.symbols().collect();
+    symbols.sort_by_key(|symbol| symbol.offset());
+    let mut listing = String::new();
+    for symbol in symbols {
+        let _ = writeln!(
+            listing,
+            "{:06x}  {:<9} {}",
+            symbol.offset(),
+            format!("{:?}", symbol.symbol_type()),
+            symbol.name()
+        );
+    }
+    listing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::symbol::{Symbol, SymbolType};
+
+    #[test]
+    fn test_registers() {
+        let mut values = vec![Value::Int(0); REGISTER_COUNT];
+        values[1] = Value::Int(-1);
+        values[2] = Value::Ref(3);
+        let mut previous = [0; REGISTER_COUNT];
+        previous[2] = 3;
+        let table = registers(&values, &previous);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), REGISTER_COUNT / REGISTERS_PER_LINE);
+        assert_eq!(
+            lines[0],
+            "$0    0x00000000           0           0     \
+             $1    0xffffffff  4294967295          -1 *"
+        );
+        assert!(lines[1].starts_with("$2    0x00000003      object          &3  "));
+        assert!(lines[15].ends_with("$zero 0x00000000           0           0  "));
+    }
+
+    #[test]
+    fn test_flags() {
+        assert_eq!(
+            flags(Flags::new(0, false, false)),
+            "Z=1 N=0 C=0 V=0  (equal)"
+        );
+    }
+
+    #[test]
+    fn test_symbols() {
+        let mut table = SymbolTable::new();
+        table.add_symbol(Symbol::new("end".to_string(), SymbolType::Label, 8));
+        table.add_symbol(Symbol::new("start".to_string(), SymbolType::Label, 0));
+        assert_eq!(
+            symbols(&table),
+            "000000  Label     start\n000008  Label     end\n"
+        );
+    }
+}
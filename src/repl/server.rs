@@ -0,0 +1,340 @@
+//! Remote REPL, serving clients over a local TCP or Unix socket.
+//!
+//! Every client has its own session, with its history, block and hex mode,
+//! while all of them share the VM of one REPL. Lines run one at a time, the
+//! REPL being locked while a line of any client runs. When the server has a
+//! secret, the first line of a client must be `AUTH <secret>`. Any local user
+//! can connect to a TCP port, so the REPL is only served over TCP with a
+//! secret.
+//!
+//! Clients cannot use the commands reading or writing files, such as
+//! `.load_file` or `.save_state`, which would give them access to the files of
+//! the user running the server.
+//!
+//! `.quit` or the end of the input closes the connection of the client, the
+//! server and the other clients carry on. A line runs at most
+//! `ReplServer::line_budget` instructions, so that a program that never ends
+//! cannot keep the REPL from the other clients.
+
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+
+use super::{Session, REPL, WELCOME};
+
+/// Instructions a line of a client may run by default
+pub const DEFAULT_LINE_BUDGET: u64 = 10_000_000;
+
+/// Where the server listens
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenAddress {
+    /// A TCP address on the loopback interface
+    Tcp(SocketAddr),
+    /// The path of a Unix socket
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddress {
+    type Err = String;
+
+    /// Parses `unix:PATH` or `HOST:PORT`, where the host is a loopback address
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(ListenAddress::Unix(PathBuf::from(path)));
+        }
+        let address = s
+            .to_socket_addrs()
+            .map_err(|e| format!("'{}' is not an address: {}", s, e))?
+            .next()
+            .ok_or_else(|| format!("'{}' does not resolve to an address", s))?;
+        if !address.ip().is_loopback() {
+            return Err(format!(
+                "'{}' is not a loopback address, the REPL is only served locally",
+                s
+            ));
+        }
+        Ok(ListenAddress::Tcp(address))
+    }
+}
+
+impl fmt::Display for ListenAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddress::Tcp(address) => write!(f, "{}", address),
+            ListenAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// A stream to a client
+pub trait Connection: Read + Write + Send + Sized + 'static {
+    fn try_clone(&self) -> io::Result<Self>;
+}
+
+impl Connection for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+}
+
+pub struct ReplServer {
+    /// The REPL shared by the clients
+    repl: Arc<Mutex<REPL>>,
+    /// Secret the clients must give before their first command
+    secret: Option<String>,
+    /// Instructions a line of a client may run before it is stopped
+    pub line_budget: u64,
+}
+
+impl ReplServer {
+    pub fn new(repl: REPL, secret: Option<String>) -> ReplServer {
+        ReplServer {
+            repl: Arc::new(Mutex::new(repl)),
+            secret,
+            line_budget: DEFAULT_LINE_BUDGET,
+        }
+    }
+
+    /// Listens on `address` and serves clients until the listener fails
+    pub fn serve(&self, address: &ListenAddress) -> io::Result<()> {
+        match address {
+            ListenAddress::Tcp(_) if self.secret.is_none() => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a secret is required to serve the REPL over TCP",
+            )),
+            ListenAddress::Tcp(address) => self.serve_tcp(TcpListener::bind(address)?),
+            #[cfg(unix)]
+            ListenAddress::Unix(path) => self.serve_unix(UnixListener::bind(path)?),
+            #[cfg(not(unix))]
+            ListenAddress::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Unix sockets are not supported on this platform",
+            )),
+        }
+    }
+
+    fn serve_tcp(&self, listener: TcpListener) -> io::Result<()> {
+        for stream in listener.incoming() {
+            self.spawn_client(stream?);
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn serve_unix(&self, listener: UnixListener) -> io::Result<()> {
+        for stream in listener.incoming() {
+            self.spawn_client(stream?);
+        }
+        Ok(())
+    }
+
+    /// Serves a client on its own thread
+    fn spawn_client<C: Connection>(&self, stream: C) {
+        let repl = Arc::clone(&self.repl);
+        let secret = self.secret.clone();
+        let session = Session {
+            line_budget: Some(self.line_budget),
+            remote: true,
+            ..Session::default()
+        };
+        thread::spawn(move || {
+            if let Err(e) = serve_client(&repl, secret.as_deref(), session, stream) {
+                log::debug!("Lost a client of the REPL: {}", e);
+            }
+        });
+    }
+}
+
+fn serve_client<C: Connection>(
+    repl: &Mutex<REPL>,
+    secret: Option<&str>,
+    mut session: Session,
+    stream: C,
+) -> io::Result<()> {
+    let mut lines = BufReader::new(stream.try_clone()?).lines();
+    let mut writer = stream;
+    if let Some(secret) = secret {
+        let line = lines.next().transpose()?.unwrap_or_default();
+        let given = line.trim_end().strip_prefix("AUTH ").unwrap_or_default();
+        if !secrets_match(given, secret) {
+            writeln!(writer, "Authentication failed")?;
+            return Ok(());
+        }
+    }
+    writeln!(writer, "{}", WELCOME)?;
+    write!(writer, "{}", session.prompt())?;
+    for line in lines {
+        let line = line?;
+        let line = line.trim();
+        // `.quit` would end the whole server, whatever its argument
+        if line.split_whitespace().next() == Some(".quit") && session.block.is_none() {
+            break;
+        }
+        let out = Box::new(writer.try_clone()?);
+        // A line that panicked was reported to its client, keep serving the
+        // others
+        let mut repl = repl.lock().unwrap_or_else(PoisonError::into_inner);
+        session = repl.handle_remote_line(session, out, line);
+        drop(repl);
+        write!(writer, "{}", session.prompt())?;
+    }
+    Ok(())
+}
+
+/// Compares a secret in a time that does not depend on where it differs
+fn secrets_match(given: &str, secret: &str) -> bool {
+    let (given, secret) = (given.as_bytes(), secret.as_bytes());
+    given.len() == secret.len()
+        && given
+            .iter()
+            .zip(secret)
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start_server(secret: Option<&str>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut server = ReplServer::new(REPL::with_history(None), secret.map(String::from));
+        server.line_budget = 1000;
+        thread::spawn(move || server.serve_tcp(listener));
+        address
+    }
+
+    /// Reads the output of the server up to the next prompt
+    fn read_until_prompt(stream: &mut TcpStream) -> String {
+        let mut output = vec![];
+        let mut byte = [0];
+        while !output.ends_with(b"> ") && !output.ends_with(b"... ") {
+            if stream.read(&mut byte).unwrap() == 0 {
+                break;
+            }
+            output.push(byte[0]);
+        }
+        String::from_utf8(output).unwrap()
+    }
+
+    fn send(stream: &mut TcpStream, line: &str) -> String {
+        writeln!(stream, "{}", line).unwrap();
+        read_until_prompt(stream)
+    }
+
+    #[test]
+    fn test_clients_share_the_vm() {
+        let address = start_server(None);
+        let mut first = TcpStream::connect(address).unwrap();
+        let mut second = TcpStream::connect(address).unwrap();
+        assert!(read_until_prompt(&mut first).starts_with(WELCOME));
+        read_until_prompt(&mut second);
+
+        send(&mut first, "LOAD $1 #42");
+        assert_eq!(send(&mut second, ".assert $1 == 42"), ">>> ");
+
+        // Each client has its own history and block
+        assert!(send(&mut first, ".begin").ends_with("... "));
+        assert_eq!(
+            send(&mut second, ".history"),
+            ".assert $1 == 42\n.history\n>>> "
+        );
+        send(&mut first, "LOAD $2 #7");
+        send(&mut first, ".end");
+        assert_eq!(send(&mut second, ".assert $2 == 7"), ">>> ");
+
+        // Leaving closes the connection of the client only
+        writeln!(first, ".quit").unwrap();
+        assert_eq!(read_until_prompt(&mut first), "");
+        assert!(send(&mut second, ".pc").contains("<end of program>"));
+    }
+
+    #[test]
+    fn test_quit_and_endless_lines() {
+        let address = start_server(None);
+        let mut first = TcpStream::connect(address).unwrap();
+        let mut second = TcpStream::connect(address).unwrap();
+        read_until_prompt(&mut first);
+        read_until_prompt(&mut second);
+
+        // The server keeps running whatever follows `.quit`
+        writeln!(first, ".quit now").unwrap();
+        assert_eq!(read_until_prompt(&mut first), "");
+
+        send(&mut second, ".begin");
+        send(&mut second, "loop: LOAD $1 @loop");
+        send(&mut second, "JMP $1");
+        assert!(send(&mut second, ".end").contains("Stopped at"));
+
+        let mut third = TcpStream::connect(address).unwrap();
+        read_until_prompt(&mut third);
+        send(&mut third, "LOAD $2 #5");
+        assert_eq!(send(&mut third, ".assert $2 == 5"), ">>> ");
+    }
+
+    #[test]
+    fn test_secret() {
+        let address = start_server(Some("hunter2"));
+        let mut client = TcpStream::connect(address).unwrap();
+        writeln!(client, "AUTH wrong").unwrap();
+        assert_eq!(read_until_prompt(&mut client), "Authentication failed\n");
+
+        let mut client = TcpStream::connect(address).unwrap();
+        writeln!(client, "AUTH hunter2").unwrap();
+        assert!(read_until_prompt(&mut client).starts_with(WELCOME));
+    }
+
+    #[test]
+    fn test_files_and_tcp_without_secret() {
+        let address = start_server(None);
+        let mut client = TcpStream::connect(address).unwrap();
+        read_until_prompt(&mut client);
+        for command in [
+            ".load_file /etc/passwd",
+            ".save_state /tmp/vm.state",
+            ".reload",
+        ] {
+            assert!(send(&mut client, command).starts_with("Files are out of reach"));
+        }
+
+        let server = ReplServer::new(REPL::with_history(None), None);
+        let result = server.serve(&"127.0.0.1:0".parse().unwrap());
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_secrets_match() {
+        assert!(secrets_match("hunter2", "hunter2"));
+        assert!(!secrets_match("hunter3", "hunter2"));
+        assert!(!secrets_match("hunter", "hunter2"));
+        assert!(!secrets_match("", "hunter2"));
+    }
+
+    #[test]
+    fn test_listen_address() {
+        assert_eq!(
+            "127.0.0.1:4000".parse(),
+            Ok(ListenAddress::Tcp("127.0.0.1:4000".parse().unwrap()))
+        );
+        assert_eq!(
+            "unix:/tmp/vm.sock".parse(),
+            Ok(ListenAddress::Unix(PathBuf::from("/tmp/vm.sock")))
+        );
+        assert!("0.0.0.0:4000".parse::<ListenAddress>().is_err());
+        assert!("localhost".parse::<ListenAddress>().is_err());
+    }
+}
@@ -0,0 +1,516 @@
+//! Green threads: many VM processes sharing one OS thread.
+//!
+//! Every process is a `VM` of its own, with its own registers, PC and heap.
+//! The scheduler runs the ready processes in turn, each for at most a time
+//! slice of instructions, and carries out the SPAWN, YIELD and JOIN requests
+//! they make.
+//!
+//! Processes share no memory and talk by message passing: SEND and SENDB
+//! append a message to the mailbox of another process, RECV takes the oldest
+//! one, parking the process until a message arrives if the mailbox is empty.
+//! TRYRECV sets the zero flag instead of waiting.
+
+use std::{collections::VecDeque, fmt::Write};
+
+use crate::vm::{Payload, ProcessRequest, ReceiveRegisters, StopReason, VMError, VM};
+
+/// Identifier of a process, unique within a scheduler
+pub type Pid = usize;
+
+/// Instructions a process runs before it is preempted, by default
+pub const DEFAULT_TIME_SLICE: usize = 100;
+
+/// Value given by JOIN when the process did not halt normally
+pub const FAILED_EXIT_VALUE: i32 = -1;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ProcessState {
+    Ready,
+    /// Blocked until the process `pid` ends, to write its exit value to
+    /// `register`
+    Joining {
+        pid: Pid,
+        register: usize,
+    },
+    /// Blocked until a message arrives in the mailbox
+    Receiving(ReceiveRegisters),
+    Halted,
+    Faulted(VMError),
+}
+
+impl ProcessState {
+    /// Whether the process has ended and will never run again
+    pub fn is_done(&self) -> bool {
+        matches!(self, ProcessState::Halted | ProcessState::Faulted(_))
+    }
+}
+
+/// Sender of the messages that do not come from a process of the scheduler
+pub const EXTERNAL_SENDER: i32 = -1;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Message {
+    /// Pid of the sending process, or `EXTERNAL_SENDER`
+    pub sender: i32,
+    pub payload: Payload,
+}
+
+pub struct Process {
+    pub pid: Pid,
+    pub vm: VM,
+    pub state: ProcessState,
+    /// Messages received and not read yet, oldest first
+    pub mailbox: VecDeque<Message>,
+}
+
+impl Process {
+    /// Value of `$0` if the process halted, `FAILED_EXIT_VALUE` if it faulted,
+    /// or `None` while it is still running
+    pub fn exit_value(&self) -> Option<i32> {
+        match self.state {
+            ProcessState::Halted => Some(self.vm.registers[0]),
+            ProcessState::Faulted(_) => Some(FAILED_EXIT_VALUE),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SchedulerError {
+    /// Every process left is waiting on another one or on a message
+    Deadlock { pids: Vec<Pid> },
+}
+
+/// Round-robin scheduler preempting processes after a number of instructions
+pub struct Scheduler {
+    processes: Vec<Process>,
+    next_pid: Pid,
+    time_slice: usize,
+}
+
+impl Scheduler {
+    pub fn new(time_slice: usize) -> Scheduler {
+        Scheduler {
+            processes: vec![],
+            next_pid: 0,
+            time_slice: time_slice.max(1),
+        }
+    }
+
+    /// Adds a process, which starts running from the current PC of `vm`
+    pub fn spawn(&mut self, vm: VM) -> Pid {
+        let pid = self.next_pid;
+        self.next_pid += 1;
+        self.processes.push(Process {
+            pid,
+            vm,
+            state: ProcessState::Ready,
+            mailbox: VecDeque::new(),
+        });
+        pid
+    }
+
+    /// Appends a message to the mailbox of the process `pid`. Messages to a
+    /// process that does not exist or has ended are dropped, and `false` is
+    /// returned.
+    pub fn send(&mut self, pid: i32, message: Message) -> bool {
+        let process = usize::try_from(pid)
+            .ok()
+            .and_then(|pid| self.process_mut(pid))
+            .filter(|p| !p.state.is_done());
+        match process {
+            Some(process) => {
+                process.mailbox.push_back(message);
+                true
+            }
+            None => {
+                log::debug!("Dropping a message to process {}", pid);
+                false
+            }
+        }
+    }
+
+    pub fn process(&self, pid: Pid) -> Option<&Process> {
+        self.processes.iter().find(|p| p.pid == pid)
+    }
+
+    pub fn process_mut(&mut self, pid: Pid) -> Option<&mut Process> {
+        self.processes.iter_mut().find(|p| p.pid == pid)
+    }
+
+    pub fn processes(&self) -> &[Process] {
+        &self.processes
+    }
+
+    pub fn exit_value(&self, pid: Pid) -> Option<i32> {
+        self.process(pid).and_then(Process::exit_value)
+    }
+
+    /// Runs until every process has ended
+    pub fn run(&mut self) -> Result<(), SchedulerError> {
+        while self.processes.iter().any(|p| !p.state.is_done()) {
+            self.round()?;
+        }
+        Ok(())
+    }
+
+    /// Runs until the process `pid` has ended, or does nothing if there is no
+    /// such process
+    pub fn run_until_done(&mut self, pid: Pid) -> Result<(), SchedulerError> {
+        while self.process(pid).is_some_and(|p| !p.state.is_done()) {
+            self.round()?;
+        }
+        Ok(())
+    }
+
+    /// Gives one time slice to every process that is ready to run. Processes
+    /// spawned during the round first run in the next one.
+    pub fn round(&mut self) -> Result<(), SchedulerError> {
+        let mut progressed = self.wake_blocked();
+        for index in 0..self.processes.len() {
+            if self.processes[index].state != ProcessState::Ready {
+                continue;
+            }
+            progressed = true;
+            let result = self.processes[index].vm.run_for(self.time_slice);
+            self.processes[index].state = match result {
+                Ok(StopReason::Halted) => ProcessState::Halted,
+                Ok(StopReason::Preempted) => ProcessState::Ready,
+                Ok(StopReason::Request(request)) => self.carry_out(index, request),
+                Err(e) => ProcessState::Faulted(e),
+            };
+        }
+
+        let blocked: Vec<Pid> = self
+            .processes
+            .iter()
+            .filter(|p| !p.state.is_done())
+            .map(|p| p.pid)
+            .collect();
+        if !progressed && !blocked.is_empty() {
+            return Err(SchedulerError::Deadlock { pids: blocked });
+        }
+        Ok(())
+    }
+
+    /// Carries out a request of the process at `index`, returning its new
+    /// state
+    fn carry_out(&mut self, index: usize, request: ProcessRequest) -> ProcessState {
+        let pid = self.processes[index].pid;
+        match request {
+            ProcessRequest::Yield => ProcessState::Ready,
+            ProcessRequest::Spawn { pc, register } => {
+                let child = self.processes[index].vm.spawn_child(pc);
+                let child_pid = self.spawn(child);
+                self.processes[index]
+                    .vm
+                    .set_register(register, child_pid as i32);
+                ProcessState::Ready
+            }
+            ProcessRequest::Join { register } => {
+                let vm = &mut self.processes[index].vm;
+                let target = vm.registers[register];
+                if target < 0 || target as Pid == pid {
+                    vm.set_register(register, FAILED_EXIT_VALUE);
+                    ProcessState::Ready
+                } else {
+                    ProcessState::Joining {
+                        pid: target as Pid,
+                        register,
+                    }
+                }
+            }
+            ProcessRequest::Send { pid: to, payload } => {
+                let sender = pid as i32;
+                self.send(to, Message { sender, payload });
+                ProcessState::Ready
+            }
+            ProcessRequest::Receive { registers, block } => {
+                let process = &mut self.processes[index];
+                match process.mailbox.pop_front() {
+                    Some(message) => {
+                        match process
+                            .vm
+                            .receive(message.sender, message.payload, registers)
+                        {
+                            Ok(()) => ProcessState::Ready,
+                            Err(e) => ProcessState::Faulted(e),
+                        }
+                    }
+                    None if block => ProcessState::Receiving(registers),
+                    None => {
+                        process.vm.mailbox_empty();
+                        ProcessState::Ready
+                    }
+                }
+            }
+        }
+    }
+
+    /// Resumes the processes whose JOIN target has ended, giving them its exit
+    /// value, and the ones waiting for a message that arrived. Returns whether
+    /// any process was resumed.
+    fn wake_blocked(&mut self) -> bool {
+        let mut woken = false;
+        for index in 0..self.processes.len() {
+            let process = &mut self.processes[index];
+            if let ProcessState::Receiving(registers) = process.state {
+                if let Some(message) = process.mailbox.pop_front() {
+                    process.state =
+                        match process
+                            .vm
+                            .receive(message.sender, message.payload, registers)
+                        {
+                            Ok(()) => ProcessState::Ready,
+                            Err(e) => ProcessState::Faulted(e),
+                        };
+                    woken = true;
+                }
+                continue;
+            }
+            let ProcessState::Joining { pid, register } = process.state else {
+                continue;
+            };
+            let exit_value = match self.process(pid) {
+                Some(process) => process.exit_value(),
+                None => Some(FAILED_EXIT_VALUE),
+            };
+            if let Some(value) = exit_value {
+                let process = &mut self.processes[index];
+                process.vm.set_register(register, value);
+                process.state = ProcessState::Ready;
+                woken = true;
+            }
+        }
+        woken
+    }
+
+    /// Process table, one line per process
+    pub fn table(&self) -> String {
+        let mut table = String::new();
+        let _ = writeln!(
+            table,
+            "{:>5}  {:<12} {:>8} {:>12} {:>8}",
+            "PID", "STATE", "PC", "CYCLES", "MAILBOX"
+        );
+        for process in &self.processes {
+            let state = match &process.state {
+                ProcessState::Ready => "ready".to_string(),
+                ProcessState::Joining { pid, .. } => format!("joining {}", pid),
+                ProcessState::Receiving(_) => "receiving".to_string(),
+                ProcessState::Halted => "halted".to_string(),
+                ProcessState::Faulted(_) => "faulted".to_string(),
+            };
+            let _ = writeln!(
+                table,
+                "{:>5}  {:<12} {:>8x} {:>12} {:>8}",
+                process.pid,
+                state,
+                process.vm.pc(),
+                process.vm.cycles().total(),
+                process.mailbox.len()
+            );
+        }
+        table
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new(DEFAULT_TIME_SLICE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vm(program: Vec<u8>) -> VM {
+        let mut vm = VM::new();
+        vm.program = program;
+        vm
+    }
+
+    #[test]
+    fn test_spawn_and_join() {
+        let mut scheduler = Scheduler::new(2);
+        let pid = scheduler.spawn(vm(vec![
+            0, 1, 0, 16, // LOAD $1 #16
+            26, 1, 2, 0, // SPAWN $1 $2
+            28, 2, 0, 0, // JOIN $2
+            11, 0, 0, 0, // HLT
+            0, 0, 0, 42, // child: LOAD $0 #42
+            11, 0, 0, 0, // HLT
+        ]));
+        scheduler.run().unwrap();
+
+        let parent = scheduler.process(pid).unwrap();
+        assert_eq!(parent.state, ProcessState::Halted);
+        assert_eq!(parent.vm.registers[2], 42);
+        assert_eq!(parent.vm.registers[0], 0);
+        assert_eq!(scheduler.exit_value(1), Some(42));
+        assert_eq!(scheduler.processes().len(), 2);
+    }
+
+    #[test]
+    fn test_preemption_interleaves_processes() {
+        let looping = vec![
+            13, 0, 0, 0, // INC $0
+            7, 0, 0, 0, // JMPB $0 : $0 is 0, jumps back to INC
+        ];
+        let mut scheduler = Scheduler::new(10);
+        let first = scheduler.spawn(vm(looping.clone()));
+        let second = scheduler.spawn(vm(looping));
+        for _ in 0..3 {
+            scheduler.round().unwrap();
+        }
+        let first = &scheduler.process(first).unwrap().vm;
+        let second = &scheduler.process(second).unwrap().vm;
+        assert!(first.registers[0] > 0);
+        assert_eq!(first.registers[0], second.registers[0]);
+    }
+
+    #[test]
+    fn test_yield_and_faults() {
+        let mut scheduler = Scheduler::new(100);
+        let yielding = scheduler.spawn(vm(vec![
+            27, 0, 0, 0, // YIELD
+            11, 0, 0, 0, // HLT
+        ]));
+        let faulting = scheduler.spawn(vm(vec![
+            4, 0, 1, 2, // DIV $0 $1 $2
+        ]));
+        scheduler.round().unwrap();
+        assert_eq!(scheduler.process(yielding).unwrap().vm.pc(), 4);
+        assert_eq!(scheduler.exit_value(faulting), Some(FAILED_EXIT_VALUE));
+        scheduler.run().unwrap();
+        assert_eq!(scheduler.exit_value(yielding), Some(0));
+    }
+
+    #[test]
+    fn test_deadlock() {
+        let mut scheduler = Scheduler::new(100);
+        let join_other = |pid| {
+            vm(vec![
+                0, 1, 0, pid, // LOAD $1 #pid
+                28, 1, 0, 0, // JOIN $1
+            ])
+        };
+        scheduler.spawn(join_other(1));
+        scheduler.spawn(join_other(0));
+        assert_eq!(
+            scheduler.run(),
+            Err(SchedulerError::Deadlock { pids: vec![0, 1] })
+        );
+    }
+
+    #[test]
+    fn test_join_self_fails() {
+        let mut scheduler = Scheduler::new(100);
+        let pid = scheduler.spawn(vm(vec![
+            28, 0, 0, 0, // JOIN $0 : $0 holds our own pid
+        ]));
+        scheduler.run().unwrap();
+        assert_eq!(
+            scheduler.process(pid).unwrap().vm.registers[0],
+            FAILED_EXIT_VALUE
+        );
+    }
+
+    #[test]
+    fn test_send_and_receive() {
+        let mut scheduler = Scheduler::new(100);
+        let parent = scheduler.spawn(vm(vec![
+            0, 1, 0, 24, // LOAD $1 #24
+            26, 1, 2, 0, // SPAWN $1 $2
+            0, 3, 0, 41, // LOAD $3 #41
+            29, 2, 3, 0, // SEND $2 $3
+            31, 4, 5, 6, // RECV $4 $5 $6
+            11, 0, 0, 0, // HLT
+            31, 1, 2, 3, // child: RECV $1 $2 $3
+            13, 1, 0, 0, // INC $1
+            29, 2, 1, 0, // SEND $2 $1
+            11, 0, 0, 0, // HLT
+        ]));
+        scheduler.run().unwrap();
+
+        let parent = &scheduler.process(parent).unwrap().vm;
+        assert_eq!(parent.registers[4], 42);
+        assert_eq!(parent.registers[5], 1);
+        assert_eq!(parent.registers[6], -1);
+    }
+
+    #[test]
+    fn test_buffer_and_non_blocking_receive() {
+        let mut scheduler = Scheduler::new(100);
+        let pid = scheduler.spawn(vm(vec![
+            0, 0, 0, 3, // LOAD $0 #3
+            12, 0, 10, 0, // ALOC $0 $10
+            0, 2, 0, 1, // LOAD $2 #1
+            0, 3, 0, 2, // LOAD $3 #2
+            30, 1, 2, 3, // SENDB $1 $2 $3 : to ourselves
+            32, 4, 5, 6, // TRYRECV $4 $5 $6
+            32, 7, 8, 9, // TRYRECV $7 $8 $9 : nothing left
+            11, 0, 0, 0, // HLT
+        ]));
+        scheduler.run().unwrap();
+
+        let vm = &scheduler.process(pid).unwrap().vm;
+        assert_eq!(vm.registers[4], 3);
+        assert_eq!(vm.registers[5], 0);
+        assert_eq!(vm.registers[6], 2);
+        assert_eq!(vm.registers[7], 0);
+        assert!(vm.flags().zero);
+    }
+
+    #[test]
+    fn test_messages_to_ended_processes_are_dropped() {
+        let mut scheduler = Scheduler::new(100);
+        let pid = scheduler.spawn(vm(vec![
+            11, 0, 0, 0, // HLT
+        ]));
+        let message = Message {
+            sender: EXTERNAL_SENDER,
+            payload: Payload::Word(1),
+        };
+        assert!(scheduler.send(pid as i32, message.clone()));
+        scheduler.run().unwrap();
+        assert!(!scheduler.send(pid as i32, message.clone()));
+        assert!(!scheduler.send(7, message));
+    }
+
+    #[test]
+    fn test_receive_deadlock() {
+        let mut scheduler = Scheduler::new(100);
+        scheduler.spawn(vm(vec![
+            31, 0, 1, 2, // RECV $0 $1 $2
+        ]));
+        assert_eq!(
+            scheduler.run(),
+            Err(SchedulerError::Deadlock { pids: vec![0] })
+        );
+        assert_eq!(scheduler.process(0).unwrap().mailbox.len(), 0);
+    }
+
+    #[test]
+    fn test_table() {
+        let mut scheduler = Scheduler::new(100);
+        let pid = scheduler.spawn(vm(vec![
+            11, 0, 0, 0, // HLT
+        ]));
+        let message = Message {
+            sender: EXTERNAL_SENDER,
+            payload: Payload::Word(1),
+        };
+        assert!(scheduler.send(pid as i32, message));
+        let table = scheduler.table();
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                "  PID  STATE              PC       CYCLES  MAILBOX",
+                "    0  ready               0            0        1",
+            ]
+        );
+    }
+}
@@ -0,0 +1,278 @@
+use std::{
+    io::{self, Write},
+    ops::Range,
+};
+
+use serde_json::json;
+
+use crate::{
+    flags::Flags,
+    instruction::{Instruction, Opcode},
+    register::{self, REGISTER_COUNT},
+};
+
+/// How each traced instruction is written out
+#[derive(Debug, Default, PartialEq, Clone, Copy, clap::ValueEnum)]
+pub enum TraceFormat {
+    /// One human readable line per instruction
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// Restricts which executed instructions end up in the trace. An empty filter
+/// lets everything through.
+#[derive(Debug, Default, Clone)]
+pub struct TraceFilter {
+    /// Only trace instructions whose address falls in this range
+    pub pc_range: Option<Range<usize>>,
+    /// Only trace these opcodes
+    pub opcodes: Vec<Opcode>,
+}
+
+impl TraceFilter {
+    pub fn matches(&self, pc: usize, opcode: Opcode) -> bool {
+        if let Some(range) = &self.pc_range {
+            if !range.contains(&pc) {
+                return false;
+            }
+        }
+        self.opcodes.is_empty() || self.opcodes.contains(&opcode)
+    }
+}
+
+/// A register whose value was changed by an instruction
+#[derive(Debug, PartialEq)]
+pub struct RegisterChange {
+    pub register: u8,
+    pub old: i32,
+    pub new: i32,
+}
+
+/// VM state observed before running an instruction, compared with the state
+/// after it ran to find out what it changed
+pub struct TraceState {
+    pub pc: usize,
+    pub registers: [i32; REGISTER_COUNT],
+    pub flags: Flags,
+}
+
+/// Writes out every instruction executed by the VM it is attached to
+pub struct Tracer {
+    format: TraceFormat,
+    filter: TraceFilter,
+    output: Box<dyn Write + Send>,
+}
+
+impl Tracer {
+    pub fn new(format: TraceFormat, filter: TraceFilter, output: Box<dyn Write + Send>) -> Tracer {
+        Tracer {
+            format,
+            filter,
+            output,
+        }
+    }
+
+    /// A tracer writing to the standard error, leaving the standard output to
+    /// the program
+    pub fn stderr(format: TraceFormat, filter: TraceFilter) -> Tracer {
+        Tracer::new(format, filter, Box::new(io::stderr()))
+    }
+
+    /// Writes out the instruction that ran from the state `before`, along
+    /// with its `source` line when the program has debug information
+    pub fn trace(
+        &mut self,
+        before: &TraceState,
+        instruction: &Instruction,
+        source: Option<&str>,
+        registers: &[i32; REGISTER_COUNT],
+        flags: Flags,
+    ) {
+        if !self.filter.matches(before.pc, instruction.opcode()) {
+            return;
+        }
+        let changes: Vec<RegisterChange> = (0..REGISTER_COUNT)
+            .filter(|&i| before.registers[i] != registers[i])
+            .map(|i| RegisterChange {
+                register: i as u8,
+                old: before.registers[i],
+                new: registers[i],
+            })
+            .collect();
+        let flags = (flags != before.flags).then_some(flags);
+
+        // A broken trace output should not bring the VM down
+        let _ = match self.format {
+            TraceFormat::Text => self.write_text(before.pc, instruction, source, &changes, flags),
+            TraceFormat::Json => self.write_json(before.pc, instruction, source, &changes, flags),
+        };
+    }
+
+    fn write_text(
+        &mut self,
+        pc: usize,
+        instruction: &Instruction,
+        source: Option<&str>,
+        changes: &[RegisterChange],
+        flags: Option<Flags>,
+    ) -> io::Result<()> {
+        let mut effects: Vec<String> = changes
+            .iter()
+            .map(|c| {
+                format!(
+                    "{}: {} -> {}",
+                    register::register_name(c.register),
+                    c.old,
+                    c.new
+                )
+            })
+            .collect();
+        if let Some(flags) = flags {
+            effects.push(flags.to_string());
+        }
+        let source = source.map(|s| format!("{:<16} ", s)).unwrap_or_default();
+        writeln!(
+            self.output,
+            "{:06x}  {}{:<20} {}",
+            pc,
+            source,
+            instruction.to_string(),
+            effects.join(", ")
+        )
+    }
+
+    fn write_json(
+        &mut self,
+        pc: usize,
+        instruction: &Instruction,
+        source: Option<&str>,
+        changes: &[RegisterChange],
+        flags: Option<Flags>,
+    ) -> io::Result<()> {
+        let registers: Vec<_> = changes
+            .iter()
+            .map(|c| json!({ "register": c.register, "old": c.old, "new": c.new }))
+            .collect();
+        let mut line = json!({
+            "pc": pc,
+            "opcode": instruction.opcode().to_string(),
+            "instruction": instruction.to_string(),
+            "registers": registers,
+        });
+        if let Some(flags) = flags {
+            line["flags"] = json!(flags.to_string());
+        }
+        if let Some(source) = source {
+            line["source"] = json!(source);
+        }
+        writeln!(self.output, "{}", line)
+    }
+}
+
+/// Parses a PC range given on the command line, as `start..end` where both
+/// bounds are decimal or `0x` prefixed hexadecimal addresses
+pub fn parse_pc_range(s: &str) -> Result<Range<usize>, String> {
+    let (start, end) = s
+        .split_once("..")
+        .ok_or_else(|| format!("expected a range like 64..128, got '{}'", s))?;
+    Ok(parse_address(start)?..parse_address(end)?)
+}
+
+/// Parses an address, in decimal or `0x` prefixed hexadecimal
+pub fn parse_address(s: &str) -> Result<usize, String> {
+    let s = s.trim();
+    let parsed = match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => s.parse::<usize>(),
+    };
+    parsed.map_err(|_| format!("invalid address '{}'", s))
+}
+
+/// Parses an opcode mnemonic given on the command line
+pub fn parse_opcode(s: &str) -> Result<Opcode, String> {
+    match Opcode::from(s) {
+        Opcode::IGL => Err(format!("unknown opcode '{}'", s)),
+        opcode => Ok(opcode),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    /// Output shared with the test, to read back what the tracer wrote
+    #[derive(Clone, Default)]
+    struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn trace_add(format: TraceFormat, filter: TraceFilter) -> String {
+        let output = SharedOutput::default();
+        let mut tracer = Tracer::new(format, filter, Box::new(output.clone()));
+        let before = TraceState {
+            pc: 68,
+            registers: [0; REGISTER_COUNT],
+            flags: Flags::default(),
+        };
+        let mut registers = [0; REGISTER_COUNT];
+        registers[2] = 25;
+        let instruction = Instruction::decode(&[1, 0, 1, 2]).unwrap();
+        tracer.trace(&before, &instruction, None, &registers, Flags::default());
+        let written = output.0.lock().unwrap().clone();
+        String::from_utf8(written).unwrap()
+    }
+
+    #[test]
+    fn test_text_trace() {
+        let line = trace_add(TraceFormat::Text, TraceFilter::default());
+        assert!(line.starts_with("000044  ADD $0 $1 $2"));
+        assert!(line.trim_end().ends_with("$2: 0 -> 25"));
+    }
+
+    #[test]
+    fn test_json_trace() {
+        let line = trace_add(TraceFormat::Json, TraceFilter::default());
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["pc"], 68);
+        assert_eq!(value["opcode"], "ADD");
+        assert_eq!(value["registers"][0]["register"], 2);
+        assert_eq!(value["registers"][0]["new"], 25);
+        assert!(value.get("flags").is_none());
+    }
+
+    #[test]
+    fn test_trace_filter() {
+        let filter = TraceFilter {
+            pc_range: Some(0..64),
+            opcodes: vec![],
+        };
+        assert_eq!(trace_add(TraceFormat::Text, filter), "");
+
+        let filter = TraceFilter {
+            pc_range: None,
+            opcodes: vec![Opcode::ADD],
+        };
+        assert!(!trace_add(TraceFormat::Text, filter).is_empty());
+    }
+
+    #[test]
+    fn test_parse_pc_range() {
+        assert_eq!(parse_pc_range("64..128"), Ok(64..128));
+        assert_eq!(parse_pc_range("0x40..0x80"), Ok(64..128));
+        assert!(parse_pc_range("64").is_err());
+        assert!(parse_opcode("add").is_ok());
+        assert!(parse_opcode("nope").is_err());
+    }
+}
@@ -1,28 +1,227 @@
-use crate::{assembler::PIE_HEADER_PREFIX, instruction::Opcode};
+pub mod allocator;
+pub mod objects;
+pub mod policy;
+pub mod recorder;
+pub mod snapshot;
+pub mod syscall;
+
+use std::{io::Write, sync::Arc, time::Instant};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::{
+    assembler::{
+        debug_info::{DebugInfo, DEBUG_OFFSET_RANGE},
+        PIE_HEADER_LENGTH, PIE_HEADER_PREFIX,
+    },
+    cost::{CostTable, CycleCounter},
+    flags::Flags,
+    instruction::{Instruction, Opcode, INSTRUCTION_LENGTH},
+    profiler::Profiler,
+    register::{self, REGISTER_COUNT},
+    trace::{TraceState, Tracer},
+};
+
+use self::{
+    allocator::{AllocationError, Allocator, HeapStats},
+    objects::{Object, ObjectError, ObjectHeap, Value},
+    policy::{Violation, VmPolicy},
+    recorder::Recorder,
+};
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum VMError {
+    /// An instruction referenced a register the VM does not have
+    InvalidRegister { register: u8, pc: usize },
+    /// A signed overflow occured while running in `ArithmeticMode::Trap`
+    ArithmeticOverflow { pc: usize },
+    /// A DIV instruction had a zero divisor
+    DivideByZero { pc: usize },
+    /// The program ends in the middle of an instruction
+    TruncatedInstruction { pc: usize },
+    /// A process management or messaging instruction ran without a scheduler
+    /// to handle it
+    NoScheduler { pc: usize },
+    /// An instruction accessed bytes outside of the heap
+    InvalidHeapAccess { address: i32, len: i32, pc: usize },
+    /// The program holds a byte that is not an opcode where an instruction
+    /// should start
+    IllegalOpcode { opcode: u8, pc: usize },
+    /// An object instruction was given a register that holds no reference
+    NotAnObject { register: u8, pc: usize },
+    /// An object was accessed past its end
+    IndexOutOfBounds { index: i32, pc: usize },
+    /// A reference was stored in a string
+    TypeMismatch { pc: usize },
+    /// An object was allocated with a negative length or record type
+    InvalidAllocation { pc: usize },
+    /// POP ran on an empty stack
+    StackUnderflow { pc: usize },
+    /// FREE was given an address that is not the start of an allocated block
+    InvalidFree { address: i32, pc: usize },
+    /// The instruction would break the policy of the VM
+    PolicyViolation { violation: Violation, pc: usize },
+    /// SYSCALL was given a number that designates no syscall
+    UnknownSyscall { number: i32, pc: usize },
+}
+
+impl VMError {
+    /// Address of the instruction that faulted
+    pub fn pc(&self) -> usize {
+        match *self {
+            VMError::InvalidRegister { pc, .. }
+            | VMError::ArithmeticOverflow { pc }
+            | VMError::DivideByZero { pc }
+            | VMError::TruncatedInstruction { pc }
+            | VMError::NoScheduler { pc }
+            | VMError::InvalidHeapAccess { pc, .. }
+            | VMError::IllegalOpcode { pc, .. }
+            | VMError::NotAnObject { pc, .. }
+            | VMError::IndexOutOfBounds { pc, .. }
+            | VMError::TypeMismatch { pc }
+            | VMError::InvalidAllocation { pc }
+            | VMError::StackUnderflow { pc }
+            | VMError::InvalidFree { pc, .. }
+            | VMError::PolicyViolation { pc, .. }
+            | VMError::UnknownSyscall { pc, .. } => pc,
+        }
+    }
+}
+
+/// Content of a message sent from one process to another
+#[derive(Debug, PartialEq, Clone)]
+pub enum Payload {
+    /// The value of a register, sent with SEND
+    Word(i32),
+    /// A copy of heap bytes, sent with SENDB
+    Buffer(Vec<u8>),
+}
+
+/// Process management asked for by an instruction, which the host of the VM
+/// (usually a `Scheduler`) carries out before it runs again
+#[derive(Debug, PartialEq, Clone)]
+pub enum ProcessRequest {
+    /// Start a new process at `pc`, writing its pid to `register`
+    Spawn { pc: usize, register: usize },
+    /// Give the rest of the time slice to the other processes
+    Yield,
+    /// Wait for the process whose pid is held in `register` to end, then
+    /// write its exit value to `register`
+    Join { register: usize },
+    /// Send a message to the process `pid`
+    Send { pid: i32, payload: Payload },
+    /// Take the oldest message of the mailbox and hand it over with `receive`.
+    /// When the mailbox is empty, wait for a message if `block` is set, or
+    /// call `mailbox_empty` otherwise.
+    Receive {
+        registers: ReceiveRegisters,
+        block: bool,
+    },
+}
+
+/// Registers a RECV or TRYRECV instruction writes the message to
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ReceiveRegisters {
+    /// Receives the word, or the heap address of the buffer
+    pub value: usize,
+    /// Receives the pid of the sender
+    pub sender: usize,
+    /// Receives the length of the buffer, or -1 for a word
+    pub len: usize,
+}
+
+/// Why `run_for` returned
+#[derive(Debug, PartialEq, Clone)]
+pub enum StopReason {
+    /// The program halted or ran past its end
+    Halted,
+    /// The instruction budget was spent
+    Preempted,
+    /// An instruction needs the host to act
+    Request(ProcessRequest),
+}
+
+/// How arithmetic instructions deal with results that do not fit in a register
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub enum ArithmeticMode {
+    /// Results wrap around, the overflow and carry flags record that it happened
+    #[default]
+    Wrapping,
+    /// A signed overflow faults the VM
+    Trap,
+}
 
 pub struct VM {
     // Array simulating hardware registers
-    pub registers: [i32; 32],
+    pub registers: [i32; REGISTER_COUNT],
     // Program counter: which byte is being executed
     pc: usize,
     // Instructions of the program
     pub program: Vec<u8>,
     // Remainder of division operation
     remainder: u32,
-    // Result of last comparison
-    equal_flag: bool,
+    // Status register, set by comparisons and arithmetic instructions
+    flags: Flags,
+    // Behaviour of arithmetic instructions on overflow
+    pub arithmetic_mode: ArithmeticMode,
     heap: Vec<u8>,
+    // Blocks of the heap handed out by ALOC
+    allocator: Allocator,
+    // Garbage collected objects
+    objects: ObjectHeap,
+    // Registers holding a reference to an object, one bit per register
+    pointers: u32,
+    // Values pushed with PUSH, which are roots of the garbage collector
+    stack: Vec<Value>,
+    // Writes out executed instructions when tracing is enabled
+    tracer: Option<Tracer>,
+    // Counts executed instructions when profiling is enabled
+    profiler: Option<Profiler>,
+    // Number of cycles each opcode takes
+    pub cost_table: CostTable,
+    // Cycles spent since the VM started
+    cycles: CycleCounter,
+    // History of executed instructions when recording is enabled
+    recorder: Option<Recorder>,
+    // Request from the last instruction, for the host to carry out
+    request: Option<ProcessRequest>,
+    // What the program may do, and the resources it may use
+    policy: VmPolicy,
+    // Output of the program, kept here instead of printed when captured
+    output: Option<Vec<u8>>,
+    // Bytes written to the output so far
+    output_written: usize,
+    // When the VM was created, for the clock syscall
+    started: Instant,
+    // Where the instructions come from, read from the debug section
+    debug_info: Option<Arc<DebugInfo>>,
 }
 
 impl VM {
     pub fn new() -> VM {
         VM {
-            registers: [0; 32],
+            registers: [0; REGISTER_COUNT],
             pc: 0,
             program: vec![],
             remainder: 0,
-            equal_flag: false,
+            flags: Flags::default(),
+            arithmetic_mode: ArithmeticMode::default(),
             heap: vec![],
+            allocator: Allocator::new(),
+            objects: ObjectHeap::new(),
+            pointers: 0,
+            stack: vec![],
+            tracer: None,
+            profiler: None,
+            cost_table: CostTable::default(),
+            cycles: CycleCounter::default(),
+            recorder: None,
+            request: None,
+            policy: VmPolicy::new(),
+            output: None,
+            output_written: 0,
+            started: Instant::now(),
+            debug_info: None,
         }
     }
 
@@ -30,118 +229,514 @@ impl VM {
         self.program.push(byte);
     }
 
-    pub fn run(&mut self) {
+    /// Runs the program until it halts. Without a scheduler, YIELD does
+    /// nothing while the other process management and messaging instructions
+    /// fault.
+    pub fn run(&mut self) -> Result<(), VMError> {
         self.verify_header();
 
         let mut is_done = false;
         while !is_done {
-            is_done = self.execute_instruction();
+            let pc = self.pc;
+            is_done = self.step()?;
+            match self.request.take() {
+                None | Some(ProcessRequest::Yield) => {}
+                Some(_) => return Err(VMError::NoScheduler { pc }),
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs at most `instructions` instructions, stopping early when the
+    /// program halts or asks its host for something
+    pub fn run_for(&mut self, instructions: usize) -> Result<StopReason, VMError> {
+        for _ in 0..instructions {
+            if self.step()? {
+                return Ok(StopReason::Halted);
+            }
+            if let Some(request) = self.request.take() {
+                return Ok(StopReason::Request(request));
+            }
+        }
+        Ok(StopReason::Preempted)
+    }
+
+    /// Takes the request left by the last instruction run with `run_once`
+    pub fn take_request(&mut self) -> Option<ProcessRequest> {
+        self.request.take()
+    }
+
+    /// Writes a received message to the registers of the RECV or TRYRECV
+    /// instruction that asked for it. A buffer is copied to the end of the
+    /// heap. Clears the zero flag, which TRYRECV sets on an empty mailbox.
+    pub fn receive(
+        &mut self,
+        sender: i32,
+        payload: Payload,
+        registers: ReceiveRegisters,
+    ) -> Result<(), VMError> {
+        let (value, len) = match payload {
+            Payload::Word(word) => (word, -1),
+            Payload::Buffer(bytes) => {
+                let address = self.allocate_heap(bytes.len(), self.pc)?;
+                self.write_heap(address, &bytes);
+                (address as i32, bytes.len() as i32)
+            }
+        };
+        self.set_register(registers.value, value);
+        self.set_register(registers.sender, sender);
+        self.set_register(registers.len, len);
+        self.flags.zero = false;
+        Ok(())
+    }
+
+    /// Tells a TRYRECV instruction that there was no message, by setting the
+    /// zero flag
+    pub fn mailbox_empty(&mut self) {
+        self.flags.zero = true;
+    }
+
+    /// Creates the VM of a process spawned by this one. It starts at `pc` with
+    /// a copy of the registers, flags and heap of this VM, and no cycles spent.
+    pub fn spawn_child(&self, pc: usize) -> VM {
+        let mut child = self.fork();
+        child.pc = pc;
+        child.cycles = CycleCounter::default();
+        child
+    }
+
+    /// Executes the next instruction, returning whether the VM halted
+    pub fn run_once(&mut self) -> Result<bool, VMError> {
+        self.step()
+    }
+
+    /// Restricts what the program may do, see `VmPolicy`
+    pub fn set_policy(&mut self, policy: VmPolicy) {
+        self.policy = policy;
+    }
+
+    pub fn policy(&self) -> &VmPolicy {
+        &self.policy
+    }
+
+    /// Keeps the output of the program in memory instead of printing it, see
+    /// `take_output`
+    pub fn capture_output(&mut self) {
+        self.output = Some(vec![]);
+    }
+
+    /// Output captured since the last call, empty if it is not captured
+    pub fn take_output(&mut self) -> Vec<u8> {
+        self.output.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    /// Writes to the output of the program, within its output quota
+    fn write_output(&mut self, bytes: &[u8], pc: usize) -> Result<(), VMError> {
+        let written = self.output_written + bytes.len();
+        if self
+            .policy
+            .output_quota
+            .is_some_and(|quota| written > quota)
+        {
+            return Err(self.violation(Violation::OutputQuota, pc));
         }
-        std::process::exit(0);
+        self.output_written = written;
+        match &mut self.output {
+            Some(output) => output.extend_from_slice(bytes),
+            None => {
+                let mut stdout = std::io::stdout();
+                let _ = stdout.write_all(bytes).and_then(|_| stdout.flush());
+            }
+        }
+        Ok(())
     }
 
-    pub fn run_once(&mut self) {
-        self.execute_instruction();
+    fn violation(&self, violation: Violation, pc: usize) -> VMError {
+        VMError::PolicyViolation { violation, pc }
+    }
+
+    /// Enables tracing of every executed instruction
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    /// Starts counting executed instructions, see `profiler`
+    pub fn enable_profiling(&mut self) {
+        self.profiler = Some(Profiler::new());
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    /// Executes one instruction, profiling, recording and tracing it if enabled
+    fn step(&mut self) -> Result<bool, VMError> {
+        if let Some(profiler) = &mut self.profiler {
+            if let Some(&opcode) = self.program.get(self.pc) {
+                profiler.record(self.pc, opcode, self.cost_table.cost(opcode));
+            }
+        }
+        if self.tracer.is_none() && self.recorder.is_none() {
+            return self.execute_instruction();
+        }
+        let start = self.step_start();
+        let result = self.execute_instruction();
+        if self.recorder.is_some() {
+            self.record_step(&start);
+        }
+        if self.tracer.is_none() {
+            return result;
+        }
+        let before = TraceState {
+            pc: start.pc,
+            registers: start.registers,
+            flags: start.flags,
+        };
+        let instruction = self.program.get(before.pc..).and_then(Instruction::decode);
+        let source = self
+            .debug_info()
+            .and_then(|info| info.source_line(before.pc));
+        if let (Some(tracer), Some(instruction)) = (&mut self.tracer, instruction) {
+            tracer.trace(
+                &before,
+                &instruction,
+                source.as_deref(),
+                &self.registers,
+                self.flags,
+            );
+        }
+        result
     }
 
-    fn verify_header(&mut self) -> bool {
-        if self.program[0..4] != PIE_HEADER_PREFIX {
+    /// Skips the PIE header if the program has one, returning whether it did.
+    /// A debug section following the code is taken out of the program and
+    /// kept for `debug_info`.
+    pub fn verify_header(&mut self) -> bool {
+        if !self.program.starts_with(&PIE_HEADER_PREFIX) {
+            log::debug!("No PIE header found, running from the first byte");
             return false;
         }
-        self.pc = 65;
+        let offset = self
+            .program
+            .get(DEBUG_OFFSET_RANGE)
+            .map_or(0, |bytes| LittleEndian::read_u32(bytes) as usize);
+        if (PIE_HEADER_LENGTH..self.program.len()).contains(&offset) {
+            self.debug_info = DebugInfo::decode(&self.program[offset..]).map(Arc::new);
+            if self.debug_info.is_none() {
+                log::debug!("Ignoring the malformed debug section at {}", offset);
+            }
+            self.program.truncate(offset);
+        }
+        self.pc = PIE_HEADER_LENGTH;
         true
     }
 
-    fn execute_instruction(&mut self) -> bool {
+    /// Debug information of the program, when it has a debug section
+    pub fn debug_info(&self) -> Option<&DebugInfo> {
+        self.debug_info.as_deref()
+    }
+
+    /// Describes the instruction at `pc`, or the one `pc` is in the middle
+    /// of, for error messages: `loop.asm:14 ADD $0 $1 $2`, or with its
+    /// address when the program has no debug information
+    pub fn describe_pc(&self, pc: usize) -> String {
+        let pc = pc - pc % INSTRUCTION_LENGTH;
+        let location = self
+            .debug_info()
+            .and_then(|info| info.source_line(pc))
+            .unwrap_or_else(|| format!("{:06x}", pc));
+        match self.program.get(pc..).and_then(Instruction::decode) {
+            Some(instruction) => format!("{} {}", location, instruction),
+            None => location,
+        }
+    }
+
+    fn execute_instruction(&mut self) -> Result<bool, VMError> {
         if self.pc >= self.program.len() {
-            return true;
+            return Ok(true);
+        }
+        let pc = self.pc;
+        if pc + INSTRUCTION_LENGTH > self.program.len() {
+            return Err(VMError::TruncatedInstruction { pc });
+        }
+        // Every instruction is INSTRUCTION_LENGTH bytes long, whatever the
+        // number of operands it reads. Jumps are the only instructions that
+        // change where the next one is fetched from.
+        let mut next_pc = pc + INSTRUCTION_LENGTH;
+        let opcode = self.program[pc];
+        let cost = self.cost_table.cost(opcode);
+        if self.policy.fuel.is_some_and(|fuel| {
+            self.cycles
+                .total()
+                .checked_add(cost)
+                .is_none_or(|total| total > fuel)
+        }) {
+            return Err(self.violation(Violation::Fuel, pc));
         }
+        self.cycles.add(opcode, cost);
         match self.decode_opcode() {
             Opcode::HLT => {
-                println!("HLT encountered");
-                return true;
+                log::info!("HLT encountered at {}", pc);
+                self.pc = next_pc;
+                return Ok(true);
             }
             Opcode::LOAD => {
-                let register = self.next_8_bits() as usize;
+                let register = self.next_register()?;
                 let number = self.next_16_bits() as usize;
-                self.registers[register] = number as i32;
+                self.set_register(register, number as i32);
             }
             Opcode::ADD => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 + register2;
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                let register = self.next_register()?;
+                let (result, flags) = Flags::add(register1, register2);
+                self.set_arithmetic_result(register, result, flags, pc)?;
             }
             Opcode::SUB => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 - register2;
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                let register = self.next_register()?;
+                let (result, flags) = Flags::sub(register1, register2);
+                self.set_arithmetic_result(register, result, flags, pc)?;
             }
             Opcode::MUL => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 * register2;
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                let register = self.next_register()?;
+                let (result, flags) = Flags::mul(register1, register2);
+                self.set_arithmetic_result(register, result, flags, pc)?;
             }
             Opcode::DIV => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 / register2;
-                self.remainder = (register1 % register2) as u32;
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                let register = self.next_register()?;
+                if register2 == 0 {
+                    return Err(VMError::DivideByZero { pc });
+                }
+                // Only i32::MIN / -1 overflows, its remainder is 0
+                let (result, overflow) = register1.overflowing_div(register2);
+                let flags = Flags::new(result, false, overflow);
+                self.set_arithmetic_result(register, result, flags, pc)?;
+                self.remainder = register1.wrapping_rem(register2) as u32;
             }
             Opcode::JMP => {
-                let target = self.registers[self.next_8_bits() as usize];
-                self.pc = target as usize;
+                let target = self.next_register_value()?;
+                next_pc = target as usize;
             }
+            // Relative jumps are counted from the start of the next instruction
             Opcode::JMPF => {
-                let value = self.registers[self.next_8_bits() as usize];
-                self.pc += value as usize;
+                let value = self.next_register_value()?;
+                next_pc = next_pc.wrapping_add(value as usize);
             }
             Opcode::JMPB => {
-                let value = self.registers[self.next_8_bits() as usize];
-                self.pc -= value as usize;
+                let value = self.next_register_value()?;
+                next_pc = next_pc.wrapping_sub(value as usize);
             }
+            // EQ compares its operands by subtracting them, without storing
+            // the result
             Opcode::EQ => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.equal_flag = register1 == register2;
-                self.next_8_bits();
-            }
-            Opcode::JEQ => {
-                let register = self.next_8_bits() as usize;
-                let target = self.registers[register] as usize;
-                if self.equal_flag {
-                    self.pc = target;
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                let (_, flags) = Flags::sub(register1, register2);
+                self.flags = flags;
+            }
+            Opcode::JEQ => self.branch(self.flags.equal(), &mut next_pc)?,
+            Opcode::JNEQ => self.branch(!self.flags.equal(), &mut next_pc)?,
+            Opcode::JGT => self.branch(self.flags.greater_than(), &mut next_pc)?,
+            Opcode::JLT => self.branch(self.flags.less_than(), &mut next_pc)?,
+            Opcode::JGE => self.branch(!self.flags.less_than(), &mut next_pc)?,
+            Opcode::JLE => self.branch(!self.flags.greater_than(), &mut next_pc)?,
+            Opcode::JA => self.branch(self.flags.above(), &mut next_pc)?,
+            Opcode::JB => self.branch(self.flags.below(), &mut next_pc)?,
+            Opcode::ALOC => {
+                let size = self.next_register_value()?;
+                let register = self.next_register()?;
+                let size = usize::try_from(size).map_err(|_| VMError::InvalidAllocation { pc })?;
+                let address = self.allocate_heap(size, pc)?;
+                self.set_register(register, address as i32);
+            }
+            Opcode::FREE => {
+                let address = self.next_register_value()?;
+                self.notify_allocator_change();
+                usize::try_from(address)
+                    .map_err(|_| AllocationError::InvalidFree)
+                    .and_then(|a| self.allocator.free(a))
+                    .map_err(|_| VMError::InvalidFree { address, pc })?;
+                self.resize_heap(self.allocator.heap_len());
+            }
+            Opcode::INC => {
+                let register = self.next_register()?;
+                let (result, mut flags) = Flags::add(self.registers[register], 1);
+                // Like on most CPUs, INC and DEC leave the carry flag untouched
+                flags.carry = self.flags.carry;
+                self.set_arithmetic_result(register, result, flags, pc)?;
+            }
+            Opcode::DEC => {
+                let register = self.next_register()?;
+                let (result, mut flags) = Flags::sub(self.registers[register], 1);
+                flags.carry = self.flags.carry;
+                self.set_arithmetic_result(register, result, flags, pc)?;
+            }
+            Opcode::JZ => self.branch(self.flags.zero, &mut next_pc)?,
+            Opcode::JN => self.branch(self.flags.negative, &mut next_pc)?,
+            Opcode::JC => self.branch(self.flags.carry, &mut next_pc)?,
+            Opcode::JO => self.branch(self.flags.overflow, &mut next_pc)?,
+            // Reads the low 32 bits of the cycle counter, which counts every
+            // instruction issued before this one
+            Opcode::CYC => {
+                let register = self.next_register()?;
+                let cycles = self
+                    .cycles
+                    .total()
+                    .saturating_sub(self.cost_table.cost(opcode));
+                self.set_register(register, cycles as i32);
+            }
+            Opcode::SPAWN => {
+                let pc = self.next_register_value()? as usize;
+                let register = self.next_register()?;
+                self.request = Some(ProcessRequest::Spawn { pc, register });
+            }
+            Opcode::YIELD => {
+                self.request = Some(ProcessRequest::Yield);
+            }
+            Opcode::JOIN => {
+                let register = self.next_register()?;
+                self.request = Some(ProcessRequest::Join { register });
+            }
+            Opcode::SEND => {
+                let pid = self.next_register_value()?;
+                let word = self.next_register_value()?;
+                self.request = Some(ProcessRequest::Send {
+                    pid,
+                    payload: Payload::Word(word),
+                });
+            }
+            Opcode::SENDB => {
+                let pid = self.next_register_value()?;
+                let address = self.next_register_value()?;
+                let len = self.next_register_value()?;
+                let bytes = usize::try_from(address)
+                    .ok()
+                    .zip(usize::try_from(len).ok())
+                    .and_then(|(address, len)| self.heap.get(address..address.checked_add(len)?))
+                    .ok_or(VMError::InvalidHeapAccess { address, len, pc })?;
+                self.request = Some(ProcessRequest::Send {
+                    pid,
+                    payload: Payload::Buffer(bytes.to_vec()),
+                });
+            }
+            Opcode::RECV | Opcode::TRYRECV => {
+                let registers = ReceiveRegisters {
+                    value: self.next_register()?,
+                    sender: self.next_register()?,
+                    len: self.next_register()?,
+                };
+                self.request = Some(ProcessRequest::Receive {
+                    registers,
+                    block: opcode == Opcode::RECV as u8,
+                });
+            }
+            Opcode::NEWARR | Opcode::NEWSTR => {
+                let len = self.next_register_value()?;
+                let register = self.next_register()?;
+                let len = usize::try_from(len).map_err(|_| VMError::InvalidAllocation { pc })?;
+                let slot = if opcode == Opcode::NEWARR as u8 {
+                    self.check_object_size(Object::value_bytes(len), pc)?;
+                    self.allocate(Object::Array(vec![Value::default(); len]))
                 } else {
-                    self.next_16_bits();
+                    self.check_object_size(len, pc)?;
+                    self.allocate(Object::String(vec![0; len]))
+                };
+                self.set_value(register, Value::Ref(slot));
+            }
+            Opcode::NEWREC => {
+                let record_type = self.next_register_value()?;
+                let len = self.next_register_value()?;
+                let register = self.next_register()?;
+                let len = usize::try_from(len)
+                    .ok()
+                    .filter(|_| record_type >= 0)
+                    .ok_or(VMError::InvalidAllocation { pc })?;
+                self.check_object_size(Object::value_bytes(len), pc)?;
+                let slot = self.allocate(Object::Record {
+                    record_type,
+                    fields: vec![Value::default(); len],
+                });
+                self.set_value(register, Value::Ref(slot));
+            }
+            Opcode::GET => {
+                let slot = self.next_object_slot(pc)?;
+                let index = self.next_register_value()?;
+                let register = self.next_register()?;
+                let value = usize::try_from(index)
+                    .ok()
+                    .and_then(|i| self.object(slot).get(i))
+                    .ok_or(VMError::IndexOutOfBounds { index, pc })?;
+                self.set_value(register, value);
+            }
+            Opcode::SET => {
+                let slot = self.next_object_slot(pc)?;
+                let index = self.next_register_value()?;
+                let register = self.next_register()?;
+                let value = self.register_value(register);
+                let result = usize::try_from(index)
+                    .map_err(|_| ObjectError::OutOfBounds)
+                    .and_then(|i| self.objects.set(slot, i, value));
+                match result {
+                    Ok(()) => {}
+                    Err(ObjectError::TypeMismatch) => return Err(VMError::TypeMismatch { pc }),
+                    Err(_) => return Err(VMError::IndexOutOfBounds { index, pc }),
                 }
             }
-            Opcode::JNEQ => {
-                let register = self.next_8_bits() as usize;
-                let target = self.registers[register] as usize;
-                if self.equal_flag {
-                    self.next_16_bits();
+            Opcode::LEN | Opcode::TYPE => {
+                let slot = self.next_object_slot(pc)?;
+                let object = self.object(slot);
+                let value = if opcode == Opcode::LEN as u8 {
+                    object.len() as i32
                 } else {
-                    self.pc = target;
+                    object.object_type()
+                };
+                let register = self.next_register()?;
+                self.set_register(register, value);
+            }
+            Opcode::MOV => {
+                let source = self.next_register()?;
+                let register = self.next_register()?;
+                self.set_value(register, self.register_value(source));
+            }
+            Opcode::PUSH => {
+                let register = self.next_register()?;
+                if self
+                    .policy
+                    .max_stack_depth
+                    .is_some_and(|depth| self.stack.len() >= depth)
+                {
+                    return Err(self.violation(Violation::MaxStackDepth, pc));
                 }
+                self.stack.push(self.register_value(register));
             }
-            Opcode::ALOC => {
-                let register = self.next_8_bits() as usize;
-                let bytes = self.registers[register];
-                let new_end = self.heap.len() as i32 + bytes;
-                self.heap.resize(new_end as usize, 0);
+            Opcode::POP => {
+                let register = self.next_register()?;
+                let value = self.stack.pop().ok_or(VMError::StackUnderflow { pc })?;
+                self.set_value(register, value);
             }
-            Opcode::INC => {
-                let register = self.next_8_bits() as usize;
-                self.registers[register] += 1;
+            Opcode::GC => {
+                self.collect_garbage();
             }
-            Opcode::DEC => {
-                let register = self.next_8_bits() as usize;
-                self.registers[register] -= 1;
+            Opcode::SYSCALL => {
+                let number = self.next_register_value()?;
+                let register = self.next_register()?;
+                let argument = self.register_value(register);
+                let register = self.next_register()?;
+                let result = self.syscall(number, argument, pc)?;
+                self.set_register(register, result);
             }
-            _ => {
-                panic!("Unrecognized opcode found! Terminating!");
+            Opcode::IGL => {
+                return Err(VMError::IllegalOpcode { opcode, pc });
             }
         }
-        false
+        self.pc = next_pc;
+        Ok(false)
     }
 
     fn decode_opcode(&mut self) -> Opcode {
@@ -156,12 +751,230 @@ impl VM {
         result
     }
 
+    /// Reads a register operand, faulting if it does not designate a register
+    fn next_register(&mut self) -> Result<usize, VMError> {
+        let pc = self.pc;
+        let register = self.next_8_bits();
+        if register::is_valid(register) {
+            Ok(register as usize)
+        } else {
+            Err(VMError::InvalidRegister { register, pc })
+        }
+    }
+
+    /// Reads a register operand and returns the value it holds
+    fn next_register_value(&mut self) -> Result<i32, VMError> {
+        let register = self.next_register()?;
+        Ok(self.registers[register])
+    }
+
+    /// Records the flags of an arithmetic instruction and stores its result,
+    /// unless it overflowed while trapping on overflow
+    fn set_arithmetic_result(
+        &mut self,
+        register: usize,
+        result: i32,
+        flags: Flags,
+        pc: usize,
+    ) -> Result<(), VMError> {
+        self.flags = flags;
+        if flags.overflow && self.arithmetic_mode == ArithmeticMode::Trap {
+            return Err(VMError::ArithmeticOverflow { pc });
+        }
+        self.set_register(register, result);
+        Ok(())
+    }
+
+    /// Reads the jump target held in the register operand, and jumps to it
+    /// if `condition` holds
+    fn branch(&mut self, condition: bool, next_pc: &mut usize) -> Result<(), VMError> {
+        let target = self.next_register_value()? as usize;
+        if condition {
+            *next_pc = target;
+        }
+        Ok(())
+    }
+
+    pub fn heap(&self) -> &[u8] {
+        &self.heap
+    }
+
+    pub fn heap_stats(&self) -> HeapStats {
+        self.allocator.stats()
+    }
+
+    /// Allocates a zeroed block of the heap, returning its address
+    fn allocate_heap(&mut self, size: usize, pc: usize) -> Result<usize, VMError> {
+        self.notify_allocator_change();
+        // Objects take their share of the maximum heap size
+        self.allocator.max_size = self
+            .policy
+            .max_heap
+            .map(|max| max.saturating_sub(self.objects.bytes()));
+        let address = self
+            .allocator
+            .allocate(size)
+            .map_err(|_| self.violation(Violation::MaxHeap { requested: size }, pc))?;
+        let old_len = self.heap.len();
+        self.resize_heap(self.allocator.heap_len());
+        // Bytes past the old end are zeroed by the resize, the others may be
+        // left over from a freed block
+        let reused = address..(address + size).min(old_len);
+        if !reused.is_empty() {
+            self.write_heap(address, &vec![0; reused.len()]);
+        }
+        Ok(address)
+    }
+
+    /// Writes bytes to the heap, which must be long enough to hold them
+    fn write_heap(&mut self, address: usize, bytes: &[u8]) {
+        if let Some(recorder) = &mut self.recorder {
+            for (offset, old) in self.heap[address..address + bytes.len()].iter().enumerate() {
+                recorder.heap_written(address + offset, *old);
+            }
+        }
+        self.heap[address..address + bytes.len()].copy_from_slice(bytes);
+    }
+
+    fn notify_allocator_change(&mut self) {
+        if let Some(recorder) = &mut self.recorder {
+            recorder.allocator_changed(&self.allocator);
+        }
+    }
+
+    fn resize_heap(&mut self, new_len: usize) {
+        if let Some(recorder) = &mut self.recorder {
+            recorder.heap_resized(&self.heap, new_len);
+        }
+        self.heap.resize(new_len, 0);
+    }
+
+    /// Writes an integer to a register, discarding writes to the hardwired
+    /// zero register
+    pub fn set_register(&mut self, register: usize, value: i32) {
+        self.set_value(register, Value::Int(value));
+    }
+
+    /// Writes a value to a register, tagging it as a pointer if it is a
+    /// reference
+    pub fn set_value(&mut self, register: usize, value: Value) {
+        if register == register::ZERO as usize {
+            return;
+        }
+        self.registers[register] = value.bits();
+        match value {
+            Value::Int(_) => self.pointers &= !(1 << register),
+            Value::Ref(_) => self.pointers |= 1 << register,
+        }
+    }
+
+    /// Value of a register, a reference if it is tagged as a pointer
+    pub fn register_value(&self, register: usize) -> Value {
+        if self.is_pointer(register) {
+            Value::Ref(self.registers[register] as u32)
+        } else {
+            Value::Int(self.registers[register])
+        }
+    }
+
+    /// Whether a register holds a reference to an object
+    pub fn is_pointer(&self, register: usize) -> bool {
+        self.pointers & (1 << register) != 0
+    }
+
+    pub fn objects(&self) -> &ObjectHeap {
+        &self.objects
+    }
+
+    pub fn stack(&self) -> &[Value] {
+        &self.stack
+    }
+
+    /// Frees the objects that are not reachable from the registers tagged as
+    /// pointers or from the stack, returning how many were freed
+    pub fn collect_garbage(&mut self) -> usize {
+        let registers = (0..REGISTER_COUNT)
+            .filter(|&r| self.is_pointer(r))
+            .map(|r| self.registers[r] as u32);
+        let stack = self.stack.iter().filter_map(|value| match value {
+            Value::Ref(slot) => Some(*slot),
+            Value::Int(_) => None,
+        });
+        let roots: Vec<u32> = registers.chain(stack).collect();
+        let freed = self.objects.collect(roots);
+        log::debug!("Collected {} objects, {} live", freed, self.objects.live());
+        freed
+    }
+
+    /// Faults if an object of `bytes` would not fit in the maximum heap size,
+    /// even after collecting the unreachable objects
+    fn check_object_size(&mut self, bytes: usize, pc: usize) -> Result<(), VMError> {
+        let Some(max) = self.policy.max_heap else {
+            return Ok(());
+        };
+        let fits = |vm: &VM| (vm.heap.len() + vm.objects.bytes()).saturating_add(bytes) <= max;
+        if !fits(self) {
+            self.collect_garbage();
+            if !fits(self) {
+                return Err(self.violation(Violation::MaxHeap { requested: bytes }, pc));
+            }
+        }
+        Ok(())
+    }
+
+    /// Allocates an object, collecting garbage first when enough objects
+    /// were allocated since the last collection
+    fn allocate(&mut self, object: Object) -> u32 {
+        if self.objects.needs_collection() {
+            self.collect_garbage();
+        }
+        self.objects.allocate(object)
+    }
+
+    /// Reads a register operand that must hold a reference, returning the slot
+    /// of the object. `pc` is the address of the instruction.
+    fn next_object_slot(&mut self, pc: usize) -> Result<u32, VMError> {
+        let register = self.next_register()?;
+        let slot = self.registers[register] as u32;
+        if !self.is_pointer(register) || self.objects.get(slot).is_none() {
+            return Err(VMError::NotAnObject {
+                register: register as u8,
+                pc,
+            });
+        }
+        Ok(slot)
+    }
+
+    /// Object in a slot returned by `next_object_slot`
+    fn object(&self, slot: u32) -> &Object {
+        self.objects
+            .get(slot)
+            .expect("The slot holds a live object")
+    }
+
     fn next_16_bits(&mut self) -> u16 {
         let result = ((self.program[self.pc] as u16) << 8) | self.program[self.pc + 1] as u16;
         self.pc += 2;
         result
     }
 
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// Moves execution to `pc`, such as the start of code added by the REPL
+    pub fn set_pc(&mut self, pc: usize) {
+        self.pc = pc;
+    }
+
+    pub fn cycles(&self) -> &CycleCounter {
+        &self.cycles
+    }
+
+    pub fn flags(&self) -> Flags {
+        self.flags
+    }
+
     pub fn add_bytes(&mut self, mut bytes: Vec<u8>) {
         self.program.append(&mut bytes);
     }
@@ -175,14 +988,14 @@ impl Default for VM {
 
 #[cfg(test)]
 pub mod tests {
-    use crate::assembler::PIE_HEADER_LENGTH;
-
     use super::*;
+    use crate::assembler::Assembler;
+    use syscall::Syscall;
 
     fn prepend_header(mut b: Vec<u8>) -> Vec<u8> {
         let mut header = Vec::with_capacity(PIE_HEADER_LENGTH);
         header.append(&mut PIE_HEADER_PREFIX.to_vec());
-        header.fill(0);
+        header.resize(PIE_HEADER_LENGTH, 0);
         header.append(&mut b);
         header
     }
@@ -199,18 +1012,20 @@ pub mod tests {
         prepend_header(test_vm.program);
         let test_bytes = vec![11, 0, 0, 0];
         test_vm.program = test_bytes;
-        test_vm.run();
-        assert_eq!(test_vm.pc, 1);
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.pc, 4);
     }
 
     #[test]
-    #[should_panic]
     fn test_opcode_igl() {
         let mut test_vm = VM::new();
         prepend_header(test_vm.program);
         let test_bytes = vec![200, 0, 0, 0];
         test_vm.program = test_bytes;
-        test_vm.run();
+        assert_eq!(
+            test_vm.run(),
+            Err(VMError::IllegalOpcode { opcode: 200, pc: 0 })
+        );
     }
 
     #[test]
@@ -218,7 +1033,7 @@ pub mod tests {
         let mut test_vm = VM::new();
         prepend_header(test_vm.program);
         test_vm.program = vec![0, 0, 1, 244]; // 500 en binaire u16 little endian
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[0], 500);
     }
 
@@ -232,7 +1047,7 @@ pub mod tests {
         ];
         prepend_header(test_vm.program);
         test_vm.program = test_bytes;
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[2], 25);
     }
 
@@ -245,9 +1060,9 @@ pub mod tests {
             2, 0, 1, 2, // SUB $0 $1 $2 : Soustraire reg 0 et 1 dans reg 2
         ];
         test_vm.program = test_bytes;
-        test_vm.run_once();
-        test_vm.run_once();
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.registers[2], 5);
     }
 
@@ -260,9 +1075,9 @@ pub mod tests {
             3, 0, 1, 2, // MUL $0 $1 $2 : Multplier reg 0 et 1 dans reg 2
         ];
         test_vm.program = test_bytes;
-        test_vm.run_once();
-        test_vm.run_once();
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.registers[2], 150);
     }
 
@@ -275,9 +1090,9 @@ pub mod tests {
             4, 0, 1, 2, // DIV $0 $1 $2 : Diviser reg 0 et 1 dans reg 2
         ];
         test_vm.program = test_bytes;
-        test_vm.run_once();
-        test_vm.run_once();
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.registers[2], 1);
         assert_eq!(test_vm.remainder, 5);
     }
@@ -287,7 +1102,7 @@ pub mod tests {
         let mut test_vm = VM::new();
         test_vm.registers[0] = 1;
         test_vm.program = vec![5, 0, 0, 0]; // JMP $0 : Saut vers pc = valeur reg 0, donc 1
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.pc, 1);
     }
 
@@ -296,8 +1111,8 @@ pub mod tests {
         let mut test_vm = VM::new();
         test_vm.registers[0] = 2;
         test_vm.program = vec![6, 0, 0, 0];
-        test_vm.run_once();
-        assert_eq!(test_vm.pc, 4);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 6);
     }
 
     #[test]
@@ -307,14 +1122,14 @@ pub mod tests {
             0, 0, 0, 10, // LOAD $0 #10 : Charger 10 dans reg 0
             0, 1, 0, 15, // LOAD $1 #15 : Charger 15 dans reg 1
             3, 0, 1, 2, // MUL $0 $1 $2 : Multiplier reg 0 et 1 dans reg 2
-            0, 3, 0, 18, // LOAD $3 #18
+            0, 3, 0, 20, // LOAD $3 #20
             7, 3, 0, 0, // JMPB $3
         ];
-        test_vm.run_once();
-        test_vm.run_once();
-        test_vm.run_once();
-        test_vm.run_once();
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.pc, 0);
     }
 
@@ -327,21 +1142,21 @@ pub mod tests {
             8, 0, 1, 0, // EQ $0 $1 : reg 0 est-il égal reg 1
             8, 0, 1, 0, // EQ $0 $1 : reg 0 est-il égal reg 1
         ];
-        test_vm.run_once();
-        assert!(test_vm.equal_flag);
+        test_vm.run_once().unwrap();
+        assert!(test_vm.flags.zero);
 
         test_vm.registers[1] = 20;
-        test_vm.run_once();
-        assert!(!test_vm.equal_flag);
+        test_vm.run_once().unwrap();
+        assert!(!test_vm.flags.zero);
     }
 
     #[test]
     fn test_jeq_opcode() {
         let mut test_vm = VM::new();
         test_vm.registers[0] = 2;
-        test_vm.equal_flag = true;
+        test_vm.flags.zero = true;
         test_vm.program = vec![9, 0, 0, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.pc, 2);
     }
 
@@ -349,9 +1164,9 @@ pub mod tests {
     fn test_jneq_opcode() {
         let mut test_vm = VM::new();
         test_vm.registers[0] = 2;
-        test_vm.equal_flag = false;
+        test_vm.flags.zero = false;
         test_vm.program = vec![10, 0, 0, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.pc, 2);
     }
 
@@ -359,9 +1174,103 @@ pub mod tests {
     fn test_aloc_opcode() {
         let mut test_vm = VM::default();
         test_vm.registers[0] = 1024;
-        test_vm.program = vec![12, 0, 0, 0];
-        test_vm.run_once();
-        assert_eq!(test_vm.heap.len(), 1024);
+        test_vm.program = vec![
+            12, 0, 1, 0, // ALOC $0 $1
+            12, 0, 2, 0, // ALOC $0 $2
+        ];
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.heap.len(), 2048);
+        assert_eq!(test_vm.registers[1], 0);
+        assert_eq!(test_vm.registers[2], 1024);
+    }
+
+    #[test]
+    fn test_free_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 16;
+        test_vm.program = vec![
+            12, 0, 1, 0, // ALOC $0 $1
+            12, 0, 2, 0, // ALOC $0 $2
+            44, 1, 0, 0, // FREE $1
+            12, 0, 3, 0, // ALOC $0 $3
+            44, 3, 0, 0, // FREE $3
+            44, 2, 0, 0, // FREE $2
+            44, 2, 0, 0, // FREE $2
+        ];
+        for _ in 0..3 {
+            test_vm.run_once().unwrap();
+        }
+        test_vm.heap[5] = 7;
+        test_vm.run_once().unwrap();
+        // The freed block is reused, and zeroed
+        assert_eq!(test_vm.registers[3], 0);
+        assert_eq!(test_vm.heap[5], 0);
+        assert_eq!(test_vm.heap_stats().allocated_bytes, 32);
+
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        assert!(test_vm.heap.is_empty());
+        assert_eq!(test_vm.heap_stats().peak, 32);
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VMError::InvalidFree {
+                address: 16,
+                pc: 24
+            })
+        );
+    }
+
+    #[test]
+    fn test_max_heap() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 100;
+        test_vm.registers[2] = 40;
+        test_vm.set_policy(VmPolicy {
+            max_heap: Some(150),
+            ..VmPolicy::new()
+        });
+        test_vm.program = vec![
+            12, 0, 1, 0, // ALOC $0 $1
+            12, 0, 1, 0, // ALOC $0 $1
+            34, 2, 3, 0, // NEWSTR $2 $3
+            34, 2, 3, 0, // NEWSTR $2 $3
+            12, 2, 1, 0, // ALOC $2 $1
+        ];
+        test_vm.run_once().unwrap();
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VMError::PolicyViolation {
+                violation: Violation::MaxHeap { requested: 100 },
+                pc: 4
+            })
+        );
+        assert_eq!(test_vm.heap.len(), 100);
+
+        // Objects take their share of the maximum heap size
+        test_vm.pc = 8;
+        test_vm.run_once().unwrap();
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VMError::PolicyViolation {
+                violation: Violation::MaxHeap { requested: 40 },
+                pc: 12
+            })
+        );
+        test_vm.pc = 16;
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VMError::PolicyViolation {
+                violation: Violation::MaxHeap { requested: 40 },
+                pc: 16
+            })
+        );
+
+        // Unreachable objects are collected to make room for a new one
+        test_vm.set_value(3, Value::Int(0));
+        test_vm.pc = 12;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.objects().live(), 1);
     }
 
     #[test]
@@ -369,7 +1278,7 @@ pub mod tests {
         let mut test_vm = VM::default();
         test_vm.registers[0] = 1;
         test_vm.program = vec![13, 0, 0, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.registers[0], 2);
     }
 
@@ -378,7 +1287,499 @@ pub mod tests {
         let mut test_vm = VM::default();
         test_vm.registers[0] = 1;
         test_vm.program = vec![14, 0, 0, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[0], 0);
+    }
+
+    #[test]
+    fn test_invalid_register_faults() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![0, 32, 0, 10]; // LOAD $32 #10
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VMError::InvalidRegister {
+                register: 32,
+                pc: 1
+            })
+        );
+
+        let mut test_vm = VM::new();
+        test_vm.program = vec![1, 0, 255, 2]; // ADD $0 $255 $2
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VMError::InvalidRegister {
+                register: 255,
+                pc: 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_debug_info() {
+        let mut asm = Assembler::new();
+        asm.emit_debug_info("loop.asm");
+        let program = asm
+            .assemble(".code\nLOAD $0 #1\nloop: DIV $0 $1 $2\nHLT\n")
+            .unwrap();
+        let mut test_vm = VM::new();
+        test_vm.add_bytes(program);
+        assert!(test_vm.verify_header());
+        assert_eq!(test_vm.program.len(), PIE_HEADER_LENGTH + 12);
+        assert!(test_vm.verify_header());
+        let info = test_vm.debug_info().unwrap();
+        assert_eq!(info.symbols.symbol_value("loop"), Some(68));
+
+        let error = test_vm.run().unwrap_err();
+        assert_eq!(error, VMError::DivideByZero { pc: 68 });
+        assert_eq!(test_vm.describe_pc(error.pc()), "loop.asm:3 DIV $0 $1 $2");
+        assert_eq!(test_vm.describe_pc(70), "loop.asm:3 DIV $0 $1 $2");
+
+        let mut test_vm = VM::new();
+        test_vm.program = vec![1, 0, 1, 2];
+        assert_eq!(test_vm.describe_pc(0), "000000 ADD $0 $1 $2");
+    }
+
+    #[test]
+    fn test_zero_register_discards_writes() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![
+            0, 31, 0, 10, // LOAD $zero #10
+            1, 31, 31, 0, // ADD $zero $zero $0
+        ];
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[31], 0);
+        assert_eq!(test_vm.registers[0], 0);
+    }
+
+    #[test]
+    fn test_add_wraps_and_sets_flags() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = i32::MAX;
+        test_vm.registers[1] = 1;
+        test_vm.program = vec![1, 0, 1, 2]; // ADD $0 $1 $2
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], i32::MIN);
+        assert!(test_vm.flags.overflow);
+        assert!(test_vm.flags.negative);
+        assert!(!test_vm.flags.carry);
+        assert!(!test_vm.flags.zero);
+    }
+
+    #[test]
+    fn test_trap_on_overflow() {
+        let mut test_vm = VM::new();
+        test_vm.arithmetic_mode = ArithmeticMode::Trap;
+        test_vm.registers[0] = i32::MIN;
+        test_vm.registers[1] = 1;
+        test_vm.program = vec![
+            1, 0, 1, 2, // ADD $0 $1 $2 : no overflow
+            2, 0, 1, 2, // SUB $0 $1 $2 : overflows
+        ];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], i32::MIN + 1);
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VMError::ArithmeticOverflow { pc: 4 })
+        );
+        assert_eq!(test_vm.registers[2], i32::MIN + 1);
+    }
+
+    #[test]
+    fn test_divide_by_zero_faults() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 10;
+        test_vm.program = vec![4, 0, 1, 2]; // DIV $0 $1 $2
+        assert_eq!(test_vm.run_once(), Err(VMError::DivideByZero { pc: 0 }));
+    }
+
+    #[test]
+    fn test_div_overflow_wraps() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = i32::MIN;
+        test_vm.registers[1] = -1;
+        test_vm.program = vec![4, 0, 1, 2]; // DIV $0 $1 $2
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], i32::MIN);
+        assert_eq!(test_vm.remainder, 0);
+        assert!(test_vm.flags.overflow);
+    }
+
+    #[test]
+    fn test_inc_keeps_carry() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = -1;
+        test_vm.flags.carry = true;
+        test_vm.program = vec![13, 0, 0, 0]; // INC $0
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.registers[0], 0);
+        assert!(test_vm.flags.zero);
+        assert!(test_vm.flags.carry);
+    }
+
+    #[test]
+    fn test_flag_jumps() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 100;
+        test_vm.flags.carry = true;
+        test_vm.program = vec![
+            15, 0, 0, 0, // JZ $0 : not taken
+            17, 0, 0, 0, // JC $0 : taken
+        ];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 4);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 100);
+    }
+
+    #[test]
+    fn test_jeq_not_taken_skips_whole_instruction() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 100;
+        test_vm.program = vec![
+            9, 0, 0, 0, // JEQ $0 : not taken
+            10, 0, 0, 0, // JNEQ $0 : not taken
+        ];
+        test_vm.flags.zero = false;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 4);
+        test_vm.flags.zero = true;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 8);
+    }
+
+    #[test]
+    fn test_signed_and_unsigned_comparisons() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = -1;
+        test_vm.registers[1] = 1;
+        test_vm.registers[2] = 100;
+        test_vm.program = vec![
+            8, 0, 1, 0, // EQ $0 $1 : -1 is less than 1, but 0xFFFFFFFF is above 1
+            20, 2, 0, 0, // JLT $2 : taken
+        ];
+        test_vm.run_once().unwrap();
+        assert!(test_vm.flags.less_than());
+        assert!(!test_vm.flags.greater_than());
+        assert!(test_vm.flags.above());
+        assert!(!test_vm.flags.below());
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 100);
+    }
+
+    #[test]
+    fn test_truncated_instruction_faults() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![0, 0, 1];
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VMError::TruncatedInstruction { pc: 0 })
+        );
+    }
+
+    #[test]
+    fn test_profiling() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 3;
+        test_vm.program = vec![
+            14, 0, 0, 0, // DEC $0
+            15, 1, 0, 0, // JZ $1 : $1 holds 12, the address of HLT
+            5, 2, 0, 0, // JMP $2 : $2 holds 0
+            11, 0, 0, 0, // HLT
+        ];
+        test_vm.registers[1] = 12;
+        assert!(test_vm.profiler().is_none());
+        test_vm.enable_profiling();
+        test_vm.run().unwrap();
+        let profiler = test_vm.profiler().unwrap();
+        assert_eq!(profiler.total(), 9);
+        assert_eq!(profiler.pc_counts()[0], (0, 3));
+    }
+
+    #[test]
+    fn test_cycle_counter() {
+        let mut test_vm = VM::new();
+        test_vm.cost_table.set(Opcode::ADD, 2);
+        test_vm.program = vec![
+            1, 0, 1, 2, // ADD $0 $1 $2 : 2 cycles
+            3, 0, 1, 2, // MUL $0 $1 $2 : 3 cycles
+            25, 5, 0, 0, // CYC $5
+            11, 0, 0, 0, // HLT
+        ];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[5], 5);
+        assert_eq!(test_vm.cycles().total(), 7);
+    }
+
+    #[test]
+    fn test_spawn_without_scheduler_faults() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![
+            27, 0, 0, 0, // YIELD : ignored
+            26, 0, 1, 0, // SPAWN $0 $1
+        ];
+        assert_eq!(test_vm.run(), Err(VMError::NoScheduler { pc: 4 }));
+    }
+
+    #[test]
+    fn test_run_for() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 12;
+        test_vm.program = vec![
+            13, 1, 0, 0, // INC $1
+            13, 1, 0, 0, // INC $1
+            26, 0, 2, 0, // SPAWN $0 $2
+            11, 0, 0, 0, // HLT
+        ];
+        assert_eq!(test_vm.run_for(1), Ok(StopReason::Preempted));
+        assert_eq!(
+            test_vm.run_for(10),
+            Ok(StopReason::Request(ProcessRequest::Spawn {
+                pc: 12,
+                register: 2
+            }))
+        );
+        assert_eq!(test_vm.registers[1], 2);
+        let child = test_vm.spawn_child(12);
+        assert_eq!(child.pc(), 12);
+        assert_eq!(child.registers[1], 2);
+        assert_eq!(test_vm.run_for(10), Ok(StopReason::Halted));
+    }
+
+    #[test]
+    fn test_send_buffer_outside_of_heap() {
+        let mut test_vm = VM::new();
+        test_vm.registers[1] = 2;
+        test_vm.registers[2] = 4;
+        test_vm.heap = vec![0; 4];
+        test_vm.program = vec![30, 0, 1, 2]; // SENDB $0 $1 $2
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VMError::InvalidHeapAccess {
+                address: 2,
+                len: 4,
+                pc: 0
+            })
+        );
+        test_vm.pc = 0;
+        test_vm.registers[2] = 2;
+        assert_eq!(test_vm.run_once(), Ok(false));
+        assert_eq!(
+            test_vm.take_request(),
+            Some(ProcessRequest::Send {
+                pid: 0,
+                payload: Payload::Buffer(vec![0, 0])
+            })
+        );
+    }
+
+    #[test]
+    fn test_fuel_limit() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![
+            13, 0, 0, 0, // INC $0
+            5, 31, 0, 0, // JMP $zero
+        ];
+        test_vm.set_policy(VmPolicy {
+            fuel: Some(7),
+            ..VmPolicy::new()
+        });
+        assert_eq!(
+            test_vm.run(),
+            Err(VMError::PolicyViolation {
+                violation: Violation::Fuel,
+                pc: 4
+            })
+        );
+        assert_eq!(test_vm.registers[0], 4);
+        assert_eq!(test_vm.cycles().total(), 7);
+
+        // Running out of cycles to count is running out of fuel
+        test_vm.cycles.add(Opcode::INC as u8, u64::MAX);
+        test_vm.set_policy(VmPolicy {
+            fuel: Some(u64::MAX),
+            ..VmPolicy::new()
+        });
+        assert_eq!(
+            test_vm.run(),
+            Err(VMError::PolicyViolation {
+                violation: Violation::Fuel,
+                pc: 4
+            })
+        );
+    }
+
+    #[test]
+    fn test_captured_output() {
+        let mut test_vm = VM::new();
+        test_vm.registers[1] = 42;
+        test_vm.program = vec![
+            45, 31, 1, 2, // SYSCALL $zero $1 $2
+            11, 0, 0, 0, // HLT
+        ];
+        test_vm.capture_output();
+        test_vm.set_policy(VmPolicy {
+            output_quota: Some(3),
+            ..VmPolicy::new()
+        });
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.take_output(), b"42\n");
+        assert!(test_vm.take_output().is_empty());
+    }
+
+    #[test]
+    fn test_syscalls() {
+        let mut test_vm = VM::new();
+        test_vm.registers[1] = 42;
+        test_vm.registers[2] = 1;
+        test_vm.registers[3] = 65;
+        test_vm.program = vec![
+            45, 0, 1, 4, // SYSCALL $0 $1 $4
+            45, 2, 3, 4, // SYSCALL $2 $3 $4
+            45, 5, 1, 4, // SYSCALL $5 $1 $4
+        ];
+        test_vm.capture_output();
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[4], 3);
+        test_vm.set_policy(VmPolicy::sandbox());
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VMError::PolicyViolation {
+                violation: Violation::Syscall(Syscall::PrintChar),
+                pc: 4
+            })
+        );
+        assert_eq!(test_vm.registers[4], 3);
+        assert_eq!(test_vm.take_output(), b"42\n");
+
+        test_vm.registers[5] = 99;
+        test_vm.pc = 8;
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VMError::UnknownSyscall { number: 99, pc: 8 })
+        );
+    }
+
+    #[test]
+    fn test_print_string_syscall() {
+        let mut test_vm = VM::new();
+        test_vm.registers[1] = 2;
+        test_vm.registers[2] = 2;
+        test_vm.program = vec![
+            34, 2, 3, 0, // NEWSTR $2 $3
+            0, 4, 0, 104, // LOAD $4 #104
+            37, 3, 0, 4, // SET $3 $0 $4
+            0, 4, 0, 105, // LOAD $4 #105
+            37, 3, 31, 4, // SET $3 $zero $4
+            45, 1, 3, 5, // SYSCALL $1 $3 $5
+            45, 1, 1, 5, // SYSCALL $1 $1 $5
+        ];
+        test_vm.registers[0] = 1;
+        test_vm.capture_output();
+        for _ in 0..6 {
+            test_vm.run_once().unwrap();
+        }
+        assert_eq!(test_vm.take_output(), b"ih");
+        assert_eq!(test_vm.registers[5], 2);
+        assert_eq!(test_vm.run_once(), Err(VMError::TypeMismatch { pc: 24 }));
+    }
+
+    #[test]
+    fn test_output_quota() {
+        let mut test_vm = VM::new();
+        test_vm.registers[1] = 1234;
+        test_vm.program = vec![
+            45, 0, 1, 2, // SYSCALL $0 $1 $2
+            45, 0, 1, 2, // SYSCALL $0 $1 $2
+        ];
+        test_vm.set_policy(VmPolicy {
+            output_quota: Some(8),
+            ..VmPolicy::new()
+        });
+        test_vm.capture_output();
+        test_vm.run_once().unwrap();
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VMError::PolicyViolation {
+                violation: Violation::OutputQuota,
+                pc: 4
+            })
+        );
+        assert_eq!(test_vm.take_output(), b"1234\n");
+    }
+
+    #[test]
+    fn test_max_stack_depth() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![
+            41, 0, 0, 0, // PUSH $0
+            5, 31, 0, 0, // JMP $zero
+        ];
+        test_vm.set_policy(VmPolicy {
+            max_stack_depth: Some(3),
+            ..VmPolicy::new()
+        });
+        assert_eq!(
+            test_vm.run(),
+            Err(VMError::PolicyViolation {
+                violation: Violation::MaxStackDepth,
+                pc: 0
+            })
+        );
+        assert_eq!(test_vm.stack().len(), 3);
+    }
+
+    #[test]
+    fn test_objects_are_collected() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![
+            0, 0, 3, 232, // LOAD $0 #1000
+            0, 5, 0, 8, // LOAD $5 #8
+            33, 1, 2, 0, // NEWARR $1 $2
+            14, 0, 0, 0, // DEC $0
+            19, 5, 0, 0, // JGT $5
+            11, 0, 0, 0, // HLT
+        ];
+        test_vm.run().unwrap();
+        assert!(test_vm.is_pointer(2));
+        let live = test_vm.objects().live();
+        assert!(live <= objects::INITIAL_GC_THRESHOLD);
+        assert_eq!(test_vm.collect_garbage(), live - 1);
+        assert_eq!(test_vm.objects().live(), 1);
+    }
+
+    #[test]
+    fn test_object_instructions() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 2;
+        test_vm.registers[1] = 1;
+        test_vm.registers[3] = 77;
+        test_vm.program = vec![
+            35, 1, 0, 2, // NEWREC $1 $0 $2 : type 1, 2 fields
+            37, 2, 1, 3, // SET $2 $1 $3
+            36, 2, 1, 4, // GET $2 $1 $4
+            38, 2, 5, 0, // LEN $2 $5
+            39, 2, 6, 0, // TYPE $2 $6
+            40, 2, 7, 0, // MOV $2 $7
+            36, 3, 1, 4, // GET $3 $1 $4 : $3 is not a reference
+        ];
+        for _ in 0..6 {
+            test_vm.run_once().unwrap();
+        }
+        assert_eq!(test_vm.registers[4], 77);
+        assert!(!test_vm.is_pointer(4));
+        assert_eq!(test_vm.registers[5], 2);
+        assert_eq!(test_vm.registers[6], 1);
+        assert_eq!(test_vm.register_value(7), Value::Ref(0));
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VMError::NotAnObject {
+                register: 3,
+                pc: 24
+            })
+        );
+        // Overwriting a reference with an integer clears its tag
+        test_vm.set_register(7, 5);
+        assert!(!test_vm.is_pointer(7));
     }
 }
@@ -0,0 +1,214 @@
+//! Free-list allocator managing the byte heap.
+//!
+//! ALOC takes the first free block that is large enough, growing the heap
+//! when there is none, and FREE gives a block back, merging it with its free
+//! neighbours. Free space at the end of the heap is given back by shrinking
+//! the heap.
+
+use std::collections::BTreeMap;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AllocationError {
+    /// The heap would grow past its maximum size
+    OutOfMemory,
+    /// The address is not the start of an allocated block
+    InvalidFree,
+}
+
+/// Heap usage, as shown by `.heap`
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct HeapStats {
+    /// Current length of the heap
+    pub size: usize,
+    /// Largest length the heap ever had
+    pub peak: usize,
+    pub allocated_bytes: usize,
+    pub allocated_blocks: usize,
+    pub free_bytes: usize,
+    pub free_blocks: usize,
+    pub largest_free_block: usize,
+    pub max_size: Option<usize>,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Allocator {
+    /// Allocated blocks, by address, with their size
+    allocated: BTreeMap<usize, usize>,
+    /// Free blocks inside the heap, by address, with their size. Adjacent
+    /// free blocks are always merged.
+    free: BTreeMap<usize, usize>,
+    /// Length of the heap the blocks are carved from
+    heap_len: usize,
+    peak: usize,
+    /// Length the heap may not grow past
+    pub max_size: Option<usize>,
+}
+
+impl Allocator {
+    pub fn new() -> Allocator {
+        Allocator::default()
+    }
+
+    /// Rebuilds the allocator of a heap of `heap_len` bytes from its allocated
+    /// blocks, the rest of the heap being free
+    pub fn from_blocks(
+        blocks: impl IntoIterator<Item = (usize, usize)>,
+        heap_len: usize,
+    ) -> Allocator {
+        let mut allocator = Allocator {
+            allocated: blocks.into_iter().collect(),
+            heap_len,
+            peak: heap_len,
+            ..Allocator::default()
+        };
+        let mut end = 0;
+        let gaps: Vec<(usize, usize)> = allocator
+            .allocated
+            .iter()
+            .chain(std::iter::once((&heap_len, &0)))
+            .filter_map(|(&address, &size)| {
+                let gap = (end < address).then(|| (end, address - end));
+                end = end.max(address + size);
+                gap
+            })
+            .collect();
+        allocator.free.extend(gaps);
+        allocator
+    }
+
+    /// Allocated blocks, by address, with their size
+    pub fn blocks(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.allocated
+            .iter()
+            .map(|(&address, &size)| (address, size))
+    }
+
+    /// Length the heap must have after the last allocation or free
+    pub fn heap_len(&self) -> usize {
+        self.heap_len
+    }
+
+    /// Reserves `size` bytes, at least one, and returns their address. The
+    /// caller grows the heap to `heap_len` and zeroes the block.
+    pub fn allocate(&mut self, size: usize) -> Result<usize, AllocationError> {
+        let size = size.max(1);
+        let fit = self
+            .free
+            .iter()
+            .find(|(_, &free)| free >= size)
+            .map(|(&address, &free)| (address, free));
+        let address = match fit {
+            Some((address, free)) => {
+                self.free.remove(&address);
+                if free > size {
+                    self.free.insert(address + size, free - size);
+                }
+                address
+            }
+            None => {
+                let address = self.heap_len;
+                let new_len = address
+                    .checked_add(size)
+                    .filter(|&len| self.max_size.is_none_or(|max| len <= max))
+                    .ok_or(AllocationError::OutOfMemory)?;
+                self.heap_len = new_len;
+                self.peak = self.peak.max(new_len);
+                address
+            }
+        };
+        self.allocated.insert(address, size);
+        Ok(address)
+    }
+
+    /// Gives back the block starting at `address`
+    pub fn free(&mut self, address: usize) -> Result<(), AllocationError> {
+        let mut size = self
+            .allocated
+            .remove(&address)
+            .ok_or(AllocationError::InvalidFree)?;
+        let mut address = address;
+        if let Some(next) = self.free.remove(&(address + size)) {
+            size += next;
+        }
+        if let Some((&previous, &previous_size)) = self.free.range(..address).next_back() {
+            if previous + previous_size == address {
+                self.free.remove(&previous);
+                address = previous;
+                size += previous_size;
+            }
+        }
+        if address + size == self.heap_len {
+            self.heap_len = address;
+        } else {
+            self.free.insert(address, size);
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            size: self.heap_len,
+            peak: self.peak,
+            allocated_bytes: self.allocated.values().sum(),
+            allocated_blocks: self.allocated.len(),
+            free_bytes: self.free.values().sum(),
+            free_blocks: self.free.len(),
+            largest_free_block: self.free.values().copied().max().unwrap_or(0),
+            max_size: self.max_size,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allocate_and_free() {
+        let mut allocator = Allocator::new();
+        let a = allocator.allocate(8).unwrap();
+        let b = allocator.allocate(4).unwrap();
+        let c = allocator.allocate(4).unwrap();
+        assert_eq!((a, b, c), (0, 8, 12));
+        assert_eq!(allocator.heap_len(), 16);
+
+        allocator.free(a).unwrap();
+        // First fit reuses the freed block
+        assert_eq!(allocator.allocate(2).unwrap(), 0);
+        assert_eq!(allocator.stats().free_bytes, 6);
+
+        // Freeing merges neighbours and gives the end of the heap back
+        allocator.free(b).unwrap();
+        allocator.free(c).unwrap();
+        assert_eq!(allocator.heap_len(), 2);
+        assert_eq!(allocator.stats().free_blocks, 0);
+        assert_eq!(allocator.stats().peak, 16);
+
+        assert_eq!(allocator.free(5), Err(AllocationError::InvalidFree));
+        assert_eq!(allocator.free(0), Ok(()));
+        assert_eq!(allocator.free(0), Err(AllocationError::InvalidFree));
+        assert_eq!(allocator.heap_len(), 0);
+    }
+
+    #[test]
+    fn test_max_size() {
+        let mut allocator = Allocator::new();
+        allocator.max_size = Some(10);
+        assert_eq!(allocator.allocate(6), Ok(0));
+        assert_eq!(allocator.allocate(6), Err(AllocationError::OutOfMemory));
+        assert_eq!(allocator.allocate(4), Ok(6));
+        assert_eq!(allocator.heap_len(), 10);
+    }
+
+    #[test]
+    fn test_from_blocks() {
+        let mut allocator = Allocator::new();
+        for size in [4, 4, 4, 4] {
+            allocator.allocate(size).unwrap();
+        }
+        allocator.free(4).unwrap();
+        let rebuilt = Allocator::from_blocks(allocator.blocks(), allocator.heap_len());
+        assert_eq!(rebuilt.allocated, allocator.allocated);
+        assert_eq!(rebuilt.free, allocator.free);
+    }
+}
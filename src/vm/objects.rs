@@ -0,0 +1,410 @@
+//! Garbage collected object heap, next to the raw byte heap of ALOC.
+//!
+//! Objects are arrays and records of values, and strings of bytes. A
+//! reference to an object is the index of its slot, held in a register or a
+//! field tagged as a pointer. Objects that cannot be reached from the tagged
+//! registers or the stack are reclaimed by a mark-sweep collector, which runs
+//! when the number of live objects doubles, or on a GC instruction.
+
+use std::fmt;
+
+/// Live objects below which the collector never runs on its own
+pub const INITIAL_GC_THRESHOLD: usize = 64;
+
+/// Type reported by TYPE for arrays; records report their own type, which
+/// cannot be negative
+pub const ARRAY_TYPE: i32 = -1;
+/// Type reported by TYPE for strings
+pub const STRING_TYPE: i32 = -2;
+
+/// A register, stack entry or field: an integer or a reference to an object
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Value {
+    Int(i32),
+    Ref(u32),
+}
+
+impl Value {
+    /// The raw bits of the value, as stored in a register
+    pub fn bits(&self) -> i32 {
+        match *self {
+            Value::Int(value) => value,
+            Value::Ref(slot) => slot as i32,
+        }
+    }
+}
+
+impl Default for Value {
+    fn default() -> Self {
+        Value::Int(0)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(value) => write!(f, "{}", value),
+            Value::Ref(slot) => write!(f, "&{}", slot),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Object {
+    Array(Vec<Value>),
+    String(Vec<u8>),
+    Record {
+        record_type: i32,
+        fields: Vec<Value>,
+    },
+}
+
+impl Object {
+    /// Bytes taken by `len` values of an array or record
+    pub fn value_bytes(len: usize) -> usize {
+        len.saturating_mul(std::mem::size_of::<Value>())
+    }
+
+    /// Bytes taken by the elements of the object
+    pub fn bytes(&self) -> usize {
+        match self {
+            Object::Array(values) | Object::Record { fields: values, .. } => {
+                Object::value_bytes(values.len())
+            }
+            Object::String(bytes) => bytes.len(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Object::Array(values) | Object::Record { fields: values, .. } => values.len(),
+            Object::String(bytes) => bytes.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn object_type(&self) -> i32 {
+        match self {
+            Object::Array(_) => ARRAY_TYPE,
+            Object::String(_) => STRING_TYPE,
+            Object::Record { record_type, .. } => *record_type,
+        }
+    }
+
+    /// Element `index`, `None` if it is out of bounds
+    pub fn get(&self, index: usize) -> Option<Value> {
+        match self {
+            Object::Array(values) | Object::Record { fields: values, .. } => {
+                values.get(index).copied()
+            }
+            Object::String(bytes) => bytes.get(index).map(|&b| Value::Int(b as i32)),
+        }
+    }
+
+    /// Overwrites element `index`, returning its previous value. Strings only
+    /// hold integers, truncated to a byte.
+    fn set(&mut self, index: usize, value: Value) -> Result<Value, ObjectError> {
+        match self {
+            Object::Array(values) | Object::Record { fields: values, .. } => {
+                let slot = values.get_mut(index).ok_or(ObjectError::OutOfBounds)?;
+                Ok(std::mem::replace(slot, value))
+            }
+            Object::String(bytes) => {
+                let Value::Int(value) = value else {
+                    return Err(ObjectError::TypeMismatch);
+                };
+                let byte = bytes.get_mut(index).ok_or(ObjectError::OutOfBounds)?;
+                Ok(Value::Int(std::mem::replace(byte, value as u8) as i32))
+            }
+        }
+    }
+
+    fn references(&self) -> impl Iterator<Item = u32> + '_ {
+        let values: &[Value] = match self {
+            Object::Array(values) | Object::Record { fields: values, .. } => values,
+            Object::String(_) => &[],
+        };
+        values.iter().filter_map(|value| match value {
+            Value::Ref(slot) => Some(*slot),
+            Value::Int(_) => None,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ObjectError {
+    /// The reference does not designate a live object
+    DanglingReference,
+    OutOfBounds,
+    /// A reference was stored in a string
+    TypeMismatch,
+}
+
+/// A change made to the object heap, kept to undo it when stepping backwards
+#[derive(Debug, PartialEq, Clone)]
+pub enum ObjectChange {
+    Allocated(u32),
+    Written { slot: u32, index: usize, old: Value },
+    Freed(u32, Object),
+}
+
+#[derive(Debug, Clone)]
+pub struct ObjectHeap {
+    slots: Vec<Option<Object>>,
+    /// Empty slots, reused before growing `slots`
+    free: Vec<u32>,
+    /// Live objects above which the next allocation collects first
+    threshold: usize,
+    /// Bytes taken by the elements of the live objects
+    bytes: usize,
+    /// Changes since the last call to `take_changes`, when logging
+    changes: Option<Vec<ObjectChange>>,
+}
+
+impl ObjectHeap {
+    pub fn new() -> ObjectHeap {
+        ObjectHeap {
+            slots: vec![],
+            free: vec![],
+            threshold: INITIAL_GC_THRESHOLD,
+            bytes: 0,
+            changes: None,
+        }
+    }
+
+    /// Number of live objects
+    pub fn live(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+
+    /// Bytes taken by the elements of the live objects
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Number of slots, live or free
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Whether an allocation should collect garbage first
+    pub fn needs_collection(&self) -> bool {
+        self.live() >= self.threshold
+    }
+
+    pub fn get(&self, slot: u32) -> Option<&Object> {
+        self.slots.get(slot as usize).and_then(Option::as_ref)
+    }
+
+    /// Live objects, with their slot
+    pub fn objects(&self) -> impl Iterator<Item = (u32, &Object)> {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter_map(|(slot, object)| object.as_ref().map(|o| (slot as u32, o)))
+    }
+
+    pub fn allocate(&mut self, object: Object) -> u32 {
+        self.bytes += object.bytes();
+        let slot = match self.free.pop() {
+            Some(slot) => {
+                self.slots[slot as usize] = Some(object);
+                slot
+            }
+            None => {
+                self.slots.push(Some(object));
+                (self.slots.len() - 1) as u32
+            }
+        };
+        self.log(ObjectChange::Allocated(slot));
+        slot
+    }
+
+    /// Overwrites element `index` of the object in `slot`
+    pub fn set(&mut self, slot: u32, index: usize, value: Value) -> Result<(), ObjectError> {
+        let object = self
+            .slots
+            .get_mut(slot as usize)
+            .and_then(Option::as_mut)
+            .ok_or(ObjectError::DanglingReference)?;
+        let old = object.set(index, value)?;
+        self.log(ObjectChange::Written { slot, index, old });
+        Ok(())
+    }
+
+    /// Frees every object that cannot be reached from `roots`, returning how
+    /// many were freed
+    pub fn collect(&mut self, roots: impl IntoIterator<Item = u32>) -> usize {
+        let mut marked = vec![false; self.slots.len()];
+        let mut pending: Vec<u32> = roots.into_iter().collect();
+        while let Some(slot) = pending.pop() {
+            match marked.get_mut(slot as usize) {
+                Some(mark) if !*mark => *mark = true,
+                _ => continue,
+            }
+            if let Some(object) = self.get(slot) {
+                pending.extend(object.references());
+            }
+        }
+
+        let mut freed = 0;
+        for (slot, mark) in marked.into_iter().enumerate() {
+            if mark {
+                continue;
+            }
+            if let Some(object) = self.slots[slot].take() {
+                self.bytes -= object.bytes();
+                self.free.push(slot as u32);
+                self.log(ObjectChange::Freed(slot as u32, object));
+                freed += 1;
+            }
+        }
+        self.threshold = (self.live() * 2).max(INITIAL_GC_THRESHOLD);
+        freed
+    }
+
+    /// Starts or stops keeping the changes made to the heap
+    pub(super) fn log_changes(&mut self, enabled: bool) {
+        self.changes = if enabled { Some(vec![]) } else { None };
+    }
+
+    /// Changes made since the last call
+    pub(super) fn take_changes(&mut self) -> Vec<ObjectChange> {
+        self.changes
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Reverts a change made to the heap
+    pub(super) fn undo(&mut self, change: ObjectChange) {
+        match change {
+            ObjectChange::Allocated(slot) => {
+                if let Some(object) = self.slots[slot as usize].take() {
+                    self.bytes -= object.bytes();
+                }
+                self.free.push(slot);
+            }
+            ObjectChange::Written { slot, index, old } => {
+                if let Some(object) = self.slots[slot as usize].as_mut() {
+                    let _ = object.set(index, old);
+                }
+            }
+            ObjectChange::Freed(slot, object) => {
+                self.free.retain(|&free| free != slot);
+                self.bytes += object.bytes();
+                self.slots[slot as usize] = Some(object);
+            }
+        }
+    }
+
+    /// Rebuilds a heap from its slots, as saved in a snapshot
+    pub(super) fn from_slots(slots: Vec<Option<Object>>) -> ObjectHeap {
+        let free = (0..slots.len() as u32)
+            .rev()
+            .filter(|&slot| slots[slot as usize].is_none())
+            .collect();
+        let bytes = slots.iter().flatten().map(Object::bytes).sum();
+        let mut heap = ObjectHeap {
+            slots,
+            free,
+            threshold: 0,
+            bytes,
+            changes: None,
+        };
+        heap.threshold = (heap.live() * 2).max(INITIAL_GC_THRESHOLD);
+        heap
+    }
+
+    pub(super) fn slots(&self) -> &[Option<Object>] {
+        &self.slots
+    }
+
+    fn log(&mut self, change: ObjectChange) {
+        if let Some(changes) = &mut self.changes {
+            changes.push(change);
+        }
+    }
+}
+
+impl Default for ObjectHeap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collect_unreachable_objects() {
+        let mut heap = ObjectHeap::new();
+        let string = heap.allocate(Object::String(b"hi".to_vec()));
+        let array = heap.allocate(Object::Array(vec![Value::Ref(string), Value::Int(3)]));
+        let garbage = heap.allocate(Object::Array(vec![Value::Int(0)]));
+        let cycle = heap.allocate(Object::Array(vec![Value::Int(0)]));
+        heap.set(cycle, 0, Value::Ref(cycle)).unwrap();
+
+        assert_eq!(heap.collect([array]), 2);
+        assert_eq!(heap.live(), 2);
+        assert_eq!(heap.get(string), Some(&Object::String(b"hi".to_vec())));
+        assert_eq!(heap.get(garbage), None);
+        assert_eq!(heap.get(cycle), None);
+
+        // Freed slots are reused
+        let reused = heap.allocate(Object::String(vec![]));
+        assert!(reused == garbage || reused == cycle);
+        assert_eq!(heap.capacity(), 4);
+    }
+
+    #[test]
+    fn test_object_access() {
+        let mut heap = ObjectHeap::new();
+        let record = heap.allocate(Object::Record {
+            record_type: 7,
+            fields: vec![Value::Int(0); 2],
+        });
+        let string = heap.allocate(Object::String(vec![0; 2]));
+        heap.set(record, 1, Value::Ref(string)).unwrap();
+        heap.set(string, 0, Value::Int(0x141)).unwrap();
+
+        assert_eq!(heap.get(record).unwrap().get(1), Some(Value::Ref(string)));
+        assert_eq!(heap.get(record).unwrap().object_type(), 7);
+        assert_eq!(heap.get(string).unwrap().get(0), Some(Value::Int(0x41)));
+        assert_eq!(
+            heap.set(record, 2, Value::Int(1)),
+            Err(ObjectError::OutOfBounds)
+        );
+        assert_eq!(
+            heap.set(string, 0, Value::Ref(record)),
+            Err(ObjectError::TypeMismatch)
+        );
+        assert_eq!(
+            heap.set(9, 0, Value::Int(1)),
+            Err(ObjectError::DanglingReference)
+        );
+    }
+
+    #[test]
+    fn test_undo_changes() {
+        let mut heap = ObjectHeap::new();
+        heap.log_changes(true);
+        let array = heap.allocate(Object::Array(vec![Value::Int(1)]));
+        heap.set(array, 0, Value::Int(2)).unwrap();
+        heap.collect([]);
+        assert_eq!(heap.live(), 0);
+
+        let mut changes = heap.take_changes();
+        heap.undo(changes.pop().unwrap());
+        assert_eq!(heap.get(array).unwrap().get(0), Some(Value::Int(2)));
+        heap.undo(changes.pop().unwrap());
+        assert_eq!(heap.get(array).unwrap().get(0), Some(Value::Int(1)));
+        heap.undo(changes.pop().unwrap());
+        assert_eq!(heap.live(), 0);
+        assert_eq!(heap.allocate(Object::String(vec![])), array);
+    }
+}
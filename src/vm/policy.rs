@@ -0,0 +1,79 @@
+//! Sandboxing policy: what a VM is allowed to do and how many resources it
+//! may use.
+//!
+//! The policy is enforced by the VM itself. An instruction that would break
+//! it faults with `VMError::PolicyViolation` before having any effect, so a
+//! host can run untrusted bytecode in-process.
+
+use std::collections::HashSet;
+
+use super::syscall::Syscall;
+
+/// The limit an instruction would have broken
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Violation {
+    /// The syscall is not allowed
+    Syscall(Syscall),
+    /// The heap and objects would take more than `VmPolicy::max_heap` bytes
+    MaxHeap { requested: usize },
+    /// The stack would get deeper than `VmPolicy::max_stack_depth`
+    MaxStackDepth,
+    /// The instruction would spend more cycles than `VmPolicy::fuel`
+    Fuel,
+    /// The program would write more than `VmPolicy::output_quota` bytes
+    OutputQuota,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct VmPolicy {
+    /// Syscalls the program may make
+    pub allowed_syscalls: HashSet<Syscall>,
+    /// Bytes the heap and the objects may take together
+    pub max_heap: Option<usize>,
+    /// Values the stack may hold
+    pub max_stack_depth: Option<usize>,
+    /// Cycles the program may spend
+    pub fuel: Option<u64>,
+    /// Bytes the program may write to its output
+    pub output_quota: Option<usize>,
+}
+
+/// Limits of `VmPolicy::sandbox`
+pub const SANDBOX_MAX_HEAP: usize = 16 * 1024 * 1024;
+pub const SANDBOX_MAX_STACK_DEPTH: usize = 64 * 1024;
+pub const SANDBOX_OUTPUT_QUOTA: usize = 1024 * 1024;
+
+impl VmPolicy {
+    /// Allows every syscall, without any limit
+    pub fn new() -> VmPolicy {
+        VmPolicy {
+            allowed_syscalls: Syscall::ALL.into_iter().collect(),
+            max_heap: None,
+            max_stack_depth: None,
+            fuel: None,
+            output_quota: None,
+        }
+    }
+
+    /// Allows no syscall, with limits that keep the host safe from the
+    /// program. There is no fuel limit, which depends on the program.
+    pub fn sandbox() -> VmPolicy {
+        VmPolicy {
+            allowed_syscalls: HashSet::new(),
+            max_heap: Some(SANDBOX_MAX_HEAP),
+            max_stack_depth: Some(SANDBOX_MAX_STACK_DEPTH),
+            fuel: None,
+            output_quota: Some(SANDBOX_OUTPUT_QUOTA),
+        }
+    }
+
+    pub fn allows(&self, syscall: Syscall) -> bool {
+        self.allowed_syscalls.contains(&syscall)
+    }
+}
+
+impl Default for VmPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
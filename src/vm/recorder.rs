@@ -0,0 +1,431 @@
+//! Execution recording, to step the VM backwards.
+//!
+//! While recording, every executed instruction leaves a `StepDelta` holding
+//! the values it overwrote. Undoing a step puts them back. Only the latest
+//! steps are kept, in a ring buffer of bounded capacity.
+
+use std::collections::VecDeque;
+
+use crate::flags::Flags;
+
+use super::{
+    allocator::Allocator,
+    objects::{ObjectChange, Value},
+    VM,
+};
+
+/// Number of steps kept when no capacity is given
+pub const DEFAULT_RECORDING_CAPACITY: usize = 10_000;
+
+/// A location whose changes stop a reverse continue
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Watchpoint {
+    Register(u8),
+    /// A byte of the heap
+    Heap(usize),
+}
+
+/// What an instruction changed, holding the values from before it ran
+#[derive(Debug, PartialEq, Clone)]
+pub struct StepDelta {
+    pc: usize,
+    /// Registers the instruction changed, with their previous value
+    registers: Vec<(u8, i32)>,
+    /// Registers tagged as pointers before the instruction
+    pointers: u32,
+    /// Stack length before the instruction, and its top entry then, which a
+    /// POP removes
+    stack_len: usize,
+    stack_top: Option<Value>,
+    /// Changes made to the object heap, in the order they were made
+    objects: Vec<ObjectChange>,
+    flags: Flags,
+    remainder: u32,
+    opcode: u8,
+    cycles: u64,
+    /// Heap length before the instruction, if it resized the heap
+    heap_len: Option<usize>,
+    /// Heap bytes the instruction overwrote or dropped, with their previous
+    /// value, in the order they were changed
+    heap_bytes: Vec<(usize, u8)>,
+    /// Allocator before the instruction, if it allocated or freed memory
+    allocator: Option<Allocator>,
+}
+
+impl StepDelta {
+    /// Address of the instruction this step executed
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// Whether undoing this step, on a heap of `heap_len` bytes, changes the
+    /// watched location
+    fn touches(&self, watchpoint: &Watchpoint, heap_len: usize) -> bool {
+        match *watchpoint {
+            Watchpoint::Register(register) => self.registers.iter().any(|(r, _)| *r == register),
+            Watchpoint::Heap(address) => {
+                let resized = self.heap_len.is_some_and(|old_len| {
+                    (old_len.min(heap_len)..old_len.max(heap_len)).contains(&address)
+                });
+                resized || self.heap_bytes.iter().any(|(a, _)| *a == address)
+            }
+        }
+    }
+}
+
+/// Bounded history of the steps executed by a VM
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    capacity: usize,
+    steps: VecDeque<StepDelta>,
+    /// Heap changes of the instruction being executed
+    heap_len: Option<usize>,
+    heap_bytes: Vec<(usize, u8)>,
+    allocator: Option<Allocator>,
+}
+
+impl Recorder {
+    pub fn new(capacity: usize) -> Recorder {
+        Recorder {
+            capacity,
+            steps: VecDeque::with_capacity(capacity.min(DEFAULT_RECORDING_CAPACITY)),
+            heap_len: None,
+            heap_bytes: vec![],
+            allocator: None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Records that the heap is about to be resized from `heap` to `new_len`
+    pub(super) fn heap_resized(&mut self, heap: &[u8], new_len: usize) {
+        if self.heap_len.is_none() {
+            self.heap_len = Some(heap.len());
+        }
+        if new_len < heap.len() {
+            for (offset, byte) in heap[new_len..].iter().enumerate() {
+                self.heap_bytes.push((new_len + offset, *byte));
+            }
+        }
+    }
+
+    /// Records that the allocator is about to change
+    pub(super) fn allocator_changed(&mut self, allocator: &Allocator) {
+        if self.allocator.is_none() {
+            self.allocator = Some(allocator.clone());
+        }
+    }
+
+    /// Records that the heap byte at `address`, currently `old`, is about to
+    /// be overwritten
+    pub(super) fn heap_written(&mut self, address: usize, old: u8) {
+        self.heap_bytes.push((address, old));
+    }
+
+    fn push(&mut self, mut step: StepDelta) {
+        step.heap_len = self.heap_len.take();
+        step.heap_bytes = std::mem::take(&mut self.heap_bytes);
+        step.allocator = self.allocator.take();
+        if self.capacity == 0 {
+            return;
+        }
+        if self.steps.len() == self.capacity {
+            self.steps.pop_front();
+        }
+        self.steps.push_back(step);
+    }
+}
+
+/// VM state from before a step, needed to build its `StepDelta`
+pub(super) struct StepStart {
+    pub pc: usize,
+    pub registers: [i32; crate::register::REGISTER_COUNT],
+    pub pointers: u32,
+    pub stack_len: usize,
+    pub stack_top: Option<Value>,
+    pub flags: Flags,
+    pub remainder: u32,
+    pub cycles: u64,
+}
+
+impl VM {
+    /// Starts recording executed instructions, keeping the last `capacity`
+    /// of them. Recording again drops the existing history.
+    pub fn enable_recording(&mut self, capacity: usize) {
+        self.recorder = Some(Recorder::new(capacity));
+        self.objects.log_changes(true);
+    }
+
+    pub fn disable_recording(&mut self) {
+        self.recorder = None;
+        self.objects.log_changes(false);
+    }
+
+    pub fn recorder(&self) -> Option<&Recorder> {
+        self.recorder.as_ref()
+    }
+
+    pub(super) fn step_start(&self) -> StepStart {
+        StepStart {
+            pc: self.pc,
+            registers: self.registers,
+            pointers: self.pointers,
+            stack_len: self.stack.len(),
+            stack_top: self.stack.last().copied(),
+            flags: self.flags,
+            remainder: self.remainder,
+            cycles: self.cycles.total(),
+        }
+    }
+
+    /// Records the changes made by the step that started in `start`
+    pub(super) fn record_step(&mut self, start: &StepStart) {
+        let objects = self.objects.take_changes();
+        let Some(&opcode) = self.program.get(start.pc) else {
+            return;
+        };
+        let registers = start
+            .registers
+            .iter()
+            .zip(self.registers.iter())
+            .enumerate()
+            .filter(|(_, (old, new))| old != new)
+            .map(|(i, (old, _))| (i as u8, *old))
+            .collect();
+        let step = StepDelta {
+            pc: start.pc,
+            registers,
+            pointers: start.pointers,
+            stack_len: start.stack_len,
+            stack_top: start.stack_top,
+            objects,
+            flags: start.flags,
+            remainder: start.remainder,
+            opcode,
+            cycles: self.cycles.total() - start.cycles,
+            heap_len: None,
+            heap_bytes: vec![],
+            allocator: None,
+        };
+        if let Some(recorder) = &mut self.recorder {
+            recorder.push(step);
+        }
+    }
+
+    /// Undoes the last recorded step, returning it, or `None` when there is
+    /// no recorded history left
+    pub fn reverse_step(&mut self) -> Option<StepDelta> {
+        let step = self.recorder.as_mut()?.steps.pop_back()?;
+        self.undo(&step);
+        Some(step)
+    }
+
+    /// Undoes recorded steps until one that changed a watched location, and
+    /// returns that watchpoint. Returns `None` if the start of the recorded
+    /// history was reached first.
+    pub fn reverse_continue(&mut self, watchpoints: &[Watchpoint]) -> Option<Watchpoint> {
+        loop {
+            let heap_len = self.heap.len();
+            let step = self.reverse_step()?;
+            if let Some(watchpoint) = watchpoints.iter().find(|w| step.touches(w, heap_len)) {
+                return Some(*watchpoint);
+            }
+        }
+    }
+
+    fn undo(&mut self, step: &StepDelta) {
+        self.pc = step.pc;
+        for &(register, value) in &step.registers {
+            self.registers[register as usize] = value;
+        }
+        self.pointers = step.pointers;
+        if self.stack.len() >= step.stack_len {
+            self.stack.truncate(step.stack_len);
+        } else if let Some(top) = step.stack_top {
+            self.stack.push(top);
+        }
+        for change in step.objects.iter().rev() {
+            self.objects.undo(change.clone());
+        }
+        self.flags = step.flags;
+        self.remainder = step.remainder;
+        self.cycles.remove(step.opcode, step.cycles);
+        if let Some(heap_len) = step.heap_len {
+            self.heap.resize(heap_len, 0);
+        }
+        for &(address, byte) in step.heap_bytes.iter().rev() {
+            if let Some(b) = self.heap.get_mut(address) {
+                *b = byte;
+            }
+        }
+        if let Some(allocator) = &step.allocator {
+            self.allocator = allocator.clone();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recorded_vm() -> VM {
+        let mut vm = VM::new();
+        vm.program = vec![
+            0, 0, 0, 10, // LOAD $0 #10
+            0, 1, 0, 4, // LOAD $1 #4
+            12, 1, 5, 0, // ALOC $1 $5
+            1, 0, 1, 2, // ADD $0 $1 $2
+            3, 0, 1, 2, // MUL $0 $1 $2
+            11, 0, 0, 0, // HLT
+        ];
+        vm.enable_recording(DEFAULT_RECORDING_CAPACITY);
+        vm
+    }
+
+    #[test]
+    fn test_reverse_step() {
+        let mut vm = recorded_vm();
+        for _ in 0..5 {
+            vm.run_once().unwrap();
+        }
+        assert_eq!(vm.registers[2], 40);
+        assert_eq!(vm.recorder().unwrap().len(), 5);
+
+        let step = vm.reverse_step().unwrap();
+        assert_eq!(step.pc(), 16);
+        assert_eq!(vm.pc, 16);
+        assert_eq!(vm.registers[2], 14);
+        assert_eq!(vm.cycles.total(), 4);
+
+        vm.reverse_step().unwrap();
+        vm.reverse_step().unwrap();
+        assert_eq!(vm.heap.len(), 0);
+        assert_eq!(vm.registers[2], 0);
+
+        vm.reverse_step().unwrap();
+        vm.reverse_step().unwrap();
+        assert_eq!(vm.pc, 0);
+        assert_eq!(vm.registers, [0; crate::register::REGISTER_COUNT]);
+        assert!(vm.reverse_step().is_none());
+
+        // Replaying gives the same results
+        for _ in 0..5 {
+            vm.run_once().unwrap();
+        }
+        assert_eq!(vm.registers[2], 40);
+    }
+
+    #[test]
+    fn test_reverse_continue_to_watchpoint() {
+        let mut vm = recorded_vm();
+        for _ in 0..5 {
+            vm.run_once().unwrap();
+        }
+        let hit = vm.reverse_continue(&[Watchpoint::Register(1)]);
+        assert_eq!(hit, Some(Watchpoint::Register(1)));
+        assert_eq!(vm.pc, 4);
+        assert_eq!(vm.registers[1], 0);
+
+        let hit = vm.reverse_continue(&[Watchpoint::Register(5)]);
+        assert_eq!(hit, None);
+        assert_eq!(vm.pc, 0);
+    }
+
+    #[test]
+    fn test_reverse_continue_to_heap_watchpoint() {
+        let mut vm = recorded_vm();
+        for _ in 0..5 {
+            vm.run_once().unwrap();
+        }
+        let hit = vm.reverse_continue(&[Watchpoint::Heap(2)]);
+        assert_eq!(hit, Some(Watchpoint::Heap(2)));
+        assert_eq!(vm.pc, 8);
+        assert!(vm.heap.is_empty());
+    }
+
+    #[test]
+    fn test_recording_is_bounded() {
+        let mut vm = recorded_vm();
+        vm.enable_recording(2);
+        for _ in 0..5 {
+            vm.run_once().unwrap();
+        }
+        assert_eq!(vm.recorder().unwrap().len(), 2);
+        vm.reverse_step().unwrap();
+        vm.reverse_step().unwrap();
+        assert_eq!(vm.pc, 12);
+        assert!(vm.reverse_step().is_none());
+    }
+
+    #[test]
+    fn test_reverse_object_instructions() {
+        let mut vm = VM::new();
+        vm.program = vec![
+            0, 0, 0, 2, // LOAD $0 #2
+            33, 0, 1, 0, // NEWARR $0 $1
+            37, 1, 31, 0, // SET $1 $zero $0
+            41, 1, 0, 0, // PUSH $1
+            0, 1, 0, 0, // LOAD $1 #0
+            43, 0, 0, 0, // GC
+            42, 2, 0, 0, // POP $2
+        ];
+        vm.enable_recording(DEFAULT_RECORDING_CAPACITY);
+        for _ in 0..7 {
+            vm.run_once().unwrap();
+        }
+        assert!(vm.is_pointer(2));
+        assert!(vm.stack.is_empty());
+
+        vm.reverse_step().unwrap();
+        assert!(!vm.is_pointer(2));
+        assert_eq!(vm.stack, vec![Value::Ref(0)]);
+        vm.reverse_step().unwrap();
+        vm.reverse_step().unwrap();
+        assert!(vm.is_pointer(1));
+        vm.reverse_step().unwrap();
+        assert!(vm.stack.is_empty());
+        vm.reverse_step().unwrap();
+        assert_eq!(vm.objects.get(0).unwrap().get(0), Some(Value::Int(0)));
+        vm.reverse_step().unwrap();
+        assert_eq!(vm.objects.live(), 0);
+        assert!(!vm.is_pointer(1));
+    }
+
+    #[test]
+    fn test_reverse_free() {
+        let mut vm = VM::new();
+        vm.registers[0] = 4;
+        vm.program = vec![
+            12, 0, 1, 0, // ALOC $0 $1
+            12, 0, 2, 0, // ALOC $0 $2
+            44, 1, 0, 0, // FREE $1
+            12, 0, 3, 0, // ALOC $0 $3
+        ];
+        vm.enable_recording(DEFAULT_RECORDING_CAPACITY);
+        for _ in 0..3 {
+            vm.run_once().unwrap();
+        }
+        vm.heap[1] = 5;
+        vm.run_once().unwrap();
+        assert_eq!(vm.heap[1], 0);
+
+        vm.reverse_step().unwrap();
+        assert_eq!(vm.heap[1], 5);
+        vm.reverse_step().unwrap();
+        assert_eq!(vm.heap_stats().allocated_blocks, 2);
+        vm.reverse_step().unwrap();
+        vm.reverse_step().unwrap();
+        assert_eq!(vm.heap_stats().allocated_blocks, 0);
+        assert!(vm.heap.is_empty());
+    }
+}
@@ -0,0 +1,457 @@
+//! Serialization of the full state of a VM, to checkpoint it and resume it
+//! later.
+//!
+//! A snapshot starts with `SNAPSHOT_MAGIC` and a version number, followed by
+//! the state of the VM in little endian:
+//!
+//! - registers, `REGISTER_COUNT` × i32
+//! - pc, u64
+//! - remainder, u32
+//! - flags, u8 packed as `NZCV`
+//! - arithmetic mode, u8
+//! - cycles spent, as the number of opcodes with cycles (u16), then for each
+//!   one its byte (u8) and cycles (u64)
+//! - heap, length (u64) then bytes
+//! - program, length (u64) then bytes
+//!
+//! Version 2 adds the object heap:
+//!
+//! - registers tagged as pointers, u32 with one bit per register
+//! - stack, length (u64) then values
+//! - object slots, count (u64) then for each slot its kind (u8): 0 for a free
+//!   slot, 1 for an array or 3 for a record followed by the record type (i32)
+//!   and values, 2 for a string followed by its bytes
+//!
+//! Version 3 adds the blocks of the heap handed out by ALOC, as their count
+//! (u64) then for each one its address (u64) and size (u64). Older snapshots
+//! are loaded with their whole heap as a single allocated block.
+//!
+//! A value is a tag (u8), 0 for an integer or 1 for a reference, followed by
+//! an i32 or a u32. Lists of values are prefixed by their length (u64).
+//!
+//! Tracing, profiling, the cost table and the policy are host configuration
+//! rather than VM state, and are not part of a snapshot.
+
+use std::io::{self, Read, Write};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::{cost::CycleCounter, flags::Flags};
+
+use super::{
+    allocator::Allocator,
+    objects::{Object, ObjectHeap, Value},
+    ArithmeticMode, VM,
+};
+
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"VMST";
+pub const SNAPSHOT_VERSION: u16 = 3;
+/// Oldest version that can still be loaded
+pub const OLDEST_SNAPSHOT_VERSION: u16 = 1;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    /// The data does not start with `SNAPSHOT_MAGIC`
+    NotASnapshot,
+    /// The snapshot was written by an incompatible version
+    UnsupportedVersion {
+        version: u16,
+    },
+    /// The snapshot holds a value the VM cannot take
+    Corrupted {
+        reason: String,
+    },
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(value: io::Error) -> Self {
+        SnapshotError::Io(value)
+    }
+}
+
+impl VM {
+    /// Writes the state of the VM, see the module documentation for the format
+    pub fn save_state<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&SNAPSHOT_MAGIC)?;
+        w.write_u16::<LittleEndian>(SNAPSHOT_VERSION)?;
+        for register in self.registers {
+            w.write_i32::<LittleEndian>(register)?;
+        }
+        w.write_u64::<LittleEndian>(self.pc as u64)?;
+        w.write_u32::<LittleEndian>(self.remainder)?;
+        w.write_u8(self.flags.bits())?;
+        w.write_u8(match self.arithmetic_mode {
+            ArithmeticMode::Wrapping => 0,
+            ArithmeticMode::Trap => 1,
+        })?;
+        let cycles = self.cycles.by_opcode();
+        w.write_u16::<LittleEndian>(cycles.len() as u16)?;
+        for (opcode, count) in cycles {
+            w.write_u8(opcode)?;
+            w.write_u64::<LittleEndian>(count)?;
+        }
+        write_bytes(w, &self.heap)?;
+        write_bytes(w, &self.program)?;
+        w.write_u32::<LittleEndian>(self.pointers)?;
+        write_values(w, &self.stack)?;
+        let slots = self.objects.slots();
+        w.write_u64::<LittleEndian>(slots.len() as u64)?;
+        for slot in slots {
+            match slot {
+                None => w.write_u8(0)?,
+                Some(Object::Array(values)) => {
+                    w.write_u8(1)?;
+                    write_values(w, values)?;
+                }
+                Some(Object::String(bytes)) => {
+                    w.write_u8(2)?;
+                    write_bytes(w, bytes)?;
+                }
+                Some(Object::Record {
+                    record_type,
+                    fields,
+                }) => {
+                    w.write_u8(3)?;
+                    w.write_i32::<LittleEndian>(*record_type)?;
+                    write_values(w, fields)?;
+                }
+            }
+        }
+        let blocks: Vec<(usize, usize)> = self.allocator.blocks().collect();
+        w.write_u64::<LittleEndian>(blocks.len() as u64)?;
+        for (address, size) in blocks {
+            w.write_u64::<LittleEndian>(address as u64)?;
+            w.write_u64::<LittleEndian>(size as u64)?;
+        }
+        Ok(())
+    }
+
+    /// Builds a VM from a state written by `save_state`
+    pub fn load_state<R: Read>(r: &mut R) -> Result<VM, SnapshotError> {
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)?;
+        if magic != SNAPSHOT_MAGIC {
+            return Err(SnapshotError::NotASnapshot);
+        }
+        let version = r.read_u16::<LittleEndian>()?;
+        if !(OLDEST_SNAPSHOT_VERSION..=SNAPSHOT_VERSION).contains(&version) {
+            return Err(SnapshotError::UnsupportedVersion { version });
+        }
+
+        let mut vm = VM::new();
+        for register in vm.registers.iter_mut() {
+            *register = r.read_i32::<LittleEndian>()?;
+        }
+        vm.pc = read_length(r)?;
+        vm.remainder = r.read_u32::<LittleEndian>()?;
+        vm.flags = Flags::from_bits(r.read_u8()?);
+        vm.arithmetic_mode = match r.read_u8()? {
+            0 => ArithmeticMode::Wrapping,
+            1 => ArithmeticMode::Trap,
+            mode => {
+                return Err(SnapshotError::Corrupted {
+                    reason: format!("unknown arithmetic mode {}", mode),
+                })
+            }
+        };
+        let mut cycles = CycleCounter::new();
+        let mut total: u64 = 0;
+        for _ in 0..r.read_u16::<LittleEndian>()? {
+            let opcode = r.read_u8()?;
+            let count = r.read_u64::<LittleEndian>()?;
+            total = total
+                .checked_add(count)
+                .ok_or_else(|| SnapshotError::Corrupted {
+                    reason: "cycle counts overflow".to_string(),
+                })?;
+            cycles.add(opcode, count);
+        }
+        vm.cycles = cycles;
+        vm.heap = read_bytes(r)?;
+        vm.program = read_bytes(r)?;
+        if version == 1 {
+            vm.allocator = whole_heap_allocator(vm.heap.len());
+            return Ok(vm);
+        }
+
+        vm.pointers = r.read_u32::<LittleEndian>()?;
+        vm.stack = read_values(r)?;
+        let mut slots = vec![];
+        for _ in 0..read_length(r)? {
+            slots.push(match r.read_u8()? {
+                0 => None,
+                1 => Some(Object::Array(read_values(r)?)),
+                2 => Some(Object::String(read_bytes(r)?)),
+                3 => Some(Object::Record {
+                    record_type: r.read_i32::<LittleEndian>()?,
+                    fields: read_values(r)?,
+                }),
+                kind => {
+                    return Err(SnapshotError::Corrupted {
+                        reason: format!("unknown object kind {}", kind),
+                    })
+                }
+            });
+        }
+        vm.objects = ObjectHeap::from_slots(slots);
+        check_references(&vm)?;
+        if version == 2 {
+            vm.allocator = whole_heap_allocator(vm.heap.len());
+            return Ok(vm);
+        }
+
+        let mut blocks = vec![];
+        let mut end = 0;
+        for _ in 0..read_length(r)? {
+            let address = read_length(r)?;
+            let size = read_length(r)?;
+            end = match address.checked_add(size) {
+                Some(block_end) if address >= end && size > 0 && block_end <= vm.heap.len() => {
+                    block_end
+                }
+                _ => {
+                    return Err(SnapshotError::Corrupted {
+                        reason: format!("invalid heap block of {} bytes at {}", size, address),
+                    })
+                }
+            };
+            blocks.push((address, size));
+        }
+        vm.allocator = Allocator::from_blocks(blocks, vm.heap.len());
+        Ok(vm)
+    }
+
+    /// Copies the state of the VM into a new, independent VM. The copy does
+    /// not inherit tracing or profiling, but keeps the cost table and the
+    /// policy.
+    pub fn fork(&self) -> VM {
+        let mut state = vec![];
+        self.save_state(&mut state)
+            .expect("Writing to memory cannot fail");
+        let mut vm = VM::load_state(&mut state.as_slice())
+            .expect("A state that was just saved can be loaded");
+        vm.cost_table = self.cost_table.clone();
+        vm.policy = self.policy.clone();
+        vm.debug_info = self.debug_info.clone();
+        vm
+    }
+}
+
+fn write_bytes<W: Write>(w: &mut W, bytes: &[u8]) -> io::Result<()> {
+    w.write_u64::<LittleEndian>(bytes.len() as u64)?;
+    w.write_all(bytes)
+}
+
+/// Allocator of a heap saved before blocks were, all in use
+fn whole_heap_allocator(heap_len: usize) -> Allocator {
+    let blocks = (heap_len > 0).then_some((0, heap_len));
+    Allocator::from_blocks(blocks, heap_len)
+}
+
+fn write_values<W: Write>(w: &mut W, values: &[Value]) -> io::Result<()> {
+    w.write_u64::<LittleEndian>(values.len() as u64)?;
+    for value in values {
+        match *value {
+            Value::Int(value) => {
+                w.write_u8(0)?;
+                w.write_i32::<LittleEndian>(value)?;
+            }
+            Value::Ref(slot) => {
+                w.write_u8(1)?;
+                w.write_u32::<LittleEndian>(slot)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_values<R: Read>(r: &mut R) -> Result<Vec<Value>, SnapshotError> {
+    let length = read_length(r)?;
+    let mut values = Vec::with_capacity(length.min(1024));
+    for _ in 0..length {
+        values.push(match r.read_u8()? {
+            0 => Value::Int(r.read_i32::<LittleEndian>()?),
+            1 => Value::Ref(r.read_u32::<LittleEndian>()?),
+            tag => {
+                return Err(SnapshotError::Corrupted {
+                    reason: format!("unknown value tag {}", tag),
+                })
+            }
+        });
+    }
+    Ok(values)
+}
+
+/// Checks that every reference of a loaded VM designates a live object
+fn check_references(vm: &VM) -> Result<(), SnapshotError> {
+    let registers = (0..vm.registers.len())
+        .filter(|&r| vm.is_pointer(r))
+        .map(|r| vm.registers[r] as u32);
+    let values = vm
+        .stack
+        .iter()
+        .chain(vm.objects.objects().flat_map(|(_, object)| match object {
+            Object::Array(values) | Object::Record { fields: values, .. } => values.as_slice(),
+            Object::String(_) => &[],
+        }))
+        .filter_map(|value| match value {
+            Value::Ref(slot) => Some(*slot),
+            Value::Int(_) => None,
+        });
+    match registers
+        .chain(values)
+        .find(|&slot| vm.objects.get(slot).is_none())
+    {
+        Some(slot) => Err(SnapshotError::Corrupted {
+            reason: format!("reference to a missing object {}", slot),
+        }),
+        None => Ok(()),
+    }
+}
+
+fn read_length<R: Read>(r: &mut R) -> Result<usize, SnapshotError> {
+    let length = r.read_u64::<LittleEndian>()?;
+    usize::try_from(length).map_err(|_| SnapshotError::Corrupted {
+        reason: format!("{} does not fit in memory", length),
+    })
+}
+
+fn read_bytes<R: Read>(r: &mut R) -> Result<Vec<u8>, SnapshotError> {
+    let length = read_length(r)?;
+    let mut bytes = vec![];
+    r.take(length as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != length {
+        return Err(SnapshotError::Io(io::ErrorKind::UnexpectedEof.into()));
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::Opcode;
+
+    fn running_vm() -> VM {
+        let mut vm = VM::new();
+        vm.program = vec![
+            0, 0, 0, 10, // LOAD $0 #10
+            0, 1, 0, 4, // LOAD $1 #4
+            12, 1, 5, 0, // ALOC $1 $5
+            4, 0, 1, 2, // DIV $0 $1 $2
+            2, 1, 0, 3, // SUB $1 $0 $3
+            11, 0, 0, 0, // HLT
+        ];
+        vm.arithmetic_mode = ArithmeticMode::Trap;
+        for _ in 0..5 {
+            vm.run_once().unwrap();
+        }
+        vm
+    }
+
+    #[test]
+    fn test_save_and_load_state() {
+        let vm = running_vm();
+        let mut state = vec![];
+        vm.save_state(&mut state).unwrap();
+        let loaded = VM::load_state(&mut state.as_slice()).unwrap();
+
+        assert_eq!(loaded.registers, vm.registers);
+        assert_eq!(loaded.pc, 20);
+        assert_eq!(loaded.remainder, 2);
+        assert_eq!(loaded.flags, vm.flags);
+        assert!(loaded.flags.negative);
+        assert_eq!(loaded.arithmetic_mode, ArithmeticMode::Trap);
+        assert_eq!(loaded.cycles, vm.cycles);
+        assert_eq!(loaded.heap.len(), 4);
+        assert_eq!(loaded.allocator, vm.allocator);
+        assert_eq!(loaded.program, vm.program);
+    }
+
+    #[test]
+    fn test_fork_is_independent() {
+        let mut vm = running_vm();
+        let mut fork = vm.fork();
+        fork.registers[0] = 42;
+        fork.run_once().unwrap();
+        assert_eq!(vm.registers[0], 10);
+        assert_eq!(vm.pc, 20);
+        assert_eq!(fork.pc, 24);
+        vm.run_once().unwrap();
+        assert_eq!(vm.pc, 24);
+    }
+
+    #[test]
+    fn test_load_invalid_state() {
+        let result = VM::load_state(&mut &b"nope, not a snapshot"[..]);
+        assert!(matches!(result, Err(SnapshotError::NotASnapshot)));
+
+        let mut state = vec![];
+        running_vm().save_state(&mut state).unwrap();
+        state[4] = 99;
+        let result = VM::load_state(&mut state.as_slice());
+        assert!(matches!(
+            result,
+            Err(SnapshotError::UnsupportedVersion { version: 99 })
+        ));
+
+        let mut state = vec![];
+        running_vm().save_state(&mut state).unwrap();
+        state.truncate(state.len() - 3);
+        let result = VM::load_state(&mut state.as_slice());
+        assert!(matches!(result, Err(SnapshotError::Io(_))));
+
+        // The heap block ends past the end of the address space
+        let mut state = vec![];
+        running_vm().save_state(&mut state).unwrap();
+        let block = state.len() - 16;
+        state[block..block + 8].copy_from_slice(&1u64.to_le_bytes());
+        state[block + 8..].copy_from_slice(&u64::MAX.to_le_bytes());
+        let result = VM::load_state(&mut state.as_slice());
+        assert!(matches!(result, Err(SnapshotError::Corrupted { .. })));
+
+        let mut vm = VM::new();
+        vm.cycles.add(Opcode::ADD as u8, u64::MAX);
+        vm.cycles.add(Opcode::DIV as u8, u64::MAX);
+        let mut state = vec![];
+        vm.save_state(&mut state).unwrap();
+        let result = VM::load_state(&mut state.as_slice());
+        assert!(matches!(result, Err(SnapshotError::Corrupted { .. })));
+    }
+
+    #[test]
+    fn test_save_and_load_objects() {
+        let mut vm = VM::new();
+        vm.program = vec![
+            0, 0, 0, 3, // LOAD $0 #3
+            34, 0, 1, 0, // NEWSTR $0 $1
+            35, 0, 0, 2, // NEWREC $0 $0 $2
+            37, 2, 31, 1, // SET $2 $zero $1
+            41, 2, 0, 0, // PUSH $2
+            33, 0, 3, 0, // NEWARR $0 $3
+        ];
+        for _ in 0..6 {
+            vm.run_once().unwrap();
+        }
+        let mut state = vec![];
+        vm.save_state(&mut state).unwrap();
+        let loaded = VM::load_state(&mut state.as_slice()).unwrap();
+
+        assert_eq!(loaded.pointers, vm.pointers);
+        assert_eq!(loaded.stack, vec![Value::Ref(1)]);
+        assert_eq!(loaded.objects.live(), 3);
+        assert_eq!(loaded.objects.get(1), vm.objects.get(1));
+        assert_eq!(
+            loaded.objects.get(2),
+            Some(&Object::Array(vec![Value::Int(0); 3]))
+        );
+
+        // A snapshot referencing a missing object is rejected
+        let mut vm = VM::new();
+        vm.set_value(4, Value::Ref(9));
+        let mut state = vec![];
+        vm.save_state(&mut state).unwrap();
+        let result = VM::load_state(&mut state.as_slice());
+        assert!(matches!(result, Err(SnapshotError::Corrupted { .. })));
+    }
+}
@@ -0,0 +1,90 @@
+//! Services the VM provides to programs through the SYSCALL instruction.
+//!
+//! `SYSCALL $number $argument $result` runs the syscall `number` with the
+//! value of `argument`, and writes its result to `result`. Every syscall must
+//! be allowed by the policy of the VM, see `VmPolicy`.
+
+use std::fmt;
+
+use super::{
+    objects::{Object, Value},
+    policy::Violation,
+    VMError, VM,
+};
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, clap::ValueEnum)]
+pub enum Syscall {
+    /// Writes the argument in decimal and a new line. Returns the number of
+    /// bytes written.
+    PrintInt = 0,
+    /// Writes the low byte of the argument. Returns 1.
+    PrintChar = 1,
+    /// Writes the string object the argument refers to. Returns its length.
+    PrintString = 2,
+    /// Returns the milliseconds elapsed since the VM was created. This is the
+    /// only syscall whose result depends on the host.
+    Clock = 3,
+}
+
+impl Syscall {
+    pub const ALL: [Syscall; 4] = [
+        Syscall::PrintInt,
+        Syscall::PrintChar,
+        Syscall::PrintString,
+        Syscall::Clock,
+    ];
+
+    pub fn from_number(number: i32) -> Option<Syscall> {
+        Syscall::ALL.into_iter().find(|s| *s as i32 == number)
+    }
+}
+
+impl fmt::Display for Syscall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl VM {
+    /// Runs a syscall for the instruction at `pc`, returning its result
+    pub(super) fn syscall(
+        &mut self,
+        number: i32,
+        argument: Value,
+        pc: usize,
+    ) -> Result<i32, VMError> {
+        let syscall = Syscall::from_number(number).ok_or(VMError::UnknownSyscall { number, pc })?;
+        if !self.policy.allows(syscall) {
+            return Err(VMError::PolicyViolation {
+                violation: Violation::Syscall(syscall),
+                pc,
+            });
+        }
+        match syscall {
+            Syscall::PrintInt => {
+                let text = format!("{}\n", argument.bits());
+                self.write_output(text.as_bytes(), pc)?;
+                Ok(text.len() as i32)
+            }
+            Syscall::PrintChar => {
+                self.write_output(&[argument.bits() as u8], pc)?;
+                Ok(1)
+            }
+            Syscall::PrintString => {
+                let string = match argument {
+                    Value::Ref(slot) => match self.objects.get(slot) {
+                        Some(Object::String(bytes)) => Some(bytes.clone()),
+                        _ => None,
+                    },
+                    Value::Int(_) => None,
+                };
+                let Some(bytes) = string else {
+                    return Err(VMError::TypeMismatch { pc });
+                };
+                self.write_output(&bytes, pc)?;
+                Ok(bytes.len() as i32)
+            }
+            Syscall::Clock => Ok(self.started.elapsed().as_millis() as i32),
+        }
+    }
+}